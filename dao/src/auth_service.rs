@@ -1,15 +1,24 @@
 use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use model::grant::AccessTokenRequest;
 use model::instances::InstanceRequest;
+use model::tokens::generate_token;
+use model::transaction::GnapTransaction;
 
 use super::auth::AuthDb;
 use super::cache::GnapCache;
 use super::db::GnapDB;
 use errors::AuthError;
-use log::{trace, debug};
+use log::{debug, trace};
 use model::credentials::Credentials;
 use model::users::User;
 use rand;
+use redis::AsyncCommands;
+
+/// Cache path of browser sessions.
+const SESSION_PATH: &str = "gnap:session";
+/// Seconds a browser session lasts.
+const SESSION_TTL: usize = 1800;
 
 pub struct AuthService {
     pub db_client: AuthDb,
@@ -30,33 +39,84 @@ impl AuthService {
         }
     }
 
+    /// Authenticate the end-user, and authorize the transaction for them.
+    /// Returns the authorized transaction, or `None` if the credentials are
+    /// not valid.
     pub async fn validate_account(
         &self,
         credentials: Credentials,
         instance: InstanceRequest,
-    ) -> Result<bool, AuthError> {
+    ) -> Result<Option<GnapTransaction>, AuthError> {
+        match self.authenticate(credentials).await? {
+            Some(user) => Ok(Some(
+                self.db_gnap
+                    .authenticate_tx(instance.instance_id, user)
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Verify the credentials of an end-user.  Returns the user, or `None` if
+    /// the password does not match.
+    pub async fn authenticate(&self, credentials: Credentials) -> Result<Option<User>, AuthError> {
         trace!("Fetching User from database");
         let user = self.db_client.fetch_account(credentials.username).await?;
-        if user.is_some() {
-            match validate_password(user.clone().unwrap().password, credentials.password) {
+        match user {
+            Some(user) => match validate_password(user.password.clone(), credentials.password) {
                 Ok(_) => {
                     debug!("Password valid");
-                    match self
-                        .db_gnap
-                        .authenticate_tx(instance.instance_id, user.unwrap())
-                        .await
-                    {
-                        Ok(_) => Ok(true),
-                        Err(_) => Err(AuthError::DatabaseNotFound),
-                    }
+                    Ok(Some(user))
                 }
-                Err(_) => Ok(false),
-            }
-        } else {
-            Ok(false)
+                Err(_) => Ok(None),
+            },
+            None => Ok(None),
         }
     }
 
+    /// Authorize a transaction for a signed in end-user, limited to the
+    /// access they consented to.  Their identity is released to the client
+    /// instance only with `subject_consent`.
+    pub async fn approve(
+        &self,
+        tx_id: String,
+        username: String,
+        approved: Vec<AccessTokenRequest>,
+        subject_consent: bool,
+    ) -> Result<GnapTransaction, AuthError> {
+        let user = self
+            .db_client
+            .fetch_account(username)
+            .await?
+            .ok_or(AuthError::DatabaseNotFound)?;
+        Ok(self
+            .db_gnap
+            .approve_tx(tx_id, user, Some(approved), subject_consent)
+            .await?)
+    }
+
+    /// Start a browser session for a signed in end-user.  Returns the
+    /// session id.
+    pub async fn create_session(&self, username: &str) -> Result<String, AuthError> {
+        let session_id = generate_token();
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", SESSION_PATH, session_id);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, username)
+            .expire(&cache_key, SESSION_TTL)
+            .query_async(&mut con)
+            .await?;
+        Ok(session_id)
+    }
+
+    /// The end-user signed in to a browser session, if it has not expired.
+    pub async fn find_session(&self, session_id: &str) -> Result<Option<String>, AuthError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", SESSION_PATH, session_id);
+        Ok(con.get(&cache_key).await?)
+    }
+
     pub async fn create_account(
         &self,
         credentials: Credentials,
@@ -68,6 +128,7 @@ impl AuthService {
             username: credentials.username,
             password: password_hash,
             access: None,
+            account_id: None,
         };
 
         match self.db_client.add_user(user).await {
@@ -5,16 +5,26 @@ use errors::GnapError;
 use futures::stream::TryStreamExt;
 use log::{debug, trace};
 use model::grant::{AccessRequest, GrantRequest, AccessTokenRequest};
-use model::transaction::{GnapTransaction, GnapTransactionState, TransactionOptions};
+use model::transaction::{
+    GnapTransaction, GnapTransactionState, PushDelivery, TransactionOptions,
+};
 use model::{
     account::{Account, AccountRequest},
     client::{GnapClient, GnapClientRequest},
     gnap::GnapOptions,
+    key::ClientKey,
+    oidc::id_token_algorithm,
+    resource::GnapResourceServer,
     tokens::Token,
     users::User,
 };
-use mongodb::{bson::doc, options::ClientOptions, Client, Database};
+use mongodb::{
+    bson::{doc, to_bson, DateTime},
+    options::{ClientOptions, IndexOptions},
+    Client, Database, IndexModel,
+};
 use std::env;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
@@ -29,6 +39,8 @@ const COL_GNAPOPTIONS: &str = "service_config";
 const COL_ACCOUNTS: &str = "accounts";
 const COL_CLIENTS: &str = "clients";
 const COL_TOKEN: &str = "tokens";
+const COL_USERS: &str = "users";
+const COL_RESOURCES: &str = "resources";
 
 //const MONGO_URI: &str = "mongodb://127.0.0.1:27017";
 
@@ -55,22 +67,63 @@ impl GnapDB {
         }
     }
 
-    pub async fn prune_db(&self) -> Result<(), GnapError> {
-        debug!("Pruning database");
-        let collection = self.database.collection::<GnapTransaction>(COL_TRANSACTION);
-        let new_filter = doc! { "state": "new"};
-        let waiting_filter = doc! { "state": "waiting"};
-        let _new = collection
-            .delete_many(new_filter, None)
+    /// Let MongoDB remove abandoned transactions once they expire.  This is
+    /// the backstop for the sweeper, which also removes their tokens.  Client
+    /// instances registered by value are looked up by key thumbprint.
+    pub async fn create_indexes(&self) -> Result<(), GnapError> {
+        let thumbprint = IndexModel::builder()
+            .keys(doc! { "key_thumbprint": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        self.database
+            .collection::<GnapClient>(COL_CLIENTS)
+            .create_index(thumbprint, None)
             .await
-            .map_err(GnapError::DatabaseError);
+            .map_err(GnapError::DatabaseError)?;
 
-        let _waiting = collection
-            .delete_many(waiting_filter, None)
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        self.database
+            .collection::<GnapTransaction>(COL_TRANSACTION)
+            .create_index(index, None)
             .await
-            .map_err(GnapError::DatabaseError);
-        debug!("Done pruning");
-        Ok(())
+            .map(|_| ())
+            .map_err(GnapError::DatabaseError)
+    }
+
+    /// The ids of the transactions that have expired.
+    pub async fn fetch_expired_transactions(&self) -> Result<Vec<String>, GnapError> {
+        let cursor = self
+            .database
+            .collection::<GnapTransaction>(COL_TRANSACTION)
+            .find(doc! { "expires_at": { "$lte": DateTime::now() } }, None)
+            .await
+            .map_err(GnapError::DatabaseError)?;
+        let expired: Vec<GnapTransaction> = cursor
+            .try_collect()
+            .await
+            .map_err(GnapError::DatabaseError)?;
+        Ok(expired.into_iter().map(|tx| tx.tx_id).collect())
+    }
+
+    /// The resource servers that registered any of the resource types.
+    pub async fn fetch_resource_servers_by_type(
+        &self,
+        resource_types: Vec<String>,
+    ) -> Result<Vec<GnapResourceServer>, GnapError> {
+        let cursor = self
+            .database
+            .collection::<GnapResourceServer>(COL_RESOURCES)
+            .find(doc! { "resource_set.type": { "$in": resource_types } }, None)
+            .await
+            .map_err(GnapError::DatabaseError)?;
+        cursor.try_collect().await.map_err(GnapError::DatabaseError)
     }
 
     pub async fn list_databases(&self) -> Result<Vec<String>, GnapError> {
@@ -167,9 +220,38 @@ impl GnapDB {
         }
     }
 
+    /// The client instance registered by value with this key.
+    pub async fn fetch_client_by_key(
+        &self,
+        key: &ClientKey,
+    ) -> Result<Option<GnapClient>, GnapError> {
+        let thumbprint = match key.thumbprint() {
+            Some(thumbprint) => thumbprint,
+            None => return Ok(None),
+        };
+        let proof = to_bson(&key.proof).map_err(|_| GnapError::BadData)?;
+        self.database
+            .collection::<GnapClient>(COL_CLIENTS)
+            .find_one(
+                doc! {"key_thumbprint": thumbprint, "key.proof": proof},
+                None,
+            )
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
     pub async fn add_client(&self, request: GnapClientRequest) -> Result<GnapClient, GnapError> {
+        let mut client = GnapClient::new(request.redirect_uris, request.client_name);
+        client.key = request.key;
+        client.access_token_lifetime = request.access_token_lifetime;
+        client.access_token_format = request.access_token_format;
+        client.id_token_signed_response_alg =
+            Some(id_token_algorithm(request.id_token_signed_response_alg)?);
+        self.insert_client(client).await
+    }
+
+    pub async fn insert_client(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
         let collection = self.database.collection::<GnapClient>(COL_CLIENTS);
-        let client = GnapClient::new(request.redirect_uris, request.client_name);
         match collection.insert_one(client.clone(), None).await {
             Ok(_) => {
                 debug!("Added client: {:?}", &client);
@@ -293,7 +375,67 @@ impl GnapDB {
         }
     }
 
-    pub async fn authenticate_tx(&self, tx_id: String, user: User) -> Result<(), GnapError> {
+    /// Record the delivery status of an interaction finish push.  Only the
+    /// delivery is updated, so concurrent continuation requests are kept.
+    pub async fn update_push_delivery(
+        &self,
+        tx_id: &str,
+        delivery: &PushDelivery,
+    ) -> Result<(), GnapError> {
+        let delivery = to_bson(delivery).map_err(|_| GnapError::BadData)?;
+        self.database
+            .collection::<GnapTransaction>(COL_TRANSACTION)
+            .update_one(doc! {"tx_id": tx_id}, doc! {"$set": {"push": delivery}}, None)
+            .await
+            .map(|_| ())
+            .map_err(GnapError::DatabaseError)
+    }
+
+    pub async fn fetch_user_by_id(&self, id: &str) -> Result<Option<User>, GnapError> {
+        self.database
+            .collection::<User>(COL_USERS)
+            .find_one(doc! { "id": id }, None)
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
+    /// Validate the requested access of a transaction against the user that
+    /// authorized it.  Only the approved rights are kept in the request.
+    pub async fn reauthorize_tx(&self, tx: GnapTransaction) -> Result<GnapTransaction, GnapError> {
+        let user_id = tx
+            .request
+            .as_ref()
+            .and_then(|request| request.user.clone())
+            .ok_or(GnapError::BadData)?;
+        let user = self
+            .fetch_user_by_id(&user_id)
+            .await?
+            .ok_or(GnapError::NotFound)?;
+        let gr = validate_user_access(user, tx.clone())?;
+        Ok(tx.update_grantrequest(gr))
+    }
+
+    /// Authorize the transaction for the authenticated user, and finish the
+    /// interaction.  Returns the updated transaction.
+    pub async fn authenticate_tx(
+        &self,
+        tx_id: String,
+        user: User,
+    ) -> Result<GnapTransaction, GnapError> {
+        self.approve_tx(tx_id, user, None, false).await
+    }
+
+    /// Authorize the transaction for the authenticated user, limited to the
+    /// access the user consented to, if given.  The result is still limited
+    /// to the access the user holds.  The subject is only released to the
+    /// client instance with `subject_consent`.
+    pub async fn approve_tx(
+        &self,
+        tx_id: String,
+        user: User,
+        approved: Option<Vec<AccessTokenRequest>>,
+        subject_consent: bool,
+    ) -> Result<GnapTransaction, GnapError> {
         let filter = doc! {"tx_id": &tx_id };
 
         let collection = self.database.collection::<GnapTransaction>(COL_TRANSACTION);
@@ -306,14 +448,37 @@ impl GnapDB {
         let tx = match cursor_result {
             Ok(trans) => {
                 if let Some(trans) = trans {
+                    // A denied grant can't be approved with a code issued
+                    // before the denial.
+                    if matches!(trans.state, GnapTransactionState::Denied) {
+                        return Err(GnapError::UserDenied);
+                    }
+                    let trans = match approved {
+                        Some(approved) => {
+                            let gr = trans.request.clone().ok_or(GnapError::BadData)?;
+                            trans.update_grantrequest(gr.update_access_token(approved))
+                        }
+                        None => trans,
+                    };
+                    let requested = trans
+                        .request
+                        .as_ref()
+                        .map_or(false, |request| !request.access_token.is_empty());
                     let gr = match validate_user_access(user.clone(), trans.clone()) {
                         Ok(gr) => gr,
                         Err(err) => return Err(err),
                     };
+                    // Nothing the user holds was approved, so no grant is
+                    // left to authorize.
+                    if requested && gr.access_token.is_empty() {
+                        return Err(GnapError::AccessMismatch);
+                    }
                     let update = trans
-                        .update_state(GnapTransactionState::Authorized)
+                        .transition(GnapTransactionState::Authorized)?
                         .update_grantrequest(gr)
-                        .update_user(user.id);
+                        .update_user(user.id)
+                        .update_subject_consent(subject_consent)
+                        .finish_interaction();
                     Some(update)
                 } else {
                     None
@@ -322,13 +487,13 @@ impl GnapDB {
             Err(_) => None,
         };
 
-        if tx.is_some() {
+        if let Some(tx) = tx {
             let res = collection
-                .find_one_and_replace(filter.clone(), &tx.unwrap(), None)
+                .find_one_and_replace(filter.clone(), &tx, None)
                 .await
                 .map_err(GnapError::DatabaseError);
             match res {
-                Ok(_) => Ok(()),
+                Ok(_) => Ok(tx),
                 Err(err) => Err(err),
             }
         } else {
@@ -346,8 +511,8 @@ impl GnapDB {
 }
 
 fn validate_user_access(user: User, tx: GnapTransaction) -> Result<GrantRequest, GnapError> {
-    let grant = tx.request.unwrap();
-    let user_access = user.access.unwrap();
+    let grant = tx.request.ok_or(GnapError::BadData)?;
+    let user_access = user.access.unwrap_or_default();
     //debug!("UserAccess {:#?}", &user_access);
     debug!("Lets VALIDATE");
 
@@ -384,41 +549,46 @@ fn validate_user_access(user: User, tx: GnapTransaction) -> Result<GrantRequest,
                     actions,
                     locations,
                     data_types,
-                } => (resource_type, actions.unwrap(), locations, data_types),
-                _ => return Err(GnapError::AccessMismatch),
+                } => (resource_type, actions, locations, data_types),
+                // A reference is approved as is, if the user holds it.
+                reference => {
+                    if user_access.contains(&reference) {
+                        approved_access.push(reference);
+                    }
+                    continue;
+                }
             };
-            
 
             for us in user_access.clone().into_iter() {
-                let (us_rs, us_actions) = match us {
+                let (us_rs, us_actions, us_loc, us_type) = match us {
                     AccessRequest::Value {
                         resource_type,
                         actions,
-                        locations: _,
-                        data_types: _,
-                    } => (resource_type, actions.unwrap()),
-                    _ => return Err(GnapError::AccessMismatch),
+                        locations,
+                        data_types,
+                    } => (resource_type, actions, locations, data_types),
+                    AccessRequest::Reference(_) => continue,
                 };
-                if us_rs.eq(&ac_rs) {
-                    debug!("{:#?}", us_rs);
-                    debug!("{:#?}", us_actions);
-                    debug!("{:#?}", ac_actions);
-                    let mut approved_actions = Vec::<String>::new();
-                    for a in ac_actions.clone().into_iter() {
-                        if us_actions.contains(&a) {
-                            debug!("Found a match");
-                            approved_actions.push(a);
-                        }
-                    }
-                    if !approved_actions.is_empty() {
-                        let approved_access_request = AccessRequest::Value {
+                if us_rs.ne(&ac_rs) {
+                    continue;
+                }
+                // Only the actions, locations and data types the user has
+                // access to are approved.
+                match (
+                    approve_actions(&ac_actions, &us_actions),
+                    restrict_to(&ac_loc, &us_loc),
+                    restrict_to(&ac_type, &us_type),
+                ) {
+                    (Some(actions), Some(locations), Some(data_types)) => {
+                        debug!("Approved {:?} of {}", actions, ac_rs);
+                        approved_access.push(AccessRequest::Value {
                             resource_type: ac_rs.clone(),
-                            actions: Some(approved_actions),
-                            locations: ac_loc.clone(),
-                            data_types: ac_type.clone(),
-                        };
-                        approved_access.push(approved_access_request.clone())
+                            actions,
+                            locations,
+                            data_types,
+                        });
                     }
+                    _ => debug!("User can't approve {}", ac_rs),
                 }
             }
         }
@@ -442,6 +612,48 @@ fn validate_user_access(user: User, tx: GnapTransaction) -> Result<GrantRequest,
     //Err(GnapError::AccessMismatch)
 }
 
+/// The requested actions the user holds.  A request without actions asks for
+/// all of them, so only a user holding all actions can approve it.  `None`
+/// when none of the requested actions are held.
+fn approve_actions(
+    requested: &Option<Vec<String>>,
+    held: &Option<Vec<String>>,
+) -> Option<Option<Vec<String>>> {
+    match (requested, held) {
+        (requested, None) => Some(requested.clone()),
+        (None, Some(_)) => None,
+        (Some(requested), Some(held)) => {
+            let approved = requested
+                .iter()
+                .filter(|action| held.contains(action))
+                .cloned()
+                .collect::<Vec<String>>();
+            (!approved.is_empty()).then_some(Some(approved))
+        }
+    }
+}
+
+/// The requested values that are also allowed.  A user without values is not
+/// restricted, and a request without values gets every allowed value.  `None`
+/// when none of the requested values are allowed.
+fn restrict_to(
+    requested: &Option<Vec<String>>,
+    allowed: &Option<Vec<String>>,
+) -> Option<Option<Vec<String>>> {
+    match (requested, allowed) {
+        (requested, None) => Some(requested.clone()),
+        (None, Some(allowed)) => Some(Some(allowed.clone())),
+        (Some(requested), Some(allowed)) => {
+            let approved = requested
+                .iter()
+                .filter(|value| allowed.contains(value))
+                .cloned()
+                .collect::<Vec<String>>();
+            (!approved.is_empty()).then_some(Some(approved))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -714,13 +926,9 @@ mod tests {
         let user = serde_json::from_str(USER_DATA).unwrap();
         let tx = serde_json::from_str(TX_DATA).unwrap();
 
-        match validate_user_access(user, tx) {
-            Ok(gr) => {
-                assert_eq!(gr.access_token.len(), 0);
-                assert!(false);
-            },
-            Err(_) => assert!(true),
-        }
+        // Neither the reference nor the value is held by the user.
+        let gr = validate_user_access(user, tx).unwrap();
+        assert_eq!(gr.access_token.len(), 0);
     }
 
     #[test]
@@ -824,4 +1032,67 @@ mod tests {
 
         assert!(validate_user_access(user, tx).is_ok())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tx_with_other_location_fail() {
+        let user = serde_json::from_str(USER_DATA).unwrap();
+        let tx = TX_DATA_CREATE.replace(
+            "http://localhost:8080/bowls/\"",
+            "http://localhost:8080/cats/\"",
+        );
+        let tx = serde_json::from_str(&tx).unwrap();
+
+        let grantrequest = validate_user_access(user, tx).unwrap();
+        assert_eq!(grantrequest.access_token.len(), 0);
+    }
+
+    fn single_right(access: serde_json::Value) -> GnapTransaction {
+        let mut tx: serde_json::Value = serde_json::from_str(TX_DATA_CREATE).unwrap();
+        tx["request"]["access_token"][0]["access"] = serde_json::json!([access]);
+        serde_json::from_value(tx).unwrap()
+    }
+
+    fn user_with(access: serde_json::Value) -> User {
+        let mut user: serde_json::Value = serde_json::from_str(USER_DATA).unwrap();
+        user["access"] = access;
+        serde_json::from_value(user).unwrap()
+    }
+
+    #[test]
+    fn test_tx_without_actions() {
+        let tx = single_right(serde_json::json!({ "type": "waterbowl-access" }));
+
+        // A user holding only some actions can't approve all of them.
+        let user = serde_json::from_str(USER_DATA).unwrap();
+        let gr = validate_user_access(user, tx.clone()).unwrap();
+        assert_eq!(gr.access_token.len(), 0);
+
+        let user = user_with(serde_json::json!([{ "type": "waterbowl-access" }]));
+        let gr = validate_user_access(user, tx).unwrap();
+        assert_eq!(
+            gr.access_token[0].access,
+            vec![AccessRequest::Value {
+                resource_type: "waterbowl-access".to_owned(),
+                actions: None,
+                locations: None,
+                data_types: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tx_with_reference() {
+        let tx = single_right(serde_json::json!("dolphin-metadata"));
+
+        let user = serde_json::from_str(USER_DATA).unwrap();
+        let gr = validate_user_access(user, tx.clone()).unwrap();
+        assert_eq!(gr.access_token.len(), 0);
+
+        let user = user_with(serde_json::json!(["dolphin-metadata"]));
+        let gr = validate_user_access(user, tx).unwrap();
+        assert_eq!(
+            gr.access_token[0].access,
+            vec![AccessRequest::Reference("dolphin-metadata".to_owned())]
+        );
+    }
+}
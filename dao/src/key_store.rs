@@ -0,0 +1,254 @@
+//! Signing key store
+//!
+//! The keys the AS signs tokens with are kept in MongoDB, so every instance of
+//! the AS signs with, and publishes, the same keys.  The keys in use are held
+//! in memory, and reloaded when the keys change.  Clones of the store share
+//! these keys, so every service of an instance signs with the same key.
+//!
+//! The private keys are stored unencrypted, so access to the `keys`
+//! collection must be restricted to the AS.
+//!
+use errors::GnapError;
+use futures::stream::TryStreamExt;
+use jsonwebtoken::jwk::JwkSet;
+use log::{debug, error, info};
+use model::paseto::PasetoKey;
+use model::signing::{KeyPurpose, KeyRotationPolicy, KeyStatus, SigningKey, StoredKey};
+use mongodb::bson::{doc, to_bson};
+use mongodb::Database;
+use std::env;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const COLLECTION: &str = "keys";
+
+/// The keys in use.
+struct LoadedKeys {
+    signing_key: SigningKey,
+    paseto_key: PasetoKey,
+    jwks: JwkSet,
+}
+
+#[derive(Clone)]
+pub struct KeyStore {
+    database: Database,
+    pub policy: KeyRotationPolicy,
+    keys: Arc<RwLock<LoadedKeys>>,
+}
+
+impl KeyStore {
+    /// Load the keys, and activate a key for each purpose that has none.  The
+    /// first key is read from the PEM file named by `AS_SIGNING_KEY` (P-256)
+    /// or `AS_PASETO_KEY` (Ed25519) if set, and generated otherwise.
+    pub async fn create(database: Database, policy: KeyRotationPolicy) -> Result<Self, GnapError> {
+        let stored = fetch_keys(&database).await?;
+        for (purpose, var) in [
+            (KeyPurpose::Jwt, "AS_SIGNING_KEY"),
+            (KeyPurpose::Paseto, "AS_PASETO_KEY"),
+        ] {
+            if !stored
+                .iter()
+                .any(|key| key.purpose == purpose && key.status == KeyStatus::Active)
+            {
+                let key = match env::var(var) {
+                    Ok(path) => {
+                        let pem = fs::read(&path).map_err(|err| {
+                            error!("Failed to read {}: {:?}", var, err);
+                            GnapError::GeneralError
+                        })?;
+                        StoredKey::from_pem(purpose, &pem)?
+                    }
+                    Err(_) => StoredKey::generate(purpose)?,
+                };
+                info!("Activating {:?} signing key {}", purpose, key.kid);
+                insert_key(&database, &key.activate()).await?;
+            }
+        }
+
+        let keys = load(&fetch_keys(&database).await?, &policy)?;
+        Ok(Self {
+            database,
+            policy,
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// The key JWT access tokens are signed with.
+    pub fn signing_key(&self) -> SigningKey {
+        self.keys.read().unwrap().signing_key.clone()
+    }
+
+    /// The key PASETO access tokens are signed with.
+    pub fn paseto_key(&self) -> PasetoKey {
+        self.keys.read().unwrap().paseto_key.clone()
+    }
+
+    /// The public keys resource servers verify tokens with.
+    pub fn jwks(&self) -> JwkSet {
+        self.keys.read().unwrap().jwks.clone()
+    }
+
+    /// Generate a pending key.  It is published, but does not sign until it
+    /// is activated.
+    pub async fn generate(&self, purpose: KeyPurpose) -> Result<StoredKey, GnapError> {
+        let key = StoredKey::generate(purpose)?;
+        insert_key(&self.database, &key).await?;
+        self.refresh().await?;
+        Ok(key)
+    }
+
+    /// Sign with a pending key from now on.  The active keys activated
+    /// before it are retired, so when instances activate keys at the same
+    /// time the newest key is left active.
+    pub async fn activate(&self, kid: &str) -> Result<(), GnapError> {
+        let collection = self.database.collection::<StoredKey>(COLLECTION);
+        let now = now() as i64;
+        let pending = to_bson(&KeyStatus::Pending).map_err(|_| GnapError::BadData)?;
+        let active = to_bson(&KeyStatus::Active).map_err(|_| GnapError::BadData)?;
+        let retired = to_bson(&KeyStatus::Retired).map_err(|_| GnapError::BadData)?;
+        let key = collection
+            .find_one_and_update(
+                doc! { "kid": kid, "status": pending },
+                doc! { "$set": { "status": active.clone(), "activated_at": now } },
+                None,
+            )
+            .await?
+            .ok_or(GnapError::NotFound)?;
+        let purpose = to_bson(&key.purpose).map_err(|_| GnapError::BadData)?;
+        collection
+            .update_many(
+                doc! {
+                    "purpose": purpose,
+                    "status": active,
+                    "$or": [
+                        { "activated_at": { "$lt": now } },
+                        { "activated_at": now, "kid": { "$lt": kid } },
+                    ],
+                },
+                doc! { "$set": { "status": retired, "retired_at": now } },
+                None,
+            )
+            .await?;
+        info!("Activated {:?} signing key {}", key.purpose, kid);
+        self.refresh().await
+    }
+
+    /// Replace the active keys that are due for rotation, and remove the
+    /// retired keys that are no longer published.  Returns the number of
+    /// keys replaced.
+    pub async fn rotate(&self) -> Result<usize, GnapError> {
+        let stored = fetch_keys(&self.database).await?;
+        let mut rotated = 0;
+        for key in stored.iter().filter(|key| self.policy.is_due(key)) {
+            let next = StoredKey::generate(key.purpose)?;
+            insert_key(&self.database, &next).await?;
+            self.activate(&next.kid).await?;
+            rotated += 1;
+        }
+
+        let expired: Vec<&str> = stored
+            .iter()
+            .filter(|key| key.status == KeyStatus::Retired && !self.policy.is_published(key))
+            .map(|key| key.kid.as_str())
+            .collect();
+        if !expired.is_empty() {
+            let removed = self
+                .database
+                .collection::<StoredKey>(COLLECTION)
+                .delete_many(doc! { "kid": { "$in": expired } }, None)
+                .await?;
+            debug!("Removed {} retired signing keys", removed.deleted_count);
+        }
+        self.refresh().await?;
+        Ok(rotated)
+    }
+
+    /// Reload the keys, to pick up changes made by other instances.
+    pub async fn refresh(&self) -> Result<(), GnapError> {
+        let keys = load(&fetch_keys(&self.database).await?, &self.policy)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+async fn fetch_keys(database: &Database) -> Result<Vec<StoredKey>, GnapError> {
+    let cursor = database
+        .collection::<StoredKey>(COLLECTION)
+        .find(None, None)
+        .await?;
+    Ok(cursor.try_collect().await?)
+}
+
+async fn insert_key(database: &Database, key: &StoredKey) -> Result<(), GnapError> {
+    database
+        .collection::<StoredKey>(COLLECTION)
+        .insert_one(key, None)
+        .await?;
+    Ok(())
+}
+
+/// The newest active key of each purpose signs.  Every published key is in
+/// the key set.
+fn load(stored: &[StoredKey], policy: &KeyRotationPolicy) -> Result<LoadedKeys, GnapError> {
+    let active = |purpose| {
+        stored
+            .iter()
+            .filter(|key| key.purpose == purpose && key.status == KeyStatus::Active)
+            .max_by_key(|key| (key.activated_at, &key.kid))
+            .ok_or(GnapError::NotFound)
+    };
+    let keys = stored
+        .iter()
+        .filter(|key| policy.is_published(key))
+        .map(StoredKey::jwk)
+        .collect::<Result<_, _>>()?;
+    Ok(LoadedKeys {
+        signing_key: active(KeyPurpose::Jwt)?.signing_key()?,
+        paseto_key: active(KeyPurpose::Paseto)?.paseto_key()?,
+        jwks: JwkSet { keys },
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_keys() {
+        let policy = KeyRotationPolicy::default();
+        let mut old = StoredKey::generate(KeyPurpose::Jwt).unwrap().activate();
+        old.activated_at = Some(now() - 60);
+        let new = StoredKey::generate(KeyPurpose::Jwt).unwrap().activate();
+        let mut retired = StoredKey::generate(KeyPurpose::Jwt).unwrap().retire();
+        let paseto = StoredKey::generate(KeyPurpose::Paseto).unwrap().activate();
+        let pending = StoredKey::generate(KeyPurpose::Paseto).unwrap();
+
+        assert!(load(&[old.clone()], &policy).is_err());
+
+        let stored = vec![
+            old.clone(),
+            new.clone(),
+            retired.clone(),
+            paseto.clone(),
+            pending.clone(),
+        ];
+        let keys = load(&stored, &policy).unwrap();
+        assert_eq!(keys.signing_key.kid, new.kid);
+        assert_eq!(keys.paseto_key.kid, paseto.kid);
+        assert_eq!(keys.jwks.keys.len(), 5);
+        assert!(keys.jwks.find(&pending.kid).is_some());
+
+        retired.retired_at = Some(now() - policy.retain_for);
+        let keys = load(&[new, retired.clone(), paseto], &policy).unwrap();
+        assert_eq!(keys.jwks.keys.len(), 2);
+        assert!(keys.jwks.find(&retired.kid).is_none());
+    }
+}
@@ -14,6 +14,7 @@ pub mod auth;
 pub mod auth_service;
 pub mod cache;
 pub mod db;
+pub mod key_store;
 pub mod resource;
 pub mod resource_service;
 pub mod service;
@@ -20,11 +20,11 @@ pub struct ResourceService {
 }
 
 impl ResourceService {
-    pub async fn create() -> Self {
+    /// The resource service uses `tx_service`, so it shares its signing keys.
+    pub async fn create(tx_service: Service) -> Self {
         let db_client = ResourceDB::new().await;
         let cache_client = GnapCache::new().await;
         let token_service = TokenService::create().await;
-        let tx_service = Service::create().await;
 
         Self {
             db_client,
@@ -107,9 +107,17 @@ impl ResourceService {
         debug!("validate access");
         match validate_access_request(&access_request, resource_server) {
             Ok(_) => {
-                let key = Some(String::from("httpsig"));
+                let key = token.key.clone();
 
                 let token_active = self.token_service.validate_token(token.id).await.is_ok();
+                // An expired token is only reported as inactive
+                if !token_active {
+                    return Ok(InstrospectResponse {
+                        active: false,
+                        access: None,
+                        key: None,
+                    });
+                }
                 // Selects just the item
                 debug!("K{:#?}", access_request);
                 let atr = match get_access_request(token.label, &access_request) {
@@ -7,20 +7,38 @@
 
 use errors::GnapError;
 use log::{debug, trace};
-use model::tokens::Token;
+use model::tokens::{Token, TokenLifetimes};
 use model::{
     account::Account,
     client::{GnapClient, GnapClientRequest},
     gnap::GnapOptions,
-    grant::GrantRequest,
-    transaction::{GnapTransaction, TransactionOptions},
+    grant::{AccessRequest, GrantRequest},
+    resource::GnapResourceServer,
+    signing::KeyRotationPolicy,
+    transaction::{GnapTransaction, GnapTransactionState, PushDelivery, TransactionOptions},
+    users::User,
     CachePath,
 };
 use redis::{AsyncCommands, Value};
+use std::env;
 use uuid::Uuid;
 
 use super::cache::GnapCache;
 use super::db::GnapDB;
+use super::key_store::KeyStore;
+
+/// Cache path of the user code to transaction mapping.
+const USER_CODE_PATH: &str = "gnap:user_code";
+/// Cache path of the app launch code to transaction mapping.
+const APP_CODE_PATH: &str = "gnap:app_code";
+/// Seconds a user code or app launch code can be used.
+const INTERACTION_CODE_TTL: usize = 600;
+/// Cache path of the failed attempts to use an interaction code.
+const ATTEMPTS_PATH: &str = "gnap:attempts";
+/// Failed attempts allowed before an interaction code is invalidated.
+const MAX_ATTEMPTS: u32 = 5;
+/// Cache path of the key proofs that were used.
+const PROOF_PATH: &str = "gnap:proof";
 
 /// Service wrapper for cache and database
 ///
@@ -34,6 +52,10 @@ pub struct Service {
     pub db_client: GnapDB,
     /// Represents the Redis cache client
     pub cache_client: GnapCache,
+    /// How long issued access tokens are active
+    pub token_lifetimes: TokenLifetimes,
+    /// The keys tokens are signed with
+    pub key_store: KeyStore,
 }
 
 impl Service {
@@ -44,15 +66,32 @@ impl Service {
         // Create the db and cache instances.  This should really migrate to the
         // Service module.  But it works for now.
         let db_client = GnapDB::new().await;
-        let _ = db_client
-            .prune_db()
+        db_client
+            .create_indexes()
             .await
-            .expect("Failed to prune database");
+            .expect("Failed to create database indexes");
 
         let cache_client = GnapCache::new().await;
+        // TOKEN_LIFETIME is the default lifetime in seconds, TOKEN_LIFETIMES
+        // caps it per resource type, as in "photo-api=600,medical=300".
+        let token_lifetimes = TokenLifetimes::parse(
+            env::var("TOKEN_LIFETIME").ok().as_deref(),
+            env::var("TOKEN_LIFETIMES").ok().as_deref(),
+        );
+        // KEY_ROTATION is how many seconds a signing key is used, and
+        // KEY_RETENTION how long it is still published after that.
+        let policy = KeyRotationPolicy::parse(
+            env::var("KEY_ROTATION").ok().as_deref(),
+            env::var("KEY_RETENTION").ok().as_deref(),
+        );
+        let key_store = KeyStore::create(db_client.database.clone(), policy)
+            .await
+            .expect("Failed to load signing keys");
         Service {
             db_client,
             cache_client,
+            token_lifetimes,
+            key_store,
         }
     }
 
@@ -119,6 +158,28 @@ impl Service {
     /// Dynamically create a client
     pub async fn add_client(&self, request: GnapClientRequest) -> Result<GnapClient, GnapError> {
         let client = self.db_client.add_client(request).await?;
+        self.cache_client(client).await
+    }
+
+    /// Persist a client instance that was presented by value.
+    pub async fn register_client(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
+        let client = self.db_client.insert_client(client).await?;
+        self.cache_client(client).await
+    }
+
+    /// Register a client instance presented by value, or reuse the client
+    /// registered earlier with the same key.
+    pub async fn register_instance(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
+        if let Some(key) = &client.key {
+            if let Some(existing) = self.db_client.fetch_client_by_key(key).await? {
+                trace!("Reusing client {} for the key", existing.client_id);
+                return Ok(existing);
+            }
+        }
+        self.register_client(client).await
+    }
+
+    async fn cache_client(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
         let mut con = self.cache_client.client.get_async_connection().await?;
         let cache_key = format!("{}:{}", GnapClient::cache_path(), client.client_id).to_owned();
         let _: () = redis::pipe()
@@ -201,6 +262,11 @@ impl Service {
         }
     }
 
+    /// The end-user that authorized a grant.
+    pub async fn get_user(&self, id: &str) -> Result<Option<User>, GnapError> {
+        self.db_client.fetch_user_by_id(id).await
+    }
+
     /// Start a GNAP transaction.
     ///
     /// This is called from the grant request handler.  The request is cached
@@ -210,7 +276,10 @@ impl Service {
         request: GrantRequest,
     ) -> Result<GnapTransaction, GnapError> {
         //  let mut con = self.cache_client.client.get_async_connection().await?;
-        let tx = GnapTransaction::new(Some(request));
+        // The grant request handler has verified the client instance.
+        let tx = GnapTransaction::new(Some(request))
+            .transition(GnapTransactionState::ClientVerified)?
+            .transition(GnapTransactionState::Waiting)?;
         /* REDIS Is out
         let cache_key = format!("{}:{}", GnapTransaction::cache_path(), &tx.tx_id.clone());
         let _: () = redis::pipe()
@@ -234,6 +303,171 @@ impl Service {
         Ok(tx.unwrap())
     }
 
+    pub async fn update_transaction(
+        &self,
+        tx: GnapTransaction,
+    ) -> Result<GnapTransaction, GnapError> {
+        self.db_client.update_transaction(tx).await
+    }
+
+    /// Re-validate the requested access of an authorized transaction.
+    pub async fn reauthorize_transaction(
+        &self,
+        tx: GnapTransaction,
+    ) -> Result<GnapTransaction, GnapError> {
+        self.db_client.reauthorize_tx(tx).await
+    }
+
+    /// Map a user code to its transaction, until the code expires.
+    pub async fn store_user_code(&self, code: &str, tx_id: &str) -> Result<(), GnapError> {
+        self.store_code(USER_CODE_PATH, code, tx_id).await
+    }
+
+    /// The transaction a user code was issued for, if it has not expired.
+    pub async fn find_user_code(&self, code: &str) -> Result<Option<String>, GnapError> {
+        self.find_code(USER_CODE_PATH, code).await
+    }
+
+    /// A user code can only be used once.
+    pub async fn remove_user_code(&self, code: &str) -> Result<(), GnapError> {
+        self.remove_code(USER_CODE_PATH, code).await
+    }
+
+    /// Map an app launch code to its transaction, until the code expires.
+    pub async fn store_app_code(&self, code: &str, tx_id: &str) -> Result<(), GnapError> {
+        self.store_code(APP_CODE_PATH, code, tx_id).await
+    }
+
+    /// The transaction an app launch code was issued for, if it has not
+    /// expired.
+    pub async fn find_app_code(&self, code: &str) -> Result<Option<String>, GnapError> {
+        self.find_code(APP_CODE_PATH, code).await
+    }
+
+    /// An app launch code can only be used once.
+    pub async fn remove_app_code(&self, code: &str) -> Result<(), GnapError> {
+        self.remove_code(APP_CODE_PATH, code).await
+    }
+
+    /// Count a failed sign in with an app launch code.  Once the attempts
+    /// run out the code is invalidated, and `TooManyAttempts` is returned.
+    pub async fn fail_app_code(&self, code: &str) -> Result<(), GnapError> {
+        let attempts = self.count_attempt(APP_CODE_PATH, code).await?;
+        if attempts < MAX_ATTEMPTS {
+            return Ok(());
+        }
+        self.remove_code(APP_CODE_PATH, code).await?;
+        Err(GnapError::TooManyAttempts)
+    }
+
+    /// Count a failed user code entry from a client address.  Once the
+    /// attempts run out, `TooManyAttempts` is returned until the codes that
+    /// could have been guessed have expired.
+    pub async fn fail_user_code(&self, address: &str) -> Result<(), GnapError> {
+        match self.count_attempt(USER_CODE_PATH, address).await? {
+            attempts if attempts < MAX_ATTEMPTS => Ok(()),
+            _ => Err(GnapError::TooManyAttempts),
+        }
+    }
+
+    /// Whether a client address ran out of user code entries.
+    pub async fn user_code_locked(&self, address: &str) -> Result<bool, GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}:{}", ATTEMPTS_PATH, USER_CODE_PATH, address);
+        let attempts: Option<u32> = con.get(&cache_key).await?;
+        Ok(attempts.map_or(false, |attempts| attempts >= MAX_ATTEMPTS))
+    }
+
+    /// Count a failed attempt, for as long as an interaction code lasts.
+    /// Returns the failed attempts so far.
+    async fn count_attempt(&self, path: &str, name: &str) -> Result<u32, GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}:{}", ATTEMPTS_PATH, path, name);
+        let (attempts, _): (u32, bool) = redis::pipe()
+            .atomic()
+            .incr(&cache_key, 1)
+            .expire(&cache_key, INTERACTION_CODE_TTL)
+            .query_async(&mut con)
+            .await?;
+        Ok(attempts)
+    }
+
+    /// Remember a key proof for `ttl` seconds.  Returns `false` when it was
+    /// already used.
+    pub async fn record_proof(&self, digest: &str, ttl: usize) -> Result<bool, GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", PROOF_PATH, digest);
+        let recorded: Option<String> = redis::cmd("SET")
+            .arg(&cache_key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut con)
+            .await?;
+        Ok(recorded.is_some())
+    }
+
+    async fn store_code(&self, path: &str, code: &str, tx_id: &str) -> Result<(), GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", path, code);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, tx_id)
+            .expire(&cache_key, INTERACTION_CODE_TTL)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_code(&self, path: &str, code: &str) -> Result<Option<String>, GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", path, code);
+        Ok(con.get(&cache_key).await?)
+    }
+
+    async fn remove_code(&self, path: &str, code: &str) -> Result<(), GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", path, code);
+        let _: () = con.del(&cache_key).await?;
+        Ok(())
+    }
+
+    pub async fn record_push_delivery(
+        &self,
+        tx_id: &str,
+        delivery: &PushDelivery,
+    ) -> Result<(), GnapError> {
+        self.db_client.update_push_delivery(tx_id, delivery).await
+    }
+
+    /// The ids of the transactions that were abandoned before tokens were
+    /// issued.
+    pub async fn expired_transactions(&self) -> Result<Vec<String>, GnapError> {
+        self.db_client.fetch_expired_transactions().await
+    }
+
+    /// The resource servers that serve any of the requested rights.
+    pub async fn resource_servers(
+        &self,
+        access: &[AccessRequest],
+    ) -> Result<Vec<GnapResourceServer>, GnapError> {
+        let resource_types: Vec<String> = access
+            .iter()
+            .filter_map(|request| request.resource_type().map(str::to_owned))
+            .collect();
+        if resource_types.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.db_client
+            .fetch_resource_servers_by_type(resource_types)
+            .await
+    }
+
+    pub async fn delete_transaction(&self, tx_id: String) -> Result<(), GnapError> {
+        self.db_client.delete_transaction(tx_id).await
+    }
+
     pub async fn store_token(&self, token: Token) -> Result<(), GnapError> {
         match self.db_client.add_token(&token).await {
             Ok(_) => Ok(()),
@@ -1,8 +1,9 @@
 use errors::TokenError;
 use log::{debug, trace};
-use model::tokens::Token;
+use model::tokens::{Token, DEFAULT_TOKEN_LIFETIME};
 use mongodb::{bson::doc, options::ClientOptions, Client, Database};
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct TokenDb {
@@ -31,23 +32,55 @@ impl TokenDb {
         }
     }
 
-    pub async fn prune_db(&self) -> Result<(), TokenError> {
+    /// Remove the expired tokens.  Returns the number removed.
+    pub async fn prune_db(&self) -> Result<u64, TokenError> {
         debug!("Pruning database");
-        let collection = self.database.collection::<Token>(COLLECTION);
-        let expire_filter = doc! { "expire": "0"};
-        let null_filter = doc! { "expire": null};
-
-        let _new = collection
-            .delete_many(expire_filter, None)
+        self.backfill_expiry().await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or_default();
+        let pruned = self
+            .database
+            .collection::<Token>(COLLECTION)
+            .delete_many(doc! { "expires_at": { "$lte": now } }, None)
             .await
-            .map_err(TokenError::DatabaseError);
+            .map(|result| result.deleted_count)
+            .map_err(TokenError::DatabaseError)?;
+        debug!("Pruned {} expired tokens", pruned);
+        Ok(pruned)
+    }
 
-        let _null = collection
-            .delete_many(null_filter, None)
+    /// Tokens stored before tokens expired have no issue time.  They are
+    /// taken to be issued when their document was created, and expire
+    /// `DEFAULT_TOKEN_LIFETIME` after that.  Returns the number updated.
+    async fn backfill_expiry(&self) -> Result<u64, TokenError> {
+        let lifetime = DEFAULT_TOKEN_LIFETIME as i64;
+        let issued_at = doc! {
+            "$toLong": { "$trunc": { "$divide": [{ "$toLong": { "$toDate": "$_id" } }, 1000] } }
+        };
+        let updated = self
+            .database
+            .collection::<Token>(COLLECTION)
+            .update_many(
+                doc! {
+                    "issued_at": { "$exists": false },
+                    "expires_at": { "$exists": false },
+                },
+                vec![
+                    doc! { "$set": { "issued_at": issued_at } },
+                    doc! { "$set": { "expires_at": { "$add": ["$issued_at", lifetime] } } },
+                    doc! { "$unset": "expire" },
+                ],
+                None,
+            )
             .await
-            .map_err(TokenError::DatabaseError);
-        debug!("Done pruning");
-        Ok(())
+            .map(|result| result.modified_count)
+            .map_err(TokenError::DatabaseError)?;
+        if updated > 0 {
+            debug!("Set the expiry of {} tokens without one", updated);
+        }
+        Ok(updated)
     }
 
     pub async fn add_token(&self, token: &Token) -> Result<bool, TokenError> {
@@ -58,21 +91,78 @@ impl TokenDb {
         }
     }
 
+    /// Store the tokens issued for a grant.
+    pub async fn add_tokens(&self, tokens: &[Token]) -> Result<(), TokenError> {
+        self.database
+            .collection::<Token>(COLLECTION)
+            .insert_many(tokens, None)
+            .await
+            .map_err(TokenError::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Remove the tokens with the given ids.  Returns the number removed.
+    pub async fn remove_tokens(&self, ids: &[String]) -> Result<u64, TokenError> {
+        self.database
+            .collection::<Token>(COLLECTION)
+            .delete_many(doc! { "id": { "$in": ids } }, None)
+            .await
+            .map(|result| result.deleted_count)
+            .map_err(TokenError::DatabaseError)
+    }
+
+    /// Remove a token.  Returns false if there was no such token.
     pub async fn remove_token(&self, token: &Token) -> Result<bool, TokenError> {
-        let cursor_result = self
+        let result = self
             .database
             .collection::<Token>(COLLECTION)
             .delete_one(doc! { "id": &token.id}, None)
             .await
-            .map_err(TokenError::DatabaseError);
-
-        match cursor_result {
-            Ok(_) => Ok(true),
-            Err(err) => {
+            .map_err(|err| {
                 trace!("Failed to revoke token {:?}", err);
-                Ok(false)
-            }
-        }
+                TokenError::DatabaseError(err)
+            })?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Remove a token, only if it still has the same value.  Of concurrent
+    /// calls for one token, only one removes it.
+    pub async fn remove_token_value(&self, token: &Token) -> Result<bool, TokenError> {
+        self.database
+            .collection::<Token>(COLLECTION)
+            .delete_one(
+                doc! { "id": &token.id, "access_token": &token.access_token },
+                None,
+            )
+            .await
+            .map(|result| result.deleted_count > 0)
+            .map_err(TokenError::DatabaseError)
+    }
+
+    /// Remove every token issued for the transaction `tx`.
+    pub async fn remove_tokens_by_tx(&self, tx: &str) -> Result<u64, TokenError> {
+        self.database
+            .collection::<Token>(COLLECTION)
+            .delete_many(doc! { "tx": tx }, None)
+            .await
+            .map(|result| result.deleted_count)
+            .map_err(TokenError::DatabaseError)
+    }
+
+    /// Remove the tokens issued for the transaction `tx`, except the ones
+    /// with the ids in `keep`.
+    pub async fn remove_tokens_by_tx_except(
+        &self,
+        tx: &str,
+        keep: &[String],
+    ) -> Result<u64, TokenError> {
+        self.database
+            .collection::<Token>(COLLECTION)
+            .delete_many(doc! { "tx": tx, "id": { "$nin": keep } }, None)
+            .await
+            .map(|result| result.deleted_count)
+            .map_err(TokenError::DatabaseError)
     }
 
     pub async fn update_token(&self, token: Token) -> bool {
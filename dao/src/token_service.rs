@@ -23,36 +23,84 @@ impl TokenService {
         }
     }
 
-    pub async fn revoke_token(&self, token: &Token) -> Result<bool, TokenError> {
-        match self.db_client.remove_token(token).await {
-            Ok(_) => Ok(true),
-            Err(_) => Err(TokenError::NotFound),
+    /// Revoke a single token.
+    pub async fn revoke_token(&self, token: &Token) -> Result<(), TokenError> {
+        if self.db_client.remove_token(token).await? {
+            Ok(())
+        } else {
+            Err(TokenError::NotFound)
         }
     }
 
-    pub async fn rotate_token(&self, token: Token) -> Result<Token, TokenError> {
-        match self.db_client.fetch_token(&token).await {
-            Ok(newtoken) => {
-                let _ = self.db_client.remove_token(&token).await;
+    /// Store the tokens issued for a grant.  Either every token is stored,
+    /// or none is.
+    pub async fn store_tokens(&self, tokens: &[Token]) -> Result<(), TokenError> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        if let Err(err) = self.db_client.add_tokens(tokens).await {
+            self.revoke_tokens(tokens).await?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Revoke the given tokens.
+    pub async fn revoke_tokens(&self, tokens: &[Token]) -> Result<u64, TokenError> {
+        let ids: Vec<String> = tokens.iter().map(|token| token.id.clone()).collect();
+        self.db_client.remove_tokens(&ids).await
+    }
+
+    /// Revoke every token issued for a grant.
+    pub async fn revoke_tx_tokens(&self, tx: &str) -> Result<u64, TokenError> {
+        let revoked = self.db_client.remove_tokens_by_tx(tx).await?;
+        debug!("Revoked {} tokens for tx {}", revoked, tx);
+        Ok(revoked)
+    }
 
-                match self.db_client.add_token(&newtoken).await {
-                    Ok(_) => Ok(newtoken),
-                    Err(_) => Err(TokenError::RotateToken),
-                }
+    /// Remove the tokens that have expired.  Returns the number removed.
+    pub async fn prune_expired_tokens(&self) -> Result<u64, TokenError> {
+        self.db_client.prune_db().await
+    }
+
+    /// Revoke the tokens of a grant that were replaced by `current`.
+    pub async fn revoke_replaced_tokens(
+        &self,
+        tx: &str,
+        current: &[Token],
+    ) -> Result<u64, TokenError> {
+        let keep: Vec<String> = current.iter().map(|token| token.id.clone()).collect();
+        let revoked = self.db_client.remove_tokens_by_tx_except(tx, &keep).await?;
+        debug!("Revoked {} replaced tokens for tx {}", revoked, tx);
+        Ok(revoked)
+    }
+
+    /// Replace a token with its rotated value, with the same rights.  The old
+    /// value stops working, unless the token is durable.  The old token is
+    /// removed first, so a token is only rotated once.
+    pub async fn rotate_token(&self, token: &Token, rotated: &Token) -> Result<(), TokenError> {
+        if !token.is_durable() && !self.db_client.remove_token_value(token).await? {
+            debug!("Token {} was already rotated or revoked", token.id);
+            return Err(TokenError::RotateToken);
+        }
+        if let Err(err) = self.db_client.add_token(rotated).await {
+            debug!("Failed to store rotated token {}: {:?}", rotated.id, err);
+            // Put the old token back, so the client instance keeps access.
+            if !token.is_durable() && self.db_client.add_token(token).await.is_err() {
+                debug!("Failed to restore token {}", token.id);
             }
-            Err(_) => Err(TokenError::RotateToken),
+            return Err(TokenError::RotateToken);
         }
+        Ok(())
     }
+
+    /// A token is valid until it expires or is revoked.
     pub async fn validate_token(&self, token_id: String) -> Result<(), TokenError> {
         match self.db_client.fetch_token_by_id(token_id).await {
+            Ok(t) if !t.is_expired() => Ok(()),
             Ok(t) => {
-                debug!("Are we here");
-                if t.expire.is_some() {
-                    // this is not enterily correct, but will do for out poc
-                    Ok(())
-                } else {
-                    Err(TokenError::InvalidToken)
-                }
+                debug!("Token {} has expired", t.id);
+                Err(TokenError::InvalidToken)
             }
             Err(_) => Err(TokenError::InvalidToken),
         }
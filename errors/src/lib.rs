@@ -1,3 +1,5 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
 use serde::Serialize;
 use thiserror::Error;
 
@@ -15,6 +17,35 @@ pub enum GnapError {
     NotFound,
     #[error("Bad data error")]
     BadData,
+    #[error("Key proof verification failed")]
+    InvalidProof,
+    #[error("Invalid continuation access token")]
+    InvalidContinuation,
+    #[error("Invalid access token")]
+    InvalidToken,
+    #[error("Client polled before the wait period passed")]
+    TooFast,
+    #[error("Unknown interaction reference")]
+    UnknownInteraction,
+    #[error("The end-user denied the grant")]
+    UserDenied,
+    #[error("The request was denied")]
+    RequestDenied,
+    #[error("Unknown user")]
+    UnknownUser,
+    #[error("Interaction start or finish method not supported")]
+    InvalidInteraction,
+    #[error("Invalid access token flag")]
+    InvalidFlag,
+    #[error("The token can't be rotated")]
+    InvalidRotation,
+    #[error("Key rotation is not supported")]
+    KeyRotationNotSupported,
+    #[error("Too many failed attempts")]
+    TooManyAttempts,
+    /// The transition is only logged, the caller gets a fixed description.
+    #[error("The grant can't be changed in its current state")]
+    InvalidTransition(String),
     #[error("General error")]
     GeneralError,
 }
@@ -35,6 +66,11 @@ pub enum AuthError {
     HashMissmatch,
     #[error("Can't store a user in the database")]
     DatabaseError(#[from] mongodb::error::Error),
+    #[error("Cache error: {0}")]
+    CacheError(#[from] redis::RedisError),
+    /// The end-user was authenticated, but their grant failed.
+    #[error(transparent)]
+    GrantError(#[from] GnapError),
 }
 
 #[derive(Error, Debug)]
@@ -74,10 +110,265 @@ pub struct ErrorResponse {
     pub message: String,
 }
 
+/// The GNAP error codes.  Section 3.6
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GnapErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    InvalidInteraction,
+    InvalidFlag,
+    InvalidRotation,
+    KeyRotationNotSupported,
+    InvalidContinuation,
+    UserDenied,
+    RequestDenied,
+    UnknownUser,
+    UnknownInteraction,
+    TooFast,
+    TooManyAttempts,
+}
+
+/// The `error` object of a GNAP error response.
+#[derive(Serialize, Debug)]
+pub struct GnapErrorObject {
+    pub code: GnapErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GnapErrorResponse {
+    pub error: GnapErrorObject,
+}
+
+impl GnapErrorResponse {
+    /// The error response for an error.  GNAP has no code for server errors,
+    /// so they are a bare status, which also avoids leaking internals to the
+    /// caller.
+    fn build<E: ResponseError>(err: &E, code: Option<GnapErrorCode>) -> HttpResponse {
+        let status = err.status_code();
+        match code {
+            Some(code) => HttpResponse::build(status).json(GnapErrorResponse {
+                error: GnapErrorObject {
+                    code,
+                    description: Some(err.to_string()),
+                },
+            }),
+            None => HttpResponse::build(status).finish(),
+        }
+    }
+}
+
+impl GnapError {
+    /// The GNAP error code, or `None` for a server error.
+    pub fn code(&self) -> Option<GnapErrorCode> {
+        match self {
+            GnapError::InvalidProof => Some(GnapErrorCode::InvalidClient),
+            GnapError::InvalidContinuation => Some(GnapErrorCode::InvalidContinuation),
+            GnapError::TooFast => Some(GnapErrorCode::TooFast),
+            GnapError::UnknownInteraction => Some(GnapErrorCode::UnknownInteraction),
+            GnapError::UserDenied => Some(GnapErrorCode::UserDenied),
+            GnapError::UnknownUser => Some(GnapErrorCode::UnknownUser),
+            GnapError::InvalidInteraction => Some(GnapErrorCode::InvalidInteraction),
+            GnapError::InvalidFlag => Some(GnapErrorCode::InvalidFlag),
+            GnapError::InvalidRotation => Some(GnapErrorCode::InvalidRotation),
+            GnapError::KeyRotationNotSupported => Some(GnapErrorCode::KeyRotationNotSupported),
+            GnapError::TooManyAttempts => Some(GnapErrorCode::TooManyAttempts),
+            GnapError::NotFound
+            | GnapError::BadData
+            | GnapError::InvalidToken
+            | GnapError::InvalidTransition(_) => Some(GnapErrorCode::InvalidRequest),
+            GnapError::AccessMismatch | GnapError::RequestDenied => {
+                Some(GnapErrorCode::RequestDenied)
+            }
+            GnapError::DatabaseError(_)
+            | GnapError::MongoDataError(_)
+            | GnapError::CacheError(_)
+            | GnapError::GeneralError => None,
+        }
+    }
+}
+
+impl ResponseError for GnapError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            GnapError::InvalidProof | GnapError::InvalidContinuation | GnapError::InvalidToken => {
+                StatusCode::UNAUTHORIZED
+            }
+            GnapError::AccessMismatch | GnapError::UserDenied | GnapError::RequestDenied => {
+                StatusCode::FORBIDDEN
+            }
+            GnapError::NotFound => StatusCode::NOT_FOUND,
+            GnapError::TooFast | GnapError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            GnapError::BadData
+            | GnapError::InvalidTransition(_)
+            | GnapError::UnknownInteraction
+            | GnapError::UnknownUser
+            | GnapError::InvalidInteraction
+            | GnapError::InvalidFlag
+            | GnapError::InvalidRotation
+            | GnapError::KeyRotationNotSupported => StatusCode::BAD_REQUEST,
+            GnapError::DatabaseError(_)
+            | GnapError::MongoDataError(_)
+            | GnapError::CacheError(_)
+            | GnapError::GeneralError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        GnapErrorResponse::build(self, self.code())
+    }
+}
+
+impl AuthError {
+    pub fn code(&self) -> Option<GnapErrorCode> {
+        match self {
+            AuthError::BasicFailed | AuthError::UserNameError | AuthError::PasswordError => {
+                Some(GnapErrorCode::InvalidRequest)
+            }
+            AuthError::DatabaseNotFound | AuthError::HashMissmatch => {
+                Some(GnapErrorCode::UnknownUser)
+            }
+            AuthError::HashError | AuthError::DatabaseError(_) | AuthError::CacheError(_) => None,
+            AuthError::GrantError(err) => err.code(),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::BasicFailed | AuthError::UserNameError | AuthError::PasswordError => {
+                StatusCode::BAD_REQUEST
+            }
+            AuthError::DatabaseNotFound | AuthError::HashMissmatch => StatusCode::UNAUTHORIZED,
+            AuthError::HashError | AuthError::DatabaseError(_) | AuthError::CacheError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AuthError::GrantError(err) => err.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        GnapErrorResponse::build(self, self.code())
+    }
+}
+
+impl ResourceError {
+    pub fn code(&self) -> Option<GnapErrorCode> {
+        match self {
+            ResourceError::TokenError | ResourceError::AccessNotFound => {
+                Some(GnapErrorCode::RequestDenied)
+            }
+            ResourceError::NotFound => Some(GnapErrorCode::InvalidClient),
+            ResourceError::DatabaseError(_) | ResourceError::GeneralError => None,
+        }
+    }
+}
+
+impl ResponseError for ResourceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResourceError::TokenError | ResourceError::AccessNotFound => StatusCode::FORBIDDEN,
+            ResourceError::NotFound => StatusCode::UNAUTHORIZED,
+            ResourceError::DatabaseError(_) | ResourceError::GeneralError => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        GnapErrorResponse::build(self, self.code())
+    }
+}
+
+impl TokenError {
+    pub fn code(&self) -> Option<GnapErrorCode> {
+        match self {
+            TokenError::InvalidToken | TokenError::NotFound => Some(GnapErrorCode::InvalidRequest),
+            TokenError::RotateToken => Some(GnapErrorCode::InvalidRotation),
+            TokenError::DatabaseError(_) => None,
+        }
+    }
+}
+
+impl ResponseError for TokenError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TokenError::InvalidToken => StatusCode::UNAUTHORIZED,
+            TokenError::NotFound => StatusCode::NOT_FOUND,
+            TokenError::RotateToken => StatusCode::BAD_REQUEST,
+            TokenError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        GnapErrorResponse::build(self, self.code())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    async fn body(response: HttpResponse) -> serde_json::Value {
+        let body = to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn gnap_error_object() {
+        let response = GnapError::TooFast.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            body(response).await,
+            serde_json::json!({
+                "error": {
+                    "code": "too_fast",
+                    "description": "Client polled before the wait period passed"
+                }
+            })
+        );
+
+        let response = TokenError::RotateToken.error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response).await["error"]["code"], "invalid_rotation");
+    }
+
+    #[actix_web::test]
+    async fn server_errors_have_no_error_object() {
+        let response = GnapError::GeneralError.error_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn grant_errors_of_the_end_user() {
+        let response = AuthError::from(GnapError::TooManyAttempts).error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body(response).await["error"]["code"], "too_many_attempts");
+
+        let response = AuthError::from(GnapError::GeneralError).error_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn transitions_are_not_described() {
+        let response = GnapError::InvalidTransition("Issued -> Issued".to_owned()).error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = body(response).await;
+        assert_eq!(body["error"]["code"], "invalid_request");
+        assert!(!body["error"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("Issued"));
+    }
 }
@@ -0,0 +1,148 @@
+//! End-user consent
+//!
+//! The consent page lists every access right of the grant request, and the
+//! end-user approves each of them, or each of their actions, on its own.
+//! Selections are named by position in the request: `<token>.<access>` for a
+//! whole access right, and `<token>.<access>.<action>` for a single action.
+use model::grant::{AccessRequest, AccessTokenRequest, GrantRequest};
+
+/// Selection of a whole access right.
+pub fn access_key(token: usize, access: usize) -> String {
+    format!("{}.{}", token, access)
+}
+
+/// Selection of a single action of an access right.
+pub fn action_key(token: usize, access: usize, action: &str) -> String {
+    format!("{}.{}.{}", token, access, action)
+}
+
+/// The access token requests of the grant, limited to the selected access.
+/// An access right without any selection is left out, and so is a token
+/// request left without access.
+pub fn approved_access(request: &GrantRequest, selected: &[String]) -> Vec<AccessTokenRequest> {
+    let is_selected = |key: String| selected.contains(&key);
+
+    request
+        .access_token
+        .iter()
+        .enumerate()
+        .filter_map(|(t, token)| {
+            let access = token
+                .access
+                .iter()
+                .enumerate()
+                .filter_map(|(a, access)| match access {
+                    AccessRequest::Value {
+                        resource_type,
+                        actions: Some(actions),
+                        locations,
+                        data_types,
+                    } if !actions.is_empty() => {
+                        let actions: Vec<String> = actions
+                            .iter()
+                            .filter(|action| is_selected(action_key(t, a, action)))
+                            .cloned()
+                            .collect();
+                        (!actions.is_empty()).then(|| AccessRequest::Value {
+                            resource_type: resource_type.clone(),
+                            actions: Some(actions),
+                            locations: locations.clone(),
+                            data_types: data_types.clone(),
+                        })
+                    }
+                    _ => is_selected(access_key(t, a)).then(|| access.clone()),
+                })
+                .collect::<Vec<AccessRequest>>();
+
+            (!access.is_empty()).then(|| AccessTokenRequest {
+                access,
+                ..token.clone()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> GrantRequest {
+        serde_json::from_str(
+            r#"{
+                "access_token": [
+                    {
+                        "label": "bowls",
+                        "access": [
+                            {
+                                "type": "waterbowl-access",
+                                "actions": ["read", "create", "delete"],
+                                "locations": ["https://localhost:8080/bowls/"]
+                            },
+                            "foo",
+                            { "type": "dolphin-metadata" }
+                        ]
+                    },
+                    { "label": "other", "access": ["bar"] }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn approve_selected_actions() {
+        let selected = vec![action_key(0, 0, "read"), action_key(0, 0, "delete")];
+        let approved = approved_access(&request(), &selected);
+
+        assert_eq!(approved.len(), 1);
+        assert_eq!(approved[0].label, Some("bowls".to_owned()));
+        assert_eq!(
+            approved[0].access,
+            vec![AccessRequest::Value {
+                resource_type: "waterbowl-access".to_owned(),
+                actions: Some(vec!["read".to_owned(), "delete".to_owned()]),
+                locations: Some(vec!["https://localhost:8080/bowls/".to_owned()]),
+                data_types: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn approve_references() {
+        let selected = vec![access_key(0, 1), access_key(1, 0)];
+        let approved = approved_access(&request(), &selected);
+
+        assert_eq!(approved.len(), 2);
+        assert_eq!(
+            approved[0].access,
+            vec![AccessRequest::Reference("foo".to_owned())]
+        );
+        assert_eq!(
+            approved[1].access,
+            vec![AccessRequest::Reference("bar".to_owned())]
+        );
+    }
+
+    #[test]
+    fn approve_right_without_actions() {
+        let approved = approved_access(&request(), &[access_key(0, 2)]);
+
+        assert_eq!(approved.len(), 1);
+        assert_eq!(
+            approved[0].access,
+            vec![AccessRequest::Value {
+                resource_type: "dolphin-metadata".to_owned(),
+                actions: None,
+                locations: None,
+                data_types: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn approve_nothing() {
+        assert!(approved_access(&request(), &[]).is_empty());
+        // A whole access right with actions can't be selected at once.
+        assert!(approved_access(&request(), &[access_key(0, 0)]).is_empty());
+    }
+}
@@ -0,0 +1,404 @@
+//! Interaction start and finish
+//!
+//! The AS tells the client instance how to start an interaction with the
+//! end-user in the grant response.  When the end-user has finished, the AS
+//! sends them back to the client instance with an interaction reference, and
+//! a hash the client instance uses to verify the callback (GNAP Section 4.2).
+use super::GRANT_ENDPOINT;
+use actix_web::rt::time::sleep;
+use dao::service::Service;
+use errors::GnapError;
+use log::{debug, error, trace};
+use model::grant::{
+    InteractFinishMethodType, InteractFinishPush, InteractFinishRequest, InteractRequest,
+    InteractResponse, InteractStartMode, UserCodeResponse,
+};
+use model::transaction::{GnapTransaction, GnapTransactionState, PushDelivery, PushStatus};
+use std::time::Duration;
+use url::Url;
+
+/// The stable page where the end-user enters a user code.
+const DEVICE_URI: &str = "http://localhost:8000/gnap/device";
+/// Base of the launch URI of the `app` start mode.
+const APP_URI: &str = "http://localhost:8000/gnap/app";
+/// Number of attempts to deliver an interaction finish push.
+const PUSH_ATTEMPTS: u32 = 5;
+/// Delay before the first retry.  Doubled for every following retry.
+const PUSH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The interaction response for the start modes the client instance asked for.
+pub fn interaction_response(
+    tx: &GnapTransaction,
+    interact: Option<&InteractRequest>,
+) -> Option<InteractResponse> {
+    let interact = interact?;
+    let mut interact_response = InteractResponse {
+        redirect: None,
+        finish: tx.finish_nonce.clone(),
+        user_code: None,
+        app: None,
+    };
+
+    // What are the interaction methods?
+    for method in interact.start.iter() {
+        match method {
+            InteractStartMode::Redirect => {
+                trace!("GrantRequest interaction contains Redirect");
+                interact_response.redirect = Some(interaction_uri(tx));
+            }
+            InteractStartMode::App => {
+                trace!("GrantRequest interaction contains App");
+                interact_response.app = tx
+                    .app_code
+                    .as_ref()
+                    .map(|code| format!("{}/{}", APP_URI, code));
+            }
+            InteractStartMode::UserCode => {
+                trace!("GrantRequest interaction contains UserCode");
+                interact_response.user_code = tx.user_code.as_ref().map(|code| UserCodeResponse {
+                    code: code.clone(),
+                    uri: DEVICE_URI.to_owned(),
+                });
+            }
+        }
+    }
+    Some(interact_response)
+}
+
+/// Make the user code and app launch code of a transaction usable.
+pub async fn register_codes(service: &Service, tx: &GnapTransaction) -> Result<(), GnapError> {
+    if let Some(code) = &tx.user_code {
+        service.store_user_code(code, &tx.tx_id).await?;
+    }
+    if let Some(code) = &tx.app_code {
+        service.store_app_code(code, &tx.tx_id).await?;
+    }
+    Ok(())
+}
+
+/// Where the end-user is sent to interact with the AS.
+fn interaction_uri(tx: &GnapTransaction) -> String {
+    format!("http://localhost:8000/gnap/auth/{}", &tx.tx_id)
+}
+
+/// The client instance URI to redirect the end-user to once the interaction
+/// has finished.  Only available if the client instance asked for the
+/// `redirect` finish method.
+pub fn finish_redirect(tx: &GnapTransaction) -> Option<String> {
+    let finish = finish_request(tx)?;
+    if !matches!(finish.method, InteractFinishMethodType::Redirect) {
+        return None;
+    }
+    let parameters = finish_parameters(tx, finish)?;
+
+    let mut uri = match Url::parse(&finish.uri) {
+        Ok(uri) => uri,
+        Err(err) => {
+            debug!("Invalid interaction finish uri {}: {:?}", &finish.uri, err);
+            return None;
+        }
+    };
+    if let (Some(hash), Some(interact_ref)) = (parameters.hash, parameters.interact_ref) {
+        uri.query_pairs_mut()
+            .append_pair("hash", &hash)
+            .append_pair("interact_ref", &interact_ref);
+    }
+    Some(uri.to_string())
+}
+
+/// The client instance URI and body of the interaction finish push.  Only
+/// available if the client instance asked for the `push` finish method.
+pub fn finish_push(tx: &GnapTransaction) -> Option<(String, InteractFinishPush)> {
+    let finish = finish_request(tx)?;
+    if !matches!(finish.method, InteractFinishMethodType::Push) {
+        return None;
+    }
+    let parameters = finish_parameters(tx, finish)?;
+    Some((finish.uri.clone(), parameters))
+}
+
+/// Deliver the interaction finish push, and record the delivery status on the
+/// transaction.
+pub async fn deliver_push(service: &Service, tx_id: &str, uri: &str, body: &InteractFinishPush) {
+    record_push(service, tx_id, PushDelivery::new(PushStatus::Pending, 0)).await;
+    let delivery = send_push(uri, body, PUSH_ATTEMPTS, PUSH_BACKOFF).await;
+    record_push(service, tx_id, delivery).await;
+}
+
+async fn record_push(service: &Service, tx_id: &str, delivery: PushDelivery) {
+    if let Err(err) = service.record_push_delivery(tx_id, &delivery).await {
+        error!("Failed to record push delivery for {}: {:?}", tx_id, err);
+    }
+}
+
+/// POST the push body to the client instance, retrying with exponential
+/// backoff until it answers with a success status.
+async fn send_push(
+    uri: &str,
+    body: &InteractFinishPush,
+    attempts: u32,
+    backoff: Duration,
+) -> PushDelivery {
+    let client = reqwest::Client::new();
+    let mut delay = backoff;
+    for attempt in 1..=attempts {
+        match client.post(uri).json(body).send().await {
+            Ok(response) if response.status().is_success() => {
+                trace!("Delivered interaction push to {}", uri);
+                return PushDelivery::new(PushStatus::Delivered, attempt);
+            }
+            Ok(response) => {
+                debug!("Push to {} failed with {}", uri, response.status());
+            }
+            Err(err) => debug!("Push to {} failed: {:?}", uri, err),
+        }
+        if attempt < attempts {
+            sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    error!("Giving up interaction push to {}", uri);
+    PushDelivery::new(PushStatus::Failed, attempts)
+}
+
+fn finish_request(tx: &GnapTransaction) -> Option<&InteractFinishRequest> {
+    tx.request.as_ref()?.interact.as_ref()?.finish.as_ref()
+}
+
+/// The `hash` and `interact_ref` of a finished interaction.  Both are left
+/// out when the end-user denied the grant, and the client instance learns
+/// the outcome from its next continuation request.
+fn finish_parameters(
+    tx: &GnapTransaction,
+    finish: &InteractFinishRequest,
+) -> Option<InteractFinishPush> {
+    if matches!(tx.state, GnapTransactionState::Denied) {
+        return Some(InteractFinishPush::default());
+    }
+    let finish_nonce = tx.finish_nonce.as_ref()?;
+    let interact_ref = tx.interact_ref.clone()?;
+    let hash = finish.hash(finish_nonce, &interact_ref, GRANT_ENDPOINT);
+    Some(InteractFinishPush {
+        hash: Some(hash),
+        interact_ref: Some(interact_ref),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use model::grant::{GrantRequest, HashMethod};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn transaction(method: InteractFinishMethodType) -> GnapTransaction {
+        let request = GrantRequest {
+            access_token: vec![],
+            subject: None,
+            client: None,
+            user: None,
+            interact: Some(InteractRequest {
+                start: vec![InteractStartMode::Redirect],
+                finish: Some(InteractFinishRequest {
+                    method,
+                    uri: "https://client.example.net/return?session=1".to_owned(),
+                    nonce: "VJLO6A4CAYLBXHTR0KRO".to_owned(),
+                    hash_method: Some(HashMethod::Sha3_512),
+                }),
+            }),
+        };
+        GnapTransaction::new(Some(request))
+            .transition(GnapTransactionState::ClientVerified)
+            .and_then(|tx| tx.transition(GnapTransactionState::Waiting))
+            .unwrap()
+    }
+
+    #[test]
+    fn redirect_with_hash() {
+        let tx = transaction(InteractFinishMethodType::Redirect);
+        assert!(finish_redirect(&tx).is_none());
+
+        let tx = tx.finish_interaction();
+        let finish = finish_request(&tx).unwrap();
+        let redirect = Url::parse(&finish_redirect(&tx).unwrap()).unwrap();
+        let query: Vec<(String, String)> = redirect.query_pairs().into_owned().collect();
+
+        let interact_ref = tx.interact_ref.clone().unwrap();
+        let hash = finish.hash(
+            tx.finish_nonce.as_ref().unwrap(),
+            &interact_ref,
+            GRANT_ENDPOINT,
+        );
+        assert_eq!(
+            query,
+            vec![
+                ("session".to_owned(), "1".to_owned()),
+                ("hash".to_owned(), hash),
+                ("interact_ref".to_owned(), interact_ref),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_redirect_for_push() {
+        let tx = transaction(InteractFinishMethodType::Push).finish_interaction();
+        assert!(finish_redirect(&tx).is_none());
+    }
+
+    #[test]
+    fn push_with_hash() {
+        let tx = transaction(InteractFinishMethodType::Push);
+        assert!(finish_push(&tx).is_none());
+
+        let tx = tx.finish_interaction();
+        let (uri, body) = finish_push(&tx).unwrap();
+        let finish = finish_request(&tx).unwrap();
+        assert_eq!(uri, finish.uri);
+        let interact_ref = tx.interact_ref.clone().unwrap();
+        assert_eq!(
+            body.hash,
+            Some(finish.hash(
+                tx.finish_nonce.as_ref().unwrap(),
+                &interact_ref,
+                GRANT_ENDPOINT
+            ))
+        );
+        assert_eq!(body.interact_ref, Some(interact_ref));
+        assert!(finish_redirect(&tx).is_none());
+    }
+
+    #[test]
+    fn finish_denied_interaction() {
+        let tx = transaction(InteractFinishMethodType::Redirect)
+            .deny_interaction()
+            .unwrap();
+        assert_eq!(
+            finish_redirect(&tx).unwrap(),
+            "https://client.example.net/return?session=1"
+        );
+
+        let tx = transaction(InteractFinishMethodType::Push)
+            .deny_interaction()
+            .unwrap();
+        let (_, body) = finish_push(&tx).unwrap();
+        assert_eq!(body, InteractFinishPush::default());
+        assert_eq!(serde_json::to_string(&body).unwrap(), "{}");
+    }
+
+    /// A stand-in for the client instance push endpoint, that fails the first
+    /// `failures` requests.
+    fn receiver(
+        failures: usize,
+    ) -> (
+        String,
+        Arc<Mutex<Vec<InteractFinishPush>>>,
+        actix_web::dev::Server,
+    ) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let data = received.clone();
+        let server = HttpServer::new(move || {
+            let data = data.clone();
+            let calls = calls.clone();
+            App::new().route(
+                "/push",
+                web::post().to(move |body: web::Json<InteractFinishPush>| {
+                    let data = data.clone();
+                    let calls = calls.clone();
+                    async move {
+                        if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                            return HttpResponse::ServiceUnavailable().finish();
+                        }
+                        data.lock().unwrap().push(body.into_inner());
+                        HttpResponse::Ok().finish()
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let uri = format!("http://{}/push", server.addrs()[0]);
+        (uri, received, server.run())
+    }
+
+    fn push_body() -> InteractFinishPush {
+        InteractFinishPush {
+            hash: Some(
+                "p28jsq0Y2KK3WS__a42tavNC64ldGTBroywsWxT4md_jZQ1R2HZT8BOWYHcLmObM7XHPAdJzTZMtKBsaraJ64A"
+                    .to_owned(),
+            ),
+            interact_ref: Some("4IFWWIKYB2PQ6U56NL1".to_owned()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn send_push_retries() {
+        let (uri, received, server) = receiver(2);
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let delivery = send_push(&uri, &push_body(), 3, Duration::from_millis(10)).await;
+        assert_eq!(delivery.status, PushStatus::Delivered);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(*received.lock().unwrap(), vec![push_body()]);
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn send_push_gives_up() {
+        let (uri, received, server) = receiver(usize::MAX);
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let delivery = send_push(&uri, &push_body(), 2, Duration::from_millis(10)).await;
+        assert_eq!(delivery.status, PushStatus::Failed);
+        assert_eq!(delivery.attempts, 2);
+        assert!(received.lock().unwrap().is_empty());
+        handle.stop(true).await;
+    }
+
+    #[test]
+    fn respond_with_user_code() {
+        let mut request = transaction(InteractFinishMethodType::Redirect)
+            .request
+            .unwrap();
+        request.interact.as_mut().unwrap().start = vec![InteractStartMode::UserCode];
+        let tx = GnapTransaction::new(Some(request.clone()));
+
+        let response = interaction_response(&tx, request.interact.as_ref()).unwrap();
+        let user_code = response.user_code.unwrap();
+        assert_eq!(Some(user_code.code), tx.user_code);
+        assert_eq!(user_code.uri, DEVICE_URI);
+        assert!(response.redirect.is_none());
+    }
+
+    #[test]
+    fn respond_with_app_uri() {
+        let mut request = transaction(InteractFinishMethodType::Redirect)
+            .request
+            .unwrap();
+        request.interact.as_mut().unwrap().start = vec![InteractStartMode::App];
+        let tx = GnapTransaction::new(Some(request.clone()));
+
+        let response = interaction_response(&tx, request.interact.as_ref()).unwrap();
+        let code = tx.app_code.clone().unwrap();
+        assert_eq!(response.app, Some(format!("{}/{}", APP_URI, code)));
+        assert!(response.user_code.is_none());
+        assert!(tx.user_code.is_none());
+    }
+
+    #[test]
+    fn respond_with_finish_nonce() {
+        let tx = transaction(InteractFinishMethodType::Redirect);
+        let request = tx.request.clone().unwrap();
+        let response = interaction_response(&tx, request.interact.as_ref()).unwrap();
+        assert_eq!(response.finish, tx.finish_nonce);
+        assert!(response.finish.is_some());
+        assert_eq!(
+            response.redirect,
+            Some(format!("http://localhost:8000/gnap/auth/{}", tx.tx_id))
+        );
+    }
+}
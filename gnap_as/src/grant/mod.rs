@@ -1 +1,12 @@
+pub mod consent;
+pub mod interaction;
 pub mod request;
+pub mod subject;
+pub mod token;
+pub mod userinfo;
+
+/// The issuer identifier of the AS, as in signed tokens.
+pub const ISSUER: &str = "http://localhost:8000";
+
+/// The grant endpoint of the AS.  Continuation URIs are relative to it.
+pub const GRANT_ENDPOINT: &str = "http://localhost:8000/gnap/tx";
@@ -1,63 +1,89 @@
+use super::interaction::{interaction_response, register_codes};
+use super::subject::{subject_response, verify_subject_request};
+use super::token::{access_token, encode_token, token_format};
+use super::GRANT_ENDPOINT;
+use crate::proof::{mtls, verify_client_key, verify_transaction_client, RequestProof};
 use dao::service::Service;
+use dao::token_service::TokenService;
 use errors::GnapError;
-use log::{error, trace, debug};
-use model::tokens::TokenBuilder;
-use model::transaction::GnapTransactionState::*;
+use log::{debug, error, trace};
+use model::client::GnapClient;
+use model::key::{ClientKey, KeyProofMethod};
+use model::tokens::{Token, TokenBuilder};
+use model::transaction::{GnapTransaction, GnapTransactionState::*, POLL_WAIT};
 use model::{grant::*, GnapID};
+use openssl::memcmp;
+
 pub async fn process_request(
     service: &Service,
     request: GrantRequest,
+    proof: &RequestProof,
 ) -> Result<GrantResponse, GnapError> {
-    // A valid request?
-    if request.client.is_none() {
-        // No client identifier
-        error!("No client id in grant request");
-        return Err(GnapError::BadData);
-    }
-    // This will fail if either there is no client_id in the request, or the
-    // client_id is not a valid uuid.
-    trace!("getting id from reqeust...");
-    let client_id = request.parse_id()?;
-    trace!("parsed id from request: {}", client_id.to_string());
-    // This will fail if the client_id provided in the request is not found.
-    let _client = service.get_client(&client_id).await?.unwrap();
-
-    // At this point, we have determined that the request contains a valid client_id
-    // and the client data was found.  Now we can compare request data against
+    // A valid request?  The client instance is either a reference to a
+    // registered client, or presented by value with its key.
+    let (client, instance_id) = match request.client.clone() {
+        None => {
+            // No client identifier
+            error!("No client id in grant request");
+            return Err(GnapError::BadData);
+        }
+        Some(GnapClientInstance::Ref(_)) => {
+            // This will fail if the client_id is not a valid uuid.
+            trace!("getting id from reqeust...");
+            let client_id = request.parse_id()?;
+            trace!("parsed id from request: {}", client_id.to_string());
+            // This will fail if the client_id provided in the request is not found.
+            let client = service
+                .get_client(&client_id)
+                .await?
+                .ok_or(GnapError::NotFound)?;
+            // The request must be signed with the key registered for the client.
+            verify_client_key(service, proof, client.key.as_ref()).await?;
+            (client, None)
+        }
+        Some(GnapClientInstance::Value {
+            key,
+            class_id,
+            display,
+        }) => {
+            // The key presented in the request must also sign it.  Only
+            // persist the instance once possession of the key is proven, and
+            // only once per key.
+            verify_client_key(service, proof, Some(&key)).await?;
+            let client = service
+                .register_instance(GnapClient::from_instance(*key, class_id, display))
+                .await?;
+            trace!("client instance: {}", client.client_id);
+            let instance_id = Some(client.client_id.to_string());
+            (client, instance_id)
+        }
+    };
+
+    // At this point, we have determined that the request identifies a known
+    // client instance holding the key.  Now we can compare request data against
     // the authorized client.
 
+    // The transaction refers to the registered client from here on, so that
+    // continuation requests can find its key.
+    let request = request.client_ref(&client.client_id);
+
+    // Subject information the client can't receive is rejected up front.
+    if let Some(subject) = &request.subject {
+        verify_subject_request(subject, &client)?;
+    }
+
     // Verify the request data against client config, etc.
 
     // Start a transaction
     let tx = service.start_transaction(request.clone()).await?;
-
-    let uri = format!("http://localhost:8000/gnap/tx/{}", &tx.tx_id);
-    let rc = RequestContinuation::as_uri(&uri);
-    let mut interact_response = InteractResponse {
-        tx_continue: rc,
-        redirect: None,
-    };
-
-    // What are the interaction methods?
-    for method in request.interact.unwrap().start.iter() {
-        match method {
-            InteractStartMode::Redirect => {
-                trace!("GrantRequest interaction contains Redirect");
-                interact_response.redirect = Some(uri.clone());
-            }
-            InteractStartMode::App => {
-                trace!("GrantRequest interaction contains App");
-            }
-            InteractStartMode::UserCode => {
-                trace!("GrantRequest interaction contains UserCode");
-            }
-        }
-    }
+    register_codes(service, &tx).await?;
 
     let response = GrantResponse {
-        instance_id: tx.tx_id,
-        interact: Some(interact_response),
+        instance_id,
+        tx_continue: Some(continuation(&tx)),
+        interact: interaction_response(&tx, request.interact.as_ref()),
         access_token: None,
+        subject: None,
     };
 
     Ok(response)
@@ -65,58 +91,368 @@ pub async fn process_request(
 
 pub async fn process_continue_request(
     service: &Service,
+    token_service: &TokenService,
     tx_id: String,
+    interact_ref: Option<String>,
+    proof: &RequestProof,
 ) -> Result<GrantResponse, GnapError> {
-    let tx = match service.get_transaction(tx_id.clone()).await {
-        Ok(data) => data,
-        Err(err) => return Err(err),
-    };
+    let (tx, client) = continue_transaction(service, tx_id, proof).await?;
 
     match tx.state {
         Authorized => {
+            verify_interact_ref(&tx, interact_ref.as_deref())?;
+            // The continuation access token is rotated on every continuation
+            // response, and the interaction reference can't be used again.
+            // Nothing is saved until the response is ready, so a failed
+            // request can be retried.
+            let tx = tx
+                .transition(Issued)?
+                .rotate_continue_token()
+                .clear_interact_ref();
+            let subject = subject_response(service, &tx, &client).await?;
+            let tokens = build_tokens(service, &tx, &client, proof).await?;
+            let tx = save_issued(service, token_service, tx, &tokens).await?;
 
-            // only create one token for the first access..
-            // this should be able to handle multiple token.
-            // if there are mutiple access_requests, then there should be generated multiple tokens.
-            // and it has to have a unique label for each.
-            //let t = Token::create(tx_id.clone());
-            
-
-            let mut access_tokens = Vec::<AccessToken>::new();
-            let grantrequest = tx.request.clone().unwrap();
-            for grant_token in grantrequest.access_token {
-                let label = grant_token.label;
-                debug!("{:#?}", label);
-                let t = TokenBuilder::new(tx_id.clone())
-                                            .label(label.clone())
-                                            .build();
-                let _ = service.store_token(t.clone())
-                            .await
-                            .expect("Failed to store token");
-                let at = AccessToken {
-                    label,
-                    value: t.access_token.unwrap(),
-                    manage: Some(format!(
-                        "http://localhost:8000/gnap/token/{}",
-                        &t.id.to_owned()
-                    )),
-                    access: Some(grant_token.access.to_owned()),
-                    key: None,
-                    expires_in: t.expire,
-                    flags: Some(vec![AccessTokenFlag::Bearer]),
-                };
-               
-                access_tokens.push(at)
-            }
-            //let tokenrequest = grantrequest.access_token.first().unwrap();
-            
             let gr = GrantResponse {
-                instance_id: tx.tx_id.clone(),
+                instance_id: None,
+                tx_continue: Some(continuation(&tx)),
                 interact: None,
-                access_token: Some(access_tokens), // missing subject
+                access_token: Some(tokens.iter().map(access_token).collect()),
+                subject,
             };
             Ok(gr)
         }
-        _ => Err(GnapError::BadData),
+        Waiting => {
+            // The client instance is polling.  Tell it to wait again, unless
+            // it did not respect the previous wait.
+            if interact_ref.is_some() {
+                debug!("Interaction for transaction {} has not finished", tx.tx_id);
+                return Err(GnapError::UnknownInteraction);
+            }
+            if tx.polled_too_fast() {
+                debug!("Client polled transaction {} too fast", tx.tx_id);
+                return Err(GnapError::TooFast);
+            }
+            let tx = service
+                .update_transaction(tx.record_poll().rotate_continue_token())
+                .await?;
+
+            Ok(GrantResponse {
+                instance_id: None,
+                tx_continue: Some(continuation(&tx)),
+                interact: None,
+                access_token: None,
+                subject: None,
+            })
+        }
+        Denied => {
+            debug!("End-user denied transaction {}", tx.tx_id);
+            Err(GnapError::UserDenied)
+        }
+        // Tokens are only issued once, from an authorized grant.
+        _ => {
+            let transition = format!("{:?} -> {:?}", tx.state, Issued);
+            debug!(
+                "Illegal transition of transaction {}: {}",
+                tx.tx_id, transition
+            );
+            Err(GnapError::InvalidTransition(transition))
+        }
+    }
+}
+
+/// Modify the requested access of an existing grant.
+///
+/// Reduced rights are re-validated against the user that authorized the grant
+/// and issued right away.  Expanded rights need new consent, so the grant goes
+/// back to waiting for interaction.  Either way, the tokens issued for the old
+/// rights are revoked, once the modified grant is saved.
+pub async fn process_modify_request(
+    service: &Service,
+    token_service: &TokenService,
+    tx_id: String,
+    modification: GrantModificationRequest,
+    proof: &RequestProof,
+) -> Result<GrantResponse, GnapError> {
+    let (tx, client) = continue_transaction(service, tx_id, proof).await?;
+    let request = tx.request.clone().ok_or(GnapError::BadData)?;
+    let interact = modification.interact.or_else(|| request.interact.clone());
+    let expanded = request.is_expanded_by(&modification.access_token);
+    let request = request.update_access_token(modification.access_token);
+
+    let authorized = matches!(tx.state, Authorized | Issued);
+    if authorized && !expanded {
+        trace!("Grant modification reduces the granted access");
+        let tx = match tx.state {
+            Issued => tx.transition(Authorized)?,
+            _ => tx,
+        };
+        let tx = service
+            .reauthorize_transaction(tx.update_grantrequest(request))
+            .await?;
+        let tx = tx.transition(Issued)?.rotate_continue_token();
+        let tokens = build_tokens(service, &tx, &client, proof).await?;
+        let tx = save_issued(service, token_service, tx, &tokens).await?;
+        revoke_replaced(token_service, &tx, &tokens).await;
+
+        return Ok(GrantResponse {
+            instance_id: None,
+            tx_continue: Some(continuation(&tx)),
+            interact: None,
+            access_token: Some(tokens.iter().map(access_token).collect()),
+            subject: None,
+        });
+    }
+
+    trace!("Grant modification needs interaction");
+    let request = GrantRequest {
+        interact: interact.clone(),
+        ..request
+    };
+    let tx = service
+        .update_transaction(
+            tx.update_grantrequest(request)
+                .transition(Waiting)?
+                .start_interaction()
+                .record_poll()
+                .rotate_continue_token(),
+        )
+        .await?;
+    register_codes(service, &tx).await?;
+    if authorized {
+        revoke_replaced(token_service, &tx, &[]).await;
+    }
+
+    Ok(GrantResponse {
+        instance_id: None,
+        tx_continue: Some(continuation(&tx)),
+        interact: interaction_response(&tx, interact.as_ref()),
+        access_token: None,
+        subject: None,
+    })
+}
+
+/// Cancel a grant, and revoke every token issued for it.
+pub async fn process_delete_request(
+    service: &Service,
+    token_service: &TokenService,
+    tx_id: String,
+    proof: &RequestProof,
+) -> Result<(), GnapError> {
+    let (tx, _) = continue_transaction(service, tx_id, proof).await?;
+    token_service
+        .revoke_tx_tokens(&tx.tx_id)
+        .await
+        .map_err(|_| GnapError::GeneralError)?;
+    service.delete_transaction(tx.tx_id).await
+}
+
+/// Revoke the tokens issued for the old rights of a modified grant.  The
+/// modification is already saved, so a failure is logged rather than
+/// returned; the old tokens still expire.
+async fn revoke_replaced(token_service: &TokenService, tx: &GnapTransaction, current: &[Token]) {
+    if let Err(err) = token_service
+        .revoke_replaced_tokens(&tx.tx_id, current)
+        .await
+    {
+        error!(
+            "Failed to revoke replaced tokens of {}: {:?}",
+            tx.tx_id, err
+        );
+    }
+}
+
+/// Load the transaction of a continuation request.  Continuation requests must
+/// be signed with the same key as the grant request, and carry the current
+/// continuation access token.
+async fn continue_transaction(
+    service: &Service,
+    tx_id: String,
+    proof: &RequestProof,
+) -> Result<(GnapTransaction, GnapClient), GnapError> {
+    let tx = service.get_transaction(tx_id).await?;
+    let client = verify_transaction_client(service, &tx, proof).await?;
+    verify_continue_token(&tx, proof)?;
+    Ok((tx, client))
+}
+
+/// Tokens are bound to the key of the client instance.  Tokens issued over
+/// mutual TLS are bound to the certificate presented on the connection.
+fn bound_key(client: &GnapClient, proof: &RequestProof) -> Option<ClientKey> {
+    match client.key.as_ref() {
+        Some(key) if key.proof == KeyProofMethod::Mtls => mtls::bound_key(proof),
+        key => key.cloned(),
+    }
+}
+
+/// Build a token for each access token request of an authorized grant.  The
+/// tokens are not stored yet.
+async fn build_tokens(
+    service: &Service,
+    tx: &GnapTransaction,
+    client: &GnapClient,
+    proof: &RequestProof,
+) -> Result<Vec<Token>, GnapError> {
+    let bound_key = bound_key(client, proof);
+    // This should be able to handle multiple token.  If there are mutiple
+    // access_requests, then there should be generated multiple tokens, and
+    // each has to have a unique label.
+    let mut tokens = Vec::<Token>::new();
+    let grantrequest = tx.request.clone().ok_or(GnapError::BadData)?;
+    for grant_token in grantrequest.access_token {
+        let label = grant_token.label;
+        debug!("{:#?}", label);
+        // A token bound to the client instance key is not a bearer token,
+        // unless the client instance asked for one.  A durable token is
+        // issued when asked for.
+        let requested = |flag: AccessTokenFlag| {
+            grant_token
+                .flags
+                .as_ref()
+                .map(|flags| flags.contains(&flag))
+                .unwrap_or(false)
+        };
+        let key = bound_key
+            .clone()
+            .filter(|_| !requested(AccessTokenFlag::Bearer));
+        let mut flags = Vec::new();
+        if key.is_none() {
+            flags.push(AccessTokenFlag::Bearer);
+        }
+        if requested(AccessTokenFlag::Durable) {
+            flags.push(AccessTokenFlag::Durable);
+        }
+        let lifetime = service
+            .token_lifetimes
+            .lifetime(client.access_token_lifetime, &grant_token.access);
+        let servers = service.resource_servers(&grant_token.access).await?;
+        let t = TokenBuilder::new(tx.tx_id.clone())
+            .label(label)
+            .lifetime(Some(lifetime))
+            .key(key)
+            .access(grant_token.access)
+            .flags(flags)
+            .format(token_format(client, &servers))
+            .build();
+        tokens.push(encode_token(service, tx, client, &servers, t)?);
+    }
+    Ok(tokens)
+}
+
+/// Store the tokens issued for a grant, then save the grant.  The tokens are
+/// removed again if the grant can't be saved, so the client instance can
+/// retry with the continuation token it holds.
+async fn save_issued(
+    service: &Service,
+    token_service: &TokenService,
+    tx: GnapTransaction,
+    tokens: &[Token],
+) -> Result<GnapTransaction, GnapError> {
+    token_service.store_tokens(tokens).await.map_err(|err| {
+        error!("Failed to store tokens for {}: {:?}", tx.tx_id, err);
+        GnapError::GeneralError
+    })?;
+    match service.update_transaction(tx).await {
+        Ok(tx) => Ok(tx),
+        Err(err) => {
+            if let Err(revoke) = token_service.revoke_tokens(tokens).await {
+                error!("Failed to remove unsaved tokens: {:?}", revoke);
+            }
+            Err(err)
+        }
+    }
+}
+
+fn continue_uri(tx: &GnapTransaction) -> String {
+    format!("{}/{}", GRANT_ENDPOINT, &tx.tx_id)
+}
+
+/// The continuation info for a transaction, with its current access token.
+/// While the grant is waiting, the client instance is told how long to wait
+/// before polling.
+fn continuation(tx: &GnapTransaction) -> RequestContinuation {
+    let rc = RequestContinuation::as_uri(&continue_uri(tx));
+    let rc = match tx.state {
+        Waiting => rc.with_wait(POLL_WAIT),
+        _ => rc,
+    };
+    match &tx.continue_token {
+        Some(token) => rc.with_access_token(token.clone()),
+        None => rc,
+    }
+}
+
+/// Continuation requests present the continuation access token with
+/// `Authorization: GNAP`.
+fn verify_continue_token(tx: &GnapTransaction, proof: &RequestProof) -> Result<(), GnapError> {
+    match (proof.access_token(), tx.continue_token.as_deref()) {
+        (Some(presented), Some(expected))
+            if presented.len() == expected.len()
+                && memcmp::eq(presented.as_bytes(), expected.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            debug!("Continuation access token missing or invalid");
+            Err(GnapError::InvalidContinuation)
+        }
+    }
+}
+
+/// A client instance that asked for an interaction finish method must present
+/// the interaction reference it received.  A client instance without one
+/// polls, and presents none.
+fn verify_interact_ref(tx: &GnapTransaction, presented: Option<&str>) -> Result<(), GnapError> {
+    match (presented, tx.interact_ref.as_deref()) {
+        (None, None) if tx.finish_nonce.is_none() => Ok(()),
+        (Some(presented), Some(expected))
+            if presented.len() == expected.len()
+                && memcmp::eq(presented.as_bytes(), expected.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            debug!("Interaction reference missing or invalid");
+            Err(GnapError::UnknownInteraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+
+    fn client(key: &str) -> GnapClient {
+        let mut client = GnapClient::new(Vec::new(), "test".to_owned());
+        client.key = Some(serde_json::from_str(key).unwrap());
+        client
+    }
+
+    fn proof(certificate: Option<&[u8]>) -> RequestProof {
+        let mut proof =
+            RequestProof::from_parts(&TestRequest::post().to_http_request(), Bytes::new());
+        proof.peer_certificate = certificate.map(|der| mtls::PeerCertificate(der.to_vec()));
+        proof
+    }
+
+    #[test]
+    fn bind_to_client_key() {
+        for method in ["httpsig", "jwsd", "jws"] {
+            let client = client(&format!(
+                r#"{{"proof": "{}", "jwk": {{"kty": "OKP", "crv": "Ed25519", "x": "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"}}}}"#,
+                method
+            ));
+            assert_eq!(bound_key(&client, &proof(None)), client.key);
+        }
+    }
+
+    #[test]
+    fn bind_to_presented_certificate() {
+        let client = client(r#"{"proof": "mtls", "cert#S256": "registered"}"#);
+        let bound = bound_key(&client, &proof(Some(b"certificate"))).unwrap();
+        assert_eq!(bound.cert_s256, Some(mtls::thumbprint(b"certificate")));
+        assert!(bound_key(&client, &proof(None)).is_none());
     }
 }
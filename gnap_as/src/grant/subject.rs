@@ -0,0 +1,317 @@
+//! Subject information
+//!
+//! Once a grant is approved, the AS returns identifiers for the end-user in
+//! the formats the client instance asked for.  They are built from the user
+//! that authorized the grant, and the account linked to it, and are only
+//! released when the RO consented to it.  An OpenID Connect ID token can be
+//! requested as an assertion.  It carries the profile of the end-user, such
+//! as their name, and the claims behind the released identifiers.
+use super::ISSUER;
+use dao::service::Service;
+use errors::GnapError;
+use log::{debug, error};
+use model::account::Account;
+use model::client::GnapClient;
+use model::grant::{
+    SubjectAssertion, SubjectAssertionType, SubjectFormatType, SubjectIdentifier, SubjectRequest,
+    SubjectResponse,
+};
+use model::oidc::{filter_claims, id_token_algorithm, IdTokenClaims};
+use model::rfc3339;
+use model::transaction::GnapTransaction;
+use model::users::User;
+use serde_json::{Map, Value};
+use url::Url;
+
+/// The formats that can be listed as aliases.
+const ALIAS_FORMATS: [SubjectFormatType; 6] = [
+    SubjectFormatType::IssSub,
+    SubjectFormatType::Opaque,
+    SubjectFormatType::Email,
+    SubjectFormatType::PhoneNumber,
+    SubjectFormatType::Account,
+    SubjectFormatType::Did,
+];
+
+/// The JWT type of ID tokens.
+const ID_TOKEN_TYPE: &str = "JWT";
+
+/// The subject information for an approved grant, if the client instance
+/// asked for it and the RO consented to releasing it.
+pub async fn subject_response(
+    service: &Service,
+    tx: &GnapTransaction,
+    client: &GnapClient,
+) -> Result<Option<SubjectResponse>, GnapError> {
+    let request = tx.request.as_ref().ok_or(GnapError::BadData)?;
+    let (subject, user_id) = match (&request.subject, &request.user) {
+        (Some(subject), Some(user_id)) => (subject, user_id),
+        _ => return Ok(None),
+    };
+    if !tx.subject_consent {
+        debug!("No consent to release the subject of {}", tx.tx_id);
+        return Ok(None);
+    }
+
+    let user = service
+        .get_user(user_id)
+        .await?
+        .ok_or(GnapError::NotFound)?;
+    let account = match &user.account_id {
+        Some(account_id) => service.get_account(account_id).await?,
+        None => None,
+    };
+
+    let formats = subject.formats.as_deref().unwrap_or_default();
+    let sub_ids = subject_identifiers(formats, &user, account.as_ref());
+    let mut assertions = Vec::new();
+    for format in subject.assertions.as_deref().unwrap_or_default() {
+        match format {
+            SubjectAssertionType::IdToken => {
+                let claims = identity_claims(formats, account.as_ref());
+                assertions.push(id_token(service, client, &user, claims)?)
+            }
+            _ => debug!("Unsupported subject assertion {:?}", format),
+        }
+    }
+    let updated_at = account
+        .as_ref()
+        .and_then(Account::updated_at)
+        .map(rfc3339)
+        .transpose()?;
+    if sub_ids.is_empty() && assertions.is_empty() && updated_at.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(SubjectResponse {
+        sub_ids: (!sub_ids.is_empty()).then_some(sub_ids),
+        assertions: (!assertions.is_empty()).then_some(assertions),
+        updated_at,
+    }))
+}
+
+/// Identifiers for the user in each of the formats it has one in.
+pub fn subject_identifiers(
+    formats: &[SubjectFormatType],
+    user: &User,
+    account: Option<&Account>,
+) -> Vec<SubjectIdentifier> {
+    formats
+        .iter()
+        .filter_map(|format| identifier(*format, user, account))
+        .collect()
+}
+
+fn identifier(
+    format: SubjectFormatType,
+    user: &User,
+    account: Option<&Account>,
+) -> Option<SubjectIdentifier> {
+    match format {
+        SubjectFormatType::Opaque => Some(SubjectIdentifier::Opaque {
+            id: user.id.clone(),
+        }),
+        SubjectFormatType::IssSub => Some(SubjectIdentifier::IssSub {
+            iss: ISSUER.to_owned(),
+            sub: user.id.clone(),
+        }),
+        SubjectFormatType::Email => account?.email().map(|email| SubjectIdentifier::Email {
+            email: email.to_owned(),
+        }),
+        SubjectFormatType::PhoneNumber => {
+            account?
+                .phone_number()
+                .map(|phone_number| SubjectIdentifier::PhoneNumber {
+                    phone_number: phone_number.to_owned(),
+                })
+        }
+        SubjectFormatType::Account => {
+            let issuer = Url::parse(ISSUER).ok()?;
+            Some(SubjectIdentifier::Account {
+                uri: format!("acct:{}@{}", user.username, issuer.host_str()?),
+            })
+        }
+        SubjectFormatType::Did => account?.did().map(|did| SubjectIdentifier::Did {
+            url: did.to_owned(),
+        }),
+        SubjectFormatType::Aliases => {
+            let identifiers = subject_identifiers(&ALIAS_FORMATS, user, account);
+            (!identifiers.is_empty()).then_some(SubjectIdentifier::Aliases { identifiers })
+        }
+    }
+}
+
+/// Check that the subject information asked for can be released to the
+/// client, before the grant is started.
+pub fn verify_subject_request(
+    subject: &SubjectRequest,
+    client: &GnapClient,
+) -> Result<(), GnapError> {
+    if id_token_requested(subject) {
+        id_token_algorithm(client.id_token_signed_response_alg)?;
+    }
+    Ok(())
+}
+
+/// What the end-user is asked to release, as listed on the consent page.
+pub fn released_information(subject: &SubjectRequest) -> Vec<&'static str> {
+    let formats = subject.formats.as_deref().unwrap_or_default();
+    let requested =
+        |format| formats.contains(&format) || formats.contains(&SubjectFormatType::Aliases);
+    let mut released = Vec::new();
+    if !formats.is_empty() || subject.assertions.is_some() {
+        released.push("your account identifier");
+    }
+    if id_token_requested(subject) {
+        released.push("your name and profile");
+    }
+    if requested(SubjectFormatType::Email) {
+        released.push("your email address");
+    }
+    if requested(SubjectFormatType::PhoneNumber) {
+        released.push("your phone number");
+    }
+    if requested(SubjectFormatType::Did) {
+        released.push("your decentralized identifier");
+    }
+    released
+}
+
+fn id_token_requested(subject: &SubjectRequest) -> bool {
+    subject
+        .assertions
+        .as_deref()
+        .unwrap_or_default()
+        .contains(&SubjectAssertionType::IdToken)
+}
+
+/// The claims of an ID token: the profile of the end-user, and the claims
+/// behind the released identifiers, the email address and phone number with
+/// their verification status.
+fn identity_claims(formats: &[SubjectFormatType], account: Option<&Account>) -> Map<String, Value> {
+    let account = match account {
+        Some(account) => account,
+        None => return Map::new(),
+    };
+    let data_types: Vec<String> = std::iter::once("profile")
+        .chain(formats.iter().flat_map(|format| match format {
+            SubjectFormatType::Email => vec!["email"],
+            SubjectFormatType::PhoneNumber => vec!["phone"],
+            SubjectFormatType::Aliases => vec!["email", "phone"],
+            _ => vec![],
+        }))
+        .map(str::to_owned)
+        .collect();
+    filter_claims(account.claims(), Some(&data_types))
+}
+
+/// An ID token for the user, signed with the algorithm the client
+/// registered.  It carries `sub`, and only the claims the RO consented to.
+fn id_token(
+    service: &Service,
+    client: &GnapClient,
+    user: &User,
+    claims: Map<String, Value>,
+) -> Result<SubjectAssertion, GnapError> {
+    let key = service.key_store.signing_key();
+    let algorithm = id_token_algorithm(client.id_token_signed_response_alg)?;
+    if algorithm != key.algorithm() {
+        error!(
+            "ID token algorithm {:?} does not match the signing key",
+            algorithm
+        );
+        return Err(GnapError::GeneralError);
+    }
+    let claims = IdTokenClaims::new(ISSUER, &user.id, &client.client_id.to_string(), claims);
+    Ok(SubjectAssertion {
+        format: SubjectAssertionType::IdToken,
+        value: key.sign(ID_TOKEN_TYPE, &claims)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::account::AccountRequest;
+
+    fn user() -> User {
+        User {
+            id: "6785732c-682a-458b-8465-2986a77abf6a".to_owned(),
+            username: "kenneth".to_owned(),
+            password: String::new(),
+            access: None,
+            account_id: None,
+        }
+    }
+
+    #[test]
+    fn identifiers_in_requested_formats() {
+        let user = user();
+        let formats = [
+            SubjectFormatType::IssSub,
+            SubjectFormatType::Account,
+            SubjectFormatType::Email,
+        ];
+        let ids = subject_identifiers(&formats, &user, None);
+        assert_eq!(
+            ids,
+            vec![
+                SubjectIdentifier::IssSub {
+                    iss: ISSUER.to_owned(),
+                    sub: user.id.clone(),
+                },
+                SubjectIdentifier::Account {
+                    uri: "acct:kenneth@localhost".to_owned(),
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&ids[0]).unwrap();
+        assert_eq!(json["format"], "iss_sub");
+        assert_eq!(json["sub"], user.id.as_str());
+    }
+
+    #[test]
+    fn only_released_claims() {
+        let account = Account::from(AccountRequest::new("Kenneth", "Fossen"));
+        let claims = identity_claims(&[SubjectFormatType::IssSub], Some(&account));
+        assert_eq!(claims["given_name"], "Kenneth");
+        assert_eq!(claims["family_name"], "Fossen");
+        assert!(claims.contains_key("name"));
+        assert!(!claims.contains_key("email"));
+        assert!(identity_claims(&[SubjectFormatType::Email], None).is_empty());
+
+        let subject = SubjectRequest {
+            formats: Some(vec![SubjectFormatType::IssSub, SubjectFormatType::Email]),
+            assertions: Some(vec![SubjectAssertionType::IdToken]),
+        };
+        assert_eq!(
+            released_information(&subject),
+            vec![
+                "your account identifier",
+                "your name and profile",
+                "your email address"
+            ]
+        );
+    }
+
+    #[test]
+    fn aliases() {
+        let account = Account::from(AccountRequest::new("Kenneth", "Fossen"));
+        let ids = subject_identifiers(&[SubjectFormatType::Aliases], &user(), Some(&account));
+        match ids.as_slice() {
+            [SubjectIdentifier::Aliases { identifiers }] => {
+                let formats: Vec<SubjectFormatType> =
+                    identifiers.iter().map(SubjectIdentifier::format).collect();
+                assert_eq!(
+                    formats,
+                    vec![
+                        SubjectFormatType::IssSub,
+                        SubjectFormatType::Opaque,
+                        SubjectFormatType::Account,
+                    ]
+                );
+            }
+            _ => panic!("expected aliases, got {:?}", ids),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Token management
+//!
+//! Every issued access token has a management URI, where the client instance
+//! rotates or revokes it (GNAP Section 6).  Management requests carry the
+//! current token value, and are signed by the client instance the token was
+//! issued to.
+//!
+//! A token is either opaque, or a JWT or PASETO token signed by the AS that a
+//! resource server validates without calling the AS.
+use super::ISSUER;
+use crate::proof::{verify_transaction_client, RequestProof};
+use dao::service::Service;
+use dao::token_service::TokenService;
+use errors::{GnapError, TokenError};
+use log::{debug, error};
+use model::client::GnapClient;
+use model::grant::AccessToken;
+use model::resource::GnapResourceServer;
+use model::tokens::{AccessTokenClaims, Token, TokenFormat};
+use model::transaction::GnapTransaction;
+use openssl::memcmp;
+
+/// The token management endpoint of the AS.
+pub const TOKEN_ENDPOINT: &str = "http://localhost:8000/gnap/token";
+
+/// The `typ` header of JWT access tokens (RFC 9068).
+const JWT_TYPE: &str = "at+jwt";
+
+/// The management URI of a token.  It never contains the token value.
+pub fn manage_uri(token: &Token) -> String {
+    format!("{}/{}", TOKEN_ENDPOINT, token.id)
+}
+
+/// The access token response for an issued token.
+pub fn access_token(token: &Token) -> AccessToken {
+    AccessToken {
+        value: token.access_token.clone().unwrap_or_default(),
+        label: token.label.clone(),
+        manage: Some(manage_uri(token)),
+        access: token.access.clone(),
+        expires_in: token
+            .expires_in()
+            .map(|secs| u32::try_from(secs).unwrap_or(u32::MAX)),
+        // A token bound to the client instance key is not a bearer token,
+        // and its key is omitted.
+        key: None,
+        flags: token.flags.clone().filter(|flags| !flags.is_empty()),
+    }
+}
+
+/// The format of a new token.  A resource server can require a format for its
+/// resources, otherwise the choice of the client instance applies.
+pub fn token_format(client: &GnapClient, servers: &[GnapResourceServer]) -> TokenFormat {
+    servers
+        .iter()
+        .find_map(|rs| rs.token_format)
+        .or(client.access_token_format)
+        .unwrap_or_default()
+}
+
+/// Set the value of a token for its format.  A JWT or PASETO token carries
+/// the grant, with the resource servers as its audience.  Opaque values are
+/// kept.
+pub fn encode_token(
+    service: &Service,
+    tx: &GnapTransaction,
+    client: &GnapClient,
+    servers: &[GnapResourceServer],
+    token: Token,
+) -> Result<Token, GnapError> {
+    if token.format == TokenFormat::Opaque {
+        return Ok(token);
+    }
+    let audience = servers
+        .iter()
+        .map(|rs| rs.resource_server.clone())
+        .collect();
+    let claims = AccessTokenClaims::new(&token, ISSUER, &subject(tx, client), audience);
+    let value = match token.format {
+        TokenFormat::Paseto => service.key_store.paseto_key().sign(&claims.paseto()?)?,
+        _ => service.key_store.signing_key().sign(JWT_TYPE, &claims)?,
+    };
+    Ok(Token {
+        access_token: Some(value),
+        ..token
+    })
+}
+
+/// The subject of a grant: the end-user who approved it, or the client
+/// instance when no end-user was involved.
+fn subject(tx: &GnapTransaction, client: &GnapClient) -> String {
+    tx.request
+        .as_ref()
+        .and_then(|request| request.user.clone())
+        .unwrap_or_else(|| client.client_id.to_string())
+}
+
+/// Load the token of a management request, and verify the request is signed
+/// by the client instance the token was issued to.  Returns the token with
+/// its grant and client.
+pub async fn load_token(
+    service: &Service,
+    token_service: &TokenService,
+    token_id: &str,
+    proof: &RequestProof,
+) -> Result<(Token, GnapTransaction, GnapClient), GnapError> {
+    let token = token_service
+        .db_client
+        .fetch_token_by_id(token_id.to_owned())
+        .await
+        .map_err(|_| GnapError::NotFound)?;
+    let tx_id = token.tx.clone().ok_or(GnapError::NotFound)?;
+    let tx = service.get_transaction(tx_id).await?;
+    let client = verify_transaction_client(service, &tx, proof).await?;
+    Ok((token, tx, client))
+}
+
+/// The request must carry the current value of the token.
+pub fn verify_token_value(token: &Token, proof: &RequestProof) -> Result<(), GnapError> {
+    match (proof.access_token(), token.access_token.as_deref()) {
+        (Some(presented), Some(expected))
+            if presented.len() == expected.len()
+                && memcmp::eq(presented.as_bytes(), expected.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => {
+            debug!("Access token missing or invalid for token {}", token.id);
+            Err(GnapError::InvalidToken)
+        }
+    }
+}
+
+/// Rotate a token.  Returns the new token, with a new management URI.
+pub async fn process_rotate_request(
+    service: &Service,
+    token_service: &TokenService,
+    token_id: &str,
+    proof: &RequestProof,
+) -> Result<AccessToken, GnapError> {
+    let (token, tx, client) = load_token(service, token_service, token_id, proof).await?;
+    verify_token_value(&token, proof)?;
+    let servers = match &token.access {
+        Some(access) => service.resource_servers(access).await?,
+        None => Vec::new(),
+    };
+    let rotated = encode_token(service, &tx, &client, &servers, token.rotate())?;
+    token_service
+        .rotate_token(&token, &rotated)
+        .await
+        .map_err(|err| {
+            error!("Failed to rotate token {}: {:?}", token.id, err);
+            GnapError::InvalidRotation
+        })?;
+    Ok(access_token(&rotated))
+}
+
+/// Revoke a token, or every token of its grant when `cascade` is set.
+pub async fn process_revoke_request(
+    service: &Service,
+    token_service: &TokenService,
+    token_id: &str,
+    cascade: bool,
+    proof: &RequestProof,
+) -> Result<(), GnapError> {
+    let (token, _, _) = load_token(service, token_service, token_id, proof).await?;
+    verify_token_value(&token, proof)?;
+    let revoked = match (cascade, token.tx.as_deref()) {
+        (true, Some(tx_id)) => token_service.revoke_tx_tokens(tx_id).await.map(|count| {
+            debug!("Revoked {} tokens of grant {}", count, tx_id);
+        }),
+        _ => token_service.revoke_token(&token).await,
+    };
+    revoked.map_err(|err| match err {
+        TokenError::NotFound => GnapError::NotFound,
+        err => {
+            error!("Failed to revoke token {}: {:?}", token.id, err);
+            GnapError::GeneralError
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::grant::{AccessRequest, AccessTokenFlag};
+    use model::resource::GnapRegisterResourceServer;
+    use model::tokens::TokenBuilder;
+
+    #[test]
+    fn access_token_response() {
+        let token = TokenBuilder::new("tx".to_owned())
+            .label(Some("bowls".to_owned()))
+            .access(vec![AccessRequest::Reference("foo".to_owned())])
+            .flags(vec![AccessTokenFlag::Bearer, AccessTokenFlag::Durable])
+            .build();
+
+        let response = access_token(&token);
+        assert_eq!(Some(response.value), token.access_token);
+        assert_eq!(response.label, token.label);
+        assert_eq!(
+            response.manage,
+            Some(format!("http://localhost:8000/gnap/token/{}", token.id))
+        );
+        assert_eq!(response.access, token.access);
+        assert_eq!(response.flags, token.flags);
+
+        let rotated = access_token(&token.rotate());
+        assert_ne!(rotated.manage, response.manage);
+    }
+
+    #[test]
+    fn select_token_format() {
+        let mut client = GnapClient::new(Vec::new(), "bowls".to_owned());
+        assert_eq!(token_format(&client, &[]), TokenFormat::Opaque);
+
+        client.access_token_format = Some(TokenFormat::Jwt);
+        assert_eq!(token_format(&client, &[]), TokenFormat::Jwt);
+
+        let rs = |token_format| {
+            GnapResourceServer::create(GnapRegisterResourceServer {
+                resource_server_name: None,
+                resource_server_key: "key".to_owned(),
+                key: None,
+                resource_set: None,
+                token_format,
+            })
+        };
+        client.access_token_format = None;
+        assert_eq!(
+            token_format(&client, &[rs(None), rs(Some(TokenFormat::Jwt))]),
+            TokenFormat::Jwt
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! OpenID Connect userinfo
+//!
+//! The userinfo endpoint returns claims about the end-user that approved a
+//! grant.  The client instance presents an access token with an `openid`
+//! access right, and gets the claims of the account linked to the end-user,
+//! limited to the `data_types` of the right.
+use crate::proof::{verify_client_key, RequestProof};
+use dao::service::Service;
+use dao::token_service::TokenService;
+use errors::GnapError;
+use log::debug;
+use model::grant::AccessRequest;
+use model::oidc::{filter_claims, USERINFO_ACCESS};
+use model::tokens::Token;
+use serde_json::{json, Map, Value};
+
+/// The claims about the end-user the presented access token gives access to.
+pub async fn process_userinfo_request(
+    service: &Service,
+    token_service: &TokenService,
+    proof: &RequestProof,
+) -> Result<Map<String, Value>, GnapError> {
+    let value = proof.access_token().ok_or(GnapError::InvalidToken)?;
+    let token = token_service
+        .fetch_token_by_accesstoken(value.to_owned())
+        .await
+        .map_err(|_| GnapError::InvalidToken)?;
+    if token.is_expired() {
+        debug!("Token {} has expired", token.id);
+        return Err(GnapError::InvalidToken);
+    }
+    // A bound token must be presented by the holder of its key.
+    if token.key.is_some() {
+        verify_client_key(service, proof, token.key.as_ref()).await?;
+    }
+    let data_types = userinfo_data_types(&token).ok_or(GnapError::AccessMismatch)?;
+
+    let tx_id = token.tx.clone().ok_or(GnapError::NotFound)?;
+    let tx = service.get_transaction(tx_id).await?;
+    let user_id = tx
+        .request
+        .and_then(|request| request.user)
+        .ok_or(GnapError::UnknownUser)?;
+    let user = service
+        .get_user(&user_id)
+        .await?
+        .ok_or(GnapError::UnknownUser)?;
+    let account_id = user.account_id.ok_or(GnapError::NotFound)?;
+    let account = service
+        .get_account(&account_id)
+        .await?
+        .ok_or(GnapError::NotFound)?;
+
+    let mut claims = filter_claims(account.claims(), data_types.as_deref());
+    claims.insert("sub".to_owned(), json!(user.id));
+    Ok(claims)
+}
+
+/// The data types of the `openid` rights of a token: `None` if a right
+/// allows every claim, and nothing if the token has no such right.
+fn userinfo_data_types(token: &Token) -> Option<Option<Vec<String>>> {
+    let mut data_types: Option<Vec<String>> = Some(Vec::new());
+    let mut granted = false;
+    for access in token.access.iter().flatten() {
+        if let AccessRequest::Value {
+            resource_type,
+            data_types: types,
+            ..
+        } = access
+        {
+            if resource_type != USERINFO_ACCESS {
+                continue;
+            }
+            granted = true;
+            match (types, data_types.as_mut()) {
+                (Some(types), Some(data_types)) => data_types.extend(types.iter().cloned()),
+                (None, _) => data_types = None,
+                _ => (),
+            }
+        }
+    }
+    granted.then_some(data_types)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::tokens::TokenBuilder;
+
+    fn right(resource_type: &str, data_types: Option<Vec<&str>>) -> AccessRequest {
+        AccessRequest::Value {
+            resource_type: resource_type.to_owned(),
+            actions: Some(vec!["read".to_owned()]),
+            locations: None,
+            data_types: data_types.map(|types| types.into_iter().map(str::to_owned).collect()),
+        }
+    }
+
+    #[test]
+    fn openid_data_types() {
+        let token = TokenBuilder::new("tx".to_owned())
+            .access(vec![right("photo-api", None)])
+            .build();
+        assert_eq!(userinfo_data_types(&token), None);
+
+        let token = TokenBuilder::new("tx".to_owned())
+            .access(vec![
+                right("openid", Some(vec!["profile"])),
+                right("openid", Some(vec!["email"])),
+            ])
+            .build();
+        assert_eq!(
+            userinfo_data_types(&token),
+            Some(Some(vec!["profile".to_owned(), "email".to_owned()]))
+        );
+
+        let token = TokenBuilder::new("tx".to_owned())
+            .access(vec![
+                right("openid", Some(vec!["email"])),
+                right("openid", None),
+            ])
+            .build();
+        assert_eq!(userinfo_data_types(&token), Some(None));
+    }
+}
@@ -1,61 +1,89 @@
 //! Transaction API Handlers
 
-use actix_web::http::header::HeaderMap;
-use actix_web::{web, HttpRequest, HttpResponse};
+use super::log_error;
+use crate::grant::interaction::{deliver_push, finish_push, finish_redirect};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 use dao::auth_service::AuthService;
-use errors::AuthError;
-use log::trace;
+use dao::service::Service;
+use errors::{AuthError, GnapError};
+use log::{error, trace};
 use model::credentials::Credentials;
 use model::instances::{InstanceRequest, InstanceResponse};
+use model::transaction::GnapTransaction;
 
-// TODO:
-// GET <as>/gnap/auth/:instance:
-pub async fn auth(
+/// Authenticate the end-user from the native app launched for the `app`
+/// interaction start mode, and approve the grant.  There is no consent step:
+/// the app flow grants the full request, limited only to the access the
+/// end-user holds, and never releases their identity.  The app launch code
+/// is invalidated after too many failed sign ins.
+/// HTTP POST <as>/gnap/app/:code
+pub async fn app(
     service: web::Data<AuthService>,
+    gnap_service: web::Data<Service>,
     request: HttpRequest,
-    instance: web::Path<String>,
-) -> HttpResponse {
-    trace!("Auth");
-    let login = basic_authentication(request.headers());
-    match login {
-        Ok(credentials) => {
-            let instance = InstanceRequest::create(instance.into_inner());
-            match service.validate_account(credentials, instance).await {
-                Ok(b) => {
-                    let body = InstanceResponse::create(b);
-                    HttpResponse::Ok().json(body)
-                }
-                Err(_) => {
-                    let json = InstanceResponse::create(false);
-                    HttpResponse::Unauthorized().json(json)
-                }
-            }
+    code: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    trace!("App");
+    let credentials = basic_authentication(request.headers())?;
+    let code = code.into_inner();
+    let tx_id = gnap_service
+        .find_app_code(&code)
+        .await
+        .map_err(log_error)?
+        .ok_or(GnapError::NotFound)?;
+
+    let tx = match service
+        .validate_account(credentials, InstanceRequest::create(tx_id))
+        .await
+        .map_err(log_error)?
+    {
+        Some(tx) => tx,
+        None => {
+            gnap_service.fail_app_code(&code).await.map_err(log_error)?;
+            return Err(AuthError::HashMissmatch.into());
         }
-        Err(_) => HttpResponse::BadRequest().body("Invalid data"),
+    };
+    if let Err(err) = gnap_service.remove_app_code(&code).await {
+        error!("Failed to remove app code: {:?}", err);
+    }
+    Ok(finish_interaction(gnap_service, tx)
+        .unwrap_or_else(|| HttpResponse::Ok().json(InstanceResponse::create(true))))
+}
+
+/// Finish the interaction of a transaction the end-user authorized.  Returns
+/// the redirect back to the client instance, if it asked for one.  A push is
+/// delivered in the background, without holding up the end-user.
+pub fn finish_interaction(
+    gnap_service: web::Data<Service>,
+    tx: GnapTransaction,
+) -> Option<HttpResponse> {
+    if let Some(location) = finish_redirect(&tx) {
+        return Some(
+            HttpResponse::Found()
+                .insert_header((header::LOCATION, location))
+                .finish(),
+        );
     }
+    if let Some((uri, body)) = finish_push(&tx) {
+        actix_web::rt::spawn(async move {
+            deliver_push(&gnap_service, &tx.tx_id, &uri, &body).await;
+        });
+    }
+    None
 }
 
 // TODO:
 // POST <as>/gnap/auth
-pub async fn create(service: web::Data<AuthService>, request: HttpRequest) -> HttpResponse {
+pub async fn create(
+    service: web::Data<AuthService>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AuthError> {
     trace!("User create");
-    let account = basic_authentication(request.headers());
-
-    match account {
-        Ok(user) => match service.create_account(user).await {
-            Ok(b) => {
-                if b.unwrap() {
-                    trace!("Created status {:?}", b);
-                    HttpResponse::Ok().json("ok")
-                } else {
-                    trace!("Created status {:?}", b);
-                    HttpResponse::InternalServerError().body("failed to create user")
-                }
-            }
-            Err(_) => HttpResponse::InternalServerError().body("failed to create user"),
-        },
-        Err(_) => HttpResponse::BadRequest().body("Invalid data"),
-    }
+    let user = basic_authentication(request.headers())?;
+    let created = service.create_account(user).await.map_err(log_error)?;
+    trace!("Created status {:?}", created);
+    Ok(HttpResponse::Ok().json("ok"))
 }
 
 fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, AuthError> {
@@ -64,14 +92,13 @@ fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, AuthError> {
     if let Some(header_values) = header_values {
         let base64encoded = header_values
             .to_str()
-            .expect("Failed to get headervalue")
-            .strip_prefix("Basic ")
-            .unwrap();
+            .ok()
+            .and_then(|value| value.strip_prefix("Basic "))
+            .ok_or(AuthError::BasicFailed)?;
 
-        let decoded_bytes =
-            base64::decode_config(base64encoded, base64::STANDARD).expect("Failed to decode");
-        let credentials = String::from_utf8(decoded_bytes)
-            .expect("Failed to turn bytes to string for credentials");
+        let decoded_bytes = base64::decode_config(base64encoded, base64::STANDARD)
+            .map_err(|_| AuthError::BasicFailed)?;
+        let credentials = String::from_utf8(decoded_bytes).map_err(|_| AuthError::BasicFailed)?;
         let mut cred = credentials.splitn(2, ':');
         let username = cred.next().ok_or(AuthError::UserNameError)?.to_string();
         let password = cred.next().ok_or(AuthError::PasswordError)?.to_string();
@@ -0,0 +1,470 @@
+//! Interaction Handlers
+//!
+//! The end-user is sent to the interaction URI of a transaction.  They sign
+//! in with a login form, which starts a browser session, and then approve or
+//! deny the access the client instance asked for on a consent page.  When the
+//! client instance asked for subject information, the end-user also decides
+//! whether their identity is released.
+use super::auth::finish_interaction;
+use super::page::{error_message, escape, render};
+use crate::grant::consent::{access_key, action_key, approved_access};
+use crate::grant::subject::released_information;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use dao::auth_service::AuthService;
+use dao::service::Service;
+use log::{debug, error, trace};
+use model::credentials::Credentials;
+use model::grant::{AccessRequest, GrantRequest};
+use model::transaction::{GnapTransaction, GnapTransactionState};
+use model::GnapID;
+use serde::Deserialize;
+use url::form_urlencoded;
+
+/// Name of the browser session cookie.
+const SESSION_COOKIE: &str = "gnap_session";
+const TITLE: &str = "Authorize access";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+/// Show the login form, or the consent page once the end-user signed in.
+/// HTTP GET <as>/gnap/auth/:tx_id
+pub async fn interact(
+    service: web::Data<AuthService>,
+    gnap_service: web::Data<Service>,
+    request: HttpRequest,
+    tx_id: web::Path<String>,
+) -> HttpResponse {
+    trace!("Interact");
+    let tx = match waiting_transaction(&gnap_service, tx_id.into_inner()).await {
+        Ok(tx) => tx,
+        Err(response) => return response,
+    };
+
+    match session_user(&service, &request).await {
+        Some(_) => consent_page(&gnap_service, &tx, StatusCode::OK, None).await,
+        None => login_page(&tx.tx_id, StatusCode::OK, None),
+    }
+}
+
+/// Sign the end-user in, and start a browser session.
+/// HTTP POST <as>/gnap/auth/:tx_id/login
+pub async fn login(
+    service: web::Data<AuthService>,
+    request: HttpRequest,
+    tx_id: web::Path<String>,
+    form: web::Form<LoginForm>,
+) -> HttpResponse {
+    trace!("Login");
+    let tx_id = tx_id.into_inner();
+    let form = form.into_inner();
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+
+    let user = match service.authenticate(credentials).await {
+        Ok(Some(user)) => user,
+        Ok(None) | Err(_) => {
+            debug!("Login failed");
+            return login_page(
+                &tx_id,
+                StatusCode::UNAUTHORIZED,
+                Some("Invalid username or password."),
+            );
+        }
+    };
+
+    match service.create_session(&user.username).await {
+        Ok(session_id) => {
+            let secure = request.connection_info().scheme() == "https";
+            let cookie = Cookie::build(SESSION_COOKIE, session_id)
+                .path("/gnap")
+                .http_only(true)
+                .secure(secure)
+                .same_site(SameSite::Lax)
+                .finish();
+            HttpResponse::SeeOther()
+                .cookie(cookie)
+                .insert_header((header::LOCATION, format!("/gnap/auth/{}", tx_id)))
+                .finish()
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            login_page(
+                &tx_id,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Some("Please try again."),
+            )
+        }
+    }
+}
+
+/// Approve or deny the grant, as the signed in end-user.
+/// HTTP POST <as>/gnap/auth/:tx_id
+pub async fn consent(
+    service: web::Data<AuthService>,
+    gnap_service: web::Data<Service>,
+    request: HttpRequest,
+    tx_id: web::Path<String>,
+    body: Bytes,
+) -> HttpResponse {
+    trace!("Consent");
+    let tx = match waiting_transaction(&gnap_service, tx_id.into_inner()).await {
+        Ok(tx) => tx,
+        Err(response) => return response,
+    };
+    let username = match session_user(&service, &request).await {
+        Some(username) => username,
+        None => {
+            return login_page(
+                &tx.tx_id,
+                StatusCode::UNAUTHORIZED,
+                Some("Please sign in again."),
+            )
+        }
+    };
+
+    let mut decision = None;
+    let mut selected = Vec::new();
+    let mut release_subject = false;
+    for (name, value) in form_urlencoded::parse(&body) {
+        match name.as_ref() {
+            "decision" => decision = Some(value.into_owned()),
+            "grant" => selected.push(value.into_owned()),
+            "subject" => release_subject = value == "release",
+            _ => (),
+        }
+    }
+
+    if decision.as_deref() != Some("approve") {
+        debug!("End-user denied transaction {}", tx.tx_id);
+        let denied = match tx.deny_interaction() {
+            Ok(tx) => gnap_service.update_transaction(tx).await,
+            Err(err) => Err(err),
+        };
+        return match denied {
+            Ok(tx) => finish_interaction(gnap_service, tx).unwrap_or_else(|| {
+                render(
+                    StatusCode::OK,
+                    TITLE,
+                    "<p>You denied the request.  You can close this window.</p>",
+                )
+            }),
+            Err(err) => {
+                error!("{:?}", err);
+                render(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    TITLE,
+                    "<p>Please try again.</p>",
+                )
+            }
+        };
+    }
+
+    let request = match &tx.request {
+        Some(request) => request,
+        None => return render(StatusCode::BAD_REQUEST, TITLE, "<p>Invalid request.</p>"),
+    };
+    let approved = approved_access(request, &selected);
+    if approved.is_empty() {
+        return consent_page(
+            &gnap_service,
+            &tx,
+            StatusCode::BAD_REQUEST,
+            Some("Select the access you approve, or deny the request."),
+        )
+        .await;
+    }
+
+    let subject_consent = release_subject && request.subject.is_some();
+    match service
+        .approve(tx.tx_id.clone(), username, approved, subject_consent)
+        .await
+    {
+        Ok(tx) => finish_interaction(gnap_service, tx).unwrap_or_else(|| {
+            render(
+                StatusCode::OK,
+                TITLE,
+                "<p>Access approved.  You can close this window.</p>",
+            )
+        }),
+        Err(err) if err.status_code().is_server_error() => {
+            error!("{:?}", err);
+            consent_page(
+                &gnap_service,
+                &tx,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Some("Please try again."),
+            )
+            .await
+        }
+        Err(err) => {
+            debug!("{:?}", err);
+            consent_page(
+                &gnap_service,
+                &tx,
+                StatusCode::FORBIDDEN,
+                Some("The access could not be approved for your account."),
+            )
+            .await
+        }
+    }
+}
+
+/// The transaction, as long as it is waiting for the end-user.
+async fn waiting_transaction(
+    gnap_service: &Service,
+    tx_id: String,
+) -> Result<GnapTransaction, HttpResponse> {
+    match gnap_service.get_transaction(tx_id).await {
+        Ok(tx) if matches!(tx.state, GnapTransactionState::Waiting) => Ok(tx),
+        Ok(_) => Err(render(
+            StatusCode::BAD_REQUEST,
+            TITLE,
+            "<p>This request is no longer waiting for approval.</p>",
+        )),
+        Err(_) => Err(render(
+            StatusCode::NOT_FOUND,
+            TITLE,
+            "<p>Unknown request.</p>",
+        )),
+    }
+}
+
+/// The end-user signed in to the browser session, if any.
+async fn session_user(service: &AuthService, request: &HttpRequest) -> Option<String> {
+    let cookie = request.cookie(SESSION_COOKIE)?;
+    match service.find_session(cookie.value()).await {
+        Ok(username) => username,
+        Err(err) => {
+            error!("{:?}", err);
+            None
+        }
+    }
+}
+
+fn login_page(tx_id: &str, status: StatusCode, message: Option<&str>) -> HttpResponse {
+    let body = format!(
+        r#"<p>Sign in to review the access requested.</p>
+    {}
+    <form method="post" action="/gnap/auth/{}/login">
+      <label>Username <input name="username" autocomplete="username" autofocus required></label>
+      <label>Password <input name="password" type="password" autocomplete="current-password" required></label>
+      <button type="submit">Sign in</button>
+    </form>"#,
+        error_message(message),
+        escape(tx_id)
+    );
+    render(status, TITLE, &body)
+}
+
+async fn consent_page(
+    gnap_service: &Service,
+    tx: &GnapTransaction,
+    status: StatusCode,
+    message: Option<&str>,
+) -> HttpResponse {
+    let request = match &tx.request {
+        Some(request) => request,
+        None => return render(StatusCode::BAD_REQUEST, TITLE, "<p>Invalid request.</p>"),
+    };
+    let client_name = match request.parse_id() {
+        Ok(client_id) => match gnap_service.get_client(&client_id).await {
+            Ok(Some(client)) => client.client_name,
+            _ => "An application".to_owned(),
+        },
+        Err(_) => "An application".to_owned(),
+    };
+
+    let body = format!(
+        r#"<p>{} is requesting access to your resources.</p>
+    {}
+    <form method="post" action="/gnap/auth/{}">
+{}{}
+      <button type="submit" name="decision" value="approve">Approve</button>
+      <button type="submit" name="decision" value="deny">Deny</button>
+    </form>"#,
+        escape(&client_name),
+        error_message(message),
+        escape(&tx.tx_id),
+        access_list(request),
+        subject_release(request)
+    );
+    render(status, TITLE, &body)
+}
+
+/// A checkbox for every action, or every access right without actions.
+fn access_list(request: &GrantRequest) -> String {
+    let checkbox = |key: String, label: &str| {
+        format!(
+            r#"<label><input type="checkbox" name="grant" value="{}" checked> {}</label>"#,
+            escape(&key),
+            escape(label)
+        )
+    };
+
+    let mut html = String::new();
+    for (t, token) in request.access_token.iter().enumerate() {
+        let label = token
+            .label
+            .clone()
+            .unwrap_or_else(|| format!("Access token {}", t + 1));
+        html.push_str(&format!(
+            "      <fieldset><legend>{}</legend>\n",
+            escape(&label)
+        ));
+        for (a, access) in token.access.iter().enumerate() {
+            html.push_str("        <div>");
+            match access {
+                AccessRequest::Reference(reference) => {
+                    html.push_str(&checkbox(access_key(t, a), reference));
+                }
+                AccessRequest::Value {
+                    resource_type,
+                    actions,
+                    locations,
+                    data_types,
+                } => {
+                    html.push_str(&format!("<strong>{}</strong>", escape(resource_type)));
+                    for (name, values) in [("Locations", locations), ("Data types", data_types)] {
+                        if let Some(values) = values {
+                            let values: Vec<String> = values.iter().map(|v| escape(v)).collect();
+                            html.push_str(&format!(" <p>{}: {}</p>", name, values.join(", ")));
+                        }
+                    }
+                    match actions {
+                        Some(actions) if !actions.is_empty() => {
+                            for action in actions {
+                                html.push_str(&checkbox(action_key(t, a, action), action));
+                            }
+                        }
+                        _ => html.push_str(&checkbox(access_key(t, a), "Allow")),
+                    }
+                }
+            }
+            html.push_str("</div>\n");
+        }
+        html.push_str("      </fieldset>\n");
+    }
+    html
+}
+
+/// A checkbox to release the end-user's identity, if the client instance
+/// asked for it, listing what is released.  Left unchecked, so the identity
+/// is only released on purpose.
+fn subject_release(request: &GrantRequest) -> String {
+    match &request.subject {
+        Some(subject) => format!(
+            r#"      <label><input type="checkbox" name="subject" value="release"> Share who you are: {}</label>
+"#,
+            released_information(subject).join(", ")
+        ),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use model::users::User;
+
+    #[test]
+    fn list_every_action() {
+        let request: GrantRequest = serde_json::from_str(
+            r#"{
+                "access_token": {
+                    "access": [
+                        {
+                            "type": "photo-api",
+                            "actions": ["read", "write"],
+                            "locations": ["https://server.example.net/"],
+                            "data_types": ["metadata", "<images>"]
+                        },
+                        "dolphin-metadata"
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let html = access_list(&request);
+        assert!(html.contains(r#"value="0.0.read""#));
+        assert!(html.contains(r#"value="0.0.write""#));
+        assert!(html.contains(r#"value="0.1""#));
+        assert!(html.contains("https://server.example.net/"));
+        assert!(html.contains("metadata, &lt;images&gt;"));
+        assert!(html.contains("Access token 1"));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn approve_each_kind_of_access() {
+        dotenv::dotenv().ok();
+        let service = web::Data::new(AuthService::create().await);
+        let gnap_service = web::Data::new(Service::create().await);
+
+        let access: Vec<AccessRequest> = serde_json::from_str(
+            r#"[
+                { "type": "photo-api", "actions": ["read", "write"] },
+                { "type": "dolphin-metadata" },
+                "waterbowl"
+            ]"#,
+        )
+        .unwrap();
+        let username = User::create_id().to_string();
+        service
+            .db_client
+            .add_user(User {
+                id: User::create_id().to_string(),
+                username: username.clone(),
+                password: String::new(),
+                access: Some(access.clone()),
+                account_id: None,
+            })
+            .await
+            .unwrap();
+        let request: GrantRequest = serde_json::from_value(serde_json::json!({
+            "access_token": { "access": access }
+        }))
+        .unwrap();
+        let tx = gnap_service.start_transaction(request).await.unwrap();
+        let session_id = service.create_session(&username).await.unwrap();
+
+        let http_request = TestRequest::default()
+            .cookie(Cookie::new(SESSION_COOKIE, session_id))
+            .to_http_request();
+        let body = "decision=approve&grant=0.0.read&grant=0.1&grant=0.2";
+        let response = consent(
+            service,
+            gnap_service.clone(),
+            http_request,
+            web::Path::from(tx.tx_id.clone()),
+            Bytes::from(body),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let tx = gnap_service.get_transaction(tx.tx_id).await.unwrap();
+        assert!(matches!(tx.state, GnapTransactionState::Authorized));
+        let approved = &tx.request.unwrap().access_token[0].access;
+        assert_eq!(
+            approved,
+            &serde_json::from_str::<Vec<AccessRequest>>(
+                r#"[
+                    { "type": "photo-api", "actions": ["read"] },
+                    { "type": "dolphin-metadata" },
+                    "waterbowl"
+                ]"#,
+            )
+            .unwrap()
+        );
+    }
+}
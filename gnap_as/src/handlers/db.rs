@@ -1,25 +1,26 @@
+use super::log_error;
 use actix_web::{web, HttpResponse};
 use dao::service::Service;
+use errors::GnapError;
 use uuid::Uuid;
 
-use log::{error, trace};
+use log::trace;
 use model::client::GnapClientRequest;
 
-pub async fn get_client(service: web::Data<Service>, id: web::Path<Uuid>) -> HttpResponse {
+pub async fn get_client(
+    service: web::Data<Service>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, GnapError> {
     trace!("get_client: {:?}", id);
     let id = id.into_inner();
-    match service.get_client(&id).await {
-        Ok(Some(data)) => {
+    match service.get_client(&id).await.map_err(log_error)? {
+        Some(data) => {
             trace!("Retrieved client: {:?}", data);
-            HttpResponse::Ok().json(data)
+            Ok(HttpResponse::Ok().json(data))
         }
-        Ok(None) => {
+        None => {
             trace!("client not found");
-            HttpResponse::NotFound().body(format!("No client found with id {}", id))
-        }
-        Err(err) => {
-            error!("{:?}", err);
-            HttpResponse::InternalServerError().body(err.to_string())
+            Err(GnapError::NotFound)
         }
     }
 }
@@ -27,9 +28,10 @@ pub async fn get_client(service: web::Data<Service>, id: web::Path<Uuid>) -> Htt
 pub async fn add_client(
     service: web::Data<Service>,
     client: web::Json<GnapClientRequest>,
-) -> HttpResponse {
-    match service.add_client(client.into_inner()).await {
-        Ok(data) => HttpResponse::Ok().json(data),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-    }
+) -> Result<HttpResponse, GnapError> {
+    let data = service
+        .add_client(client.into_inner())
+        .await
+        .map_err(log_error)?;
+    Ok(HttpResponse::Ok().json(data))
 }
@@ -0,0 +1,147 @@
+//! User code entry page
+//!
+//! The stable page of the `user_code` interaction start mode.  The end-user
+//! types the code the client instance displayed, and continues on the
+//! interaction pages of the transaction: they sign in, and review the access
+//! the device asked for before approving it.  A client address only gets a
+//! few wrong codes, so user codes can't be guessed.
+use super::page::{error_message, render};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use dao::service::Service;
+use errors::GnapError;
+use log::{debug, error, trace};
+use model::tokens::normalize_usercode;
+use serde::Deserialize;
+
+const TITLE: &str = "Connect a device";
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceForm {
+    pub user_code: String,
+}
+
+/// HTTP GET <as>/gnap/device
+pub async fn device_page() -> HttpResponse {
+    page(StatusCode::OK, None)
+}
+
+/// HTTP POST <as>/gnap/device
+pub async fn device_submit(
+    gnap_service: web::Data<Service>,
+    request: HttpRequest,
+    form: web::Form<DeviceForm>,
+) -> HttpResponse {
+    trace!("Device code entry");
+    // The peer address, as forwarding headers are set by the client.
+    let address = request
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    match gnap_service.user_code_locked(&address).await {
+        Ok(false) => (),
+        Ok(true) => return too_many_attempts(),
+        Err(err) => {
+            error!("{:?}", err);
+            return page(StatusCode::INTERNAL_SERVER_ERROR, Some("Please try again."));
+        }
+    }
+
+    let code = match normalize_usercode(&form.user_code) {
+        Some(code) => code,
+        None => return page(StatusCode::BAD_REQUEST, Some("That code is not valid.")),
+    };
+
+    let tx_id = match gnap_service.find_user_code(&code).await {
+        Ok(Some(tx_id)) => tx_id,
+        Ok(None) => {
+            debug!("Unknown or expired user code");
+            return match gnap_service.fail_user_code(&address).await {
+                Ok(()) => page(
+                    StatusCode::NOT_FOUND,
+                    Some("That code is unknown, or has expired."),
+                ),
+                Err(GnapError::TooManyAttempts) => too_many_attempts(),
+                Err(err) => {
+                    error!("{:?}", err);
+                    page(StatusCode::INTERNAL_SERVER_ERROR, Some("Please try again."))
+                }
+            };
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            return page(StatusCode::INTERNAL_SERVER_ERROR, Some("Please try again."));
+        }
+    };
+
+    // The code has done its job once it leads to the transaction.
+    if let Err(err) = gnap_service.remove_user_code(&code).await {
+        error!("Failed to remove user code: {:?}", err);
+    }
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, format!("/gnap/auth/{}", tx_id)))
+        .finish()
+}
+
+fn too_many_attempts() -> HttpResponse {
+    page(
+        StatusCode::TOO_MANY_REQUESTS,
+        Some("Too many wrong codes.  Please try again later."),
+    )
+}
+
+/// The code entry form, with an optional error message.
+fn page(status: StatusCode, message: Option<&str>) -> HttpResponse {
+    let body = format!(
+        r#"<p>Enter the code shown on your device.  You will then sign in and review the access it asks for.</p>
+    {}
+    <form method="post" action="/gnap/device">
+      <label>Code <input name="user_code" autocomplete="off" autofocus required></label>
+      <button type="submit">Continue</button>
+    </form>"#,
+        error_message(message)
+    );
+    render(status, TITLE, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::test::TestRequest;
+    use model::tokens::generate_usercode;
+
+    #[actix_web::test]
+    async fn render_code_entry_page() {
+        let response = device_page().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(r#"name="user_code""#));
+        assert!(!body.contains(r#"name="password""#));
+        assert!(!body.contains(r#"class="error""#));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn limit_wrong_codes() {
+        dotenv::dotenv().ok();
+        let gnap_service = web::Data::new(Service::create().await);
+        let address = format!("10.{}.0.1", std::process::id() % 256);
+
+        let mut statuses = Vec::new();
+        for _ in 0..6 {
+            let request = TestRequest::default()
+                .peer_addr(format!("{}:4000", address).parse().unwrap())
+                .to_http_request();
+            let form = web::Form(DeviceForm {
+                user_code: generate_usercode(),
+            });
+            let response = device_submit(gnap_service.clone(), request, form).await;
+            statuses.push(response.status());
+        }
+        assert_eq!(statuses[0], StatusCode::NOT_FOUND);
+        assert_eq!(statuses[4], StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(statuses[5], StatusCode::TOO_MANY_REQUESTS);
+    }
+}
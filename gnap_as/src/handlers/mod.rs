@@ -1,6 +1,22 @@
 pub mod auth;
+pub mod consent;
 pub mod db;
+pub mod device;
+pub mod page;
 pub mod resources;
 pub mod tokens;
 pub mod transaction;
+pub mod userinfo;
 pub mod well_known;
+
+use actix_web::ResponseError;
+use log::error;
+use std::fmt::Debug;
+
+/// Server errors are logged, as their details are not returned to the caller.
+pub(crate) fn log_error<E: ResponseError + Debug>(err: E) -> E {
+    if err.status_code().is_server_error() {
+        error!("{:?}", err);
+    }
+    err
+}
@@ -0,0 +1,58 @@
+//! Server rendered pages for the end-user.
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+/// Render a page with the given title and body.
+pub fn render(status: StatusCode, title: &str, body: &str) -> HttpResponse {
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head><meta charset="utf-8"><title>{title}</title></head>
+  <body>
+    <h1>{title}</h1>
+    {body}
+  </body>
+</html>"#,
+        title = escape(title),
+        body = body
+    );
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
+
+/// An optional error message.
+pub fn error_message(message: Option<&str>) -> String {
+    message
+        .map(|message| format!("<p class=\"error\">{}</p>", escape(message)))
+        .unwrap_or_default()
+}
+
+/// Escape text for use in HTML content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_markup() {
+        assert_eq!(
+            escape(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#x27;&amp;&#x27;&lt;/a&gt;"
+        );
+    }
+}
@@ -1,8 +1,11 @@
 //! Transaction API Handlers
 
+use super::log_error;
+use crate::proof::{verify_client_key, RequestProof};
 use actix_web::{web, HttpResponse};
 use dao::resource_service::ResourceService;
-use log::{error, trace};
+use errors::{GnapError, ResourceError};
+use log::{debug, trace};
 use model::introspect::IntrospectRequest;
 use model::resource::{GnapRegisterResourceServer, GnapResourceServer};
 use mongodb::bson::doc;
@@ -10,16 +13,41 @@ use mongodb::bson::doc;
 /// HTTP POST  <as>/gnap/introspect
 pub async fn introspect(
     service: web::Data<ResourceService>,
-    intro_request: web::Json<IntrospectRequest>,
-) -> HttpResponse {
-    let ir = intro_request.into_inner();
-    println!("{:#?}", ir);
-    match service.introspect_token(ir).await {
-        Ok(data) => HttpResponse::Ok().json(data),
-        Err(_) => {
-            trace!("Unable to verify token");
-            HttpResponse::InternalServerError().json(doc! { "status": "error"})
-        }
+    proof: RequestProof,
+) -> Result<HttpResponse, GnapError> {
+    let ir: IntrospectRequest = proof.json()?;
+    debug!("{:#?}", ir);
+
+    // The RS must sign the request with the key it registered.
+    let resource_server = service
+        .db_client
+        .fetch_resource_server(ir.resource_server.clone())
+        .await
+        .map_err(introspection_error)
+        .map_err(log_error)?
+        .ok_or(GnapError::InvalidProof)?;
+    if let Err(err) =
+        verify_client_key(&service.tx_service, &proof, resource_server.key.as_ref()).await
+    {
+        debug!("Introspection request failed key proof");
+        return Err(err);
+    }
+
+    let data = service.introspect_token(ir).await.map_err(|err| {
+        trace!("Unable to verify token");
+        log_error(introspection_error(err))
+    })?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// An unknown RS has not proven a key, and a token the RS may not see is
+/// denied.
+fn introspection_error(err: ResourceError) -> GnapError {
+    match err {
+        ResourceError::NotFound => GnapError::InvalidProof,
+        ResourceError::TokenError | ResourceError::AccessNotFound => GnapError::RequestDenied,
+        ResourceError::DatabaseError(err) => GnapError::DatabaseError(err),
+        ResourceError::GeneralError => GnapError::GeneralError,
     }
 }
 
@@ -27,33 +55,22 @@ pub async fn introspect(
 pub async fn register_resources_set(
     service: web::Data<ResourceService>,
     rs: web::Json<GnapResourceServer>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ResourceError> {
     let rs = rs.into_inner();
-    match service.register_resources_set(rs).await {
-        Ok(_) => {
-            trace!("Registered");
-            HttpResponse::Ok().json(doc! { "status": "registered"})
-        }
-        Err(_) => {
-            error!("Something went horribly wrong");
-            HttpResponse::InternalServerError().json(doc! {"status": "failed"})
-        }
-    }
+    service
+        .register_resources_set(rs)
+        .await
+        .map_err(log_error)?;
+    trace!("Registered");
+    Ok(HttpResponse::Ok().json(doc! { "status": "registered"}))
 }
 
 pub async fn register_resource_server(
     service: web::Data<ResourceService>,
     rs: web::Json<GnapRegisterResourceServer>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ResourceError> {
     let rs = rs.into_inner();
-    match service.add_resource_server(rs).await {
-        Ok(_) => {
-            trace!("Created");
-            HttpResponse::Ok().json(doc! { "status": "created"})
-        }
-        Err(_) => {
-            error!("Something went horribly wrong");
-            HttpResponse::InternalServerError().json(doc! {"status": "failed"})
-        }
-    }
+    service.add_resource_server(rs).await.map_err(log_error)?;
+    trace!("Created");
+    Ok(HttpResponse::Ok().json(doc! { "status": "created"}))
 }
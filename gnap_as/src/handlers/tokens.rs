@@ -1,34 +1,51 @@
 //! Token API Handlers
 
+use super::log_error;
+use crate::grant::token::{process_revoke_request, process_rotate_request};
+use crate::proof::RequestProof;
 use actix_web::{web, HttpResponse};
+use dao::service::Service;
 use dao::token_service::TokenService;
+use errors::GnapError;
 use log::{debug, trace};
-use model::tokens::Token;
-use mongodb::bson::doc;
+use serde::Deserialize;
+use serde_json::json;
 
+#[derive(Debug, Default, Deserialize)]
+pub struct RevokeOptions {
+    /// Also revoke every other token issued for the same grant.
+    #[serde(default)]
+    pub cascade: bool,
+}
+
+/// Revoke an access token
+/// HTTP DELETE <as>/gnap/token/:id[?cascade=true]
 pub async fn revoke_token(
+    tx_service: web::Data<Service>,
     service: web::Data<TokenService>,
     token_id: web::Path<String>,
-) -> HttpResponse {
+    options: web::Query<RevokeOptions>,
+    proof: RequestProof,
+) -> Result<HttpResponse, GnapError> {
     debug!("revoke token");
-    let token_id = token_id.into_inner();
-    let token = Token::from_string(token_id);
-    match service.revoke_token(&token).await {
-        Ok(_) => {
-            trace!("Succesfully revoked");
-            HttpResponse::Ok().json(doc! { "status": "revoked" })
-        }
-        Err(_) => {
-            trace!("Can't find the token");
-            HttpResponse::NoContent().json(doc! { "status": "error" })
-        }
-    }
+    process_revoke_request(&tx_service, &service, &token_id, options.cascade, &proof)
+        .await
+        .map_err(log_error)?;
+    trace!("Succesfully revoked");
+    Ok(HttpResponse::NoContent().finish())
 }
 
+/// Rotate an access token
+/// HTTP POST <as>/gnap/token/:id
 pub async fn rotate_token(
-    _service: web::Data<TokenService>,
+    tx_service: web::Data<Service>,
+    service: web::Data<TokenService>,
     token_id: web::Path<String>,
-) -> HttpResponse {
+    proof: RequestProof,
+) -> Result<HttpResponse, GnapError> {
     trace!("rotate token");
-    HttpResponse::Ok().json(token_id.into_inner())
+    let access_token = process_rotate_request(&tx_service, &service, &token_id, &proof)
+        .await
+        .map_err(log_error)?;
+    Ok(HttpResponse::Ok().json(json!({ "access_token": access_token })))
 }
@@ -1,70 +1,104 @@
 //! Transaction API Handlers
-use crate::grant::request::{process_continue_request, process_request};
+use super::log_error;
+use crate::grant::request::{
+    process_continue_request, process_delete_request, process_modify_request, process_request,
+};
+use crate::proof::RequestProof;
 use actix_web::{web, HttpResponse};
 use dao::service::Service;
-use log::{debug, error, trace};
-use model::grant::{ContinuationRequest, GrantRequest};
+use dao::token_service::TokenService;
+use errors::GnapError;
+use log::trace;
+use model::grant::{ContinuationRequest, GrantModificationRequest, GrantRequest};
 
 /// HTTP OPTIONS <as>/gnap/tx
-pub async fn grant_options(service: web::Data<Service>) -> HttpResponse {
+pub async fn grant_options(service: web::Data<Service>) -> Result<HttpResponse, GnapError> {
     trace!("grant_options");
-    match service.get_grant_options().await {
-        Ok(data) => {
-            trace!("Retrieved grant options: {:?}", data);
-            HttpResponse::Ok().json(data)
-        }
-        Err(err) => {
-            error!("{:?}", err);
-            HttpResponse::InternalServerError().body(err.to_string())
-        }
-    }
+    let data = service.get_grant_options().await.map_err(log_error)?;
+    trace!("Retrieved grant options: {:?}", data);
+    Ok(HttpResponse::Ok().json(data))
 }
 
 /// Initiate a grant transaction
 /// HTTP POST <as>/gnap/tx
 pub async fn grant_request(
     service: web::Data<Service>,
-    request: web::Json<GrantRequest>,
-) -> HttpResponse {
+    proof: RequestProof,
+) -> Result<HttpResponse, GnapError> {
+    let request: GrantRequest = proof.json()?;
+
     // Create a response from the request
-    let result = process_request(&service, request.into_inner()).await;
-    match result {
-        Ok(data) => {
-            trace!("processed grant request: {:?}", data);
-            HttpResponse::Ok().json(data)
-        }
-        Err(err) => {
-            error!("{:?}", err);
-            HttpResponse::InternalServerError().body(err.to_string())
-        }
-    }
+    let data = process_request(&service, request, &proof)
+        .await
+        .map_err(log_error)?;
+    trace!("processed grant request: {:?}", data);
+    Ok(HttpResponse::Ok().json(data))
 }
 
 /// Continue a grant transaction
 /// HTTP POST <as>/gnap/tx/:id
 pub async fn continue_request(
     service: web::Data<Service>,
-    request: web::Json<ContinuationRequest>,
+    token_service: web::Data<TokenService>,
+    proof: RequestProof,
     tx_id: web::Path<String>,
-) -> HttpResponse {
-    let hash = request.into_inner();
-    let tx_id = tx_id.into_inner();
-    if hash.interact_ref.eq(&tx_id.clone()) {
-        debug!("Valid hash");
-        // This hash should be validate after being computed at the client
-    }
+) -> Result<HttpResponse, GnapError> {
+    // A polling client instance may send an empty body.
+    let continuation: ContinuationRequest = if proof.body.is_empty() {
+        ContinuationRequest::default()
+    } else {
+        proof.json()?
+    };
 
-    let result = process_continue_request(&service, tx_id).await;
-    match result {
-        Ok(data) => {
-            trace!("processed grant request: {:?}", data);
-            HttpResponse::Ok().json(data)
-        }
-        Err(err) => {
-            error!("{:?}", err);
-            HttpResponse::InternalServerError().body(err.to_string())
-        }
-    }
+    let data = process_continue_request(
+        &service,
+        &token_service,
+        tx_id.into_inner(),
+        continuation.interact_ref,
+        &proof,
+    )
+    .await
+    .map_err(log_error)?;
+    trace!("processed grant request: {:?}", data);
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// Modify a grant transaction
+/// HTTP PATCH <as>/gnap/tx/:id
+pub async fn modify_request(
+    service: web::Data<Service>,
+    token_service: web::Data<TokenService>,
+    proof: RequestProof,
+    tx_id: web::Path<String>,
+) -> Result<HttpResponse, GnapError> {
+    let modification: GrantModificationRequest = proof.json()?;
+
+    let data = process_modify_request(
+        &service,
+        &token_service,
+        tx_id.into_inner(),
+        modification,
+        &proof,
+    )
+    .await
+    .map_err(log_error)?;
+    trace!("processed grant modification: {:?}", data);
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// Cancel a grant transaction
+/// HTTP DELETE <as>/gnap/tx/:id
+pub async fn delete_request(
+    service: web::Data<Service>,
+    token_service: web::Data<TokenService>,
+    proof: RequestProof,
+    tx_id: web::Path<String>,
+) -> Result<HttpResponse, GnapError> {
+    process_delete_request(&service, &token_service, tx_id.into_inner(), &proof)
+        .await
+        .map_err(log_error)?;
+    trace!("deleted grant");
+    Ok(HttpResponse::NoContent().finish())
 }
 
 #[cfg(test)]
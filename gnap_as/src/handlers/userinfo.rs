@@ -0,0 +1,24 @@
+//! Userinfo Handler
+
+use super::log_error;
+use crate::grant::userinfo::process_userinfo_request;
+use crate::proof::RequestProof;
+use actix_web::{web, HttpResponse};
+use dao::service::Service;
+use dao::token_service::TokenService;
+use errors::GnapError;
+use log::trace;
+
+/// Claims about the end-user, for an access token with an `openid` right.
+/// HTTP GET|POST <as>/gnap/userinfo
+pub async fn userinfo(
+    service: web::Data<Service>,
+    token_service: web::Data<TokenService>,
+    proof: RequestProof,
+) -> Result<HttpResponse, GnapError> {
+    trace!("userinfo");
+    let claims = process_userinfo_request(&service, &token_service, &proof)
+        .await
+        .map_err(log_error)?;
+    Ok(HttpResponse::Ok().json(claims))
+}
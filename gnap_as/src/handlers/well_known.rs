@@ -1,3 +1,4 @@
+use super::log_error;
 use actix_web::{web, HttpResponse};
 use dao::service::Service;
 use errors::GnapError;
@@ -24,14 +25,14 @@ pub async fn openid_config(_service: web::Data<Service>) -> HttpResponse {
     HttpResponse::Ok().json(config)
 }
 
-pub async fn gnap_config(service: web::Data<Service>) -> HttpResponse {
-    let result = service.get_gnap_well_knowns().await;
+/// The public keys of the AS, to verify the tokens it signs.
+/// HTTP GET <as>/gnap/jwks
+pub async fn jwks(service: web::Data<Service>) -> HttpResponse {
+    trace!("jwks");
+    HttpResponse::Ok().json(service.key_store.jwks())
+}
 
-    match result {
-        Ok(response) => HttpResponse::Ok().json(&response),
-        Err(err) => match err {
-            GnapError::BadData => HttpResponse::BadRequest().body("Missing GNAP options"),
-            _ => HttpResponse::InternalServerError().body(err.to_string()),
-        },
-    }
+pub async fn gnap_config(service: web::Data<Service>) -> Result<HttpResponse, GnapError> {
+    let response = service.get_gnap_well_knowns().await.map_err(log_error)?;
+    Ok(HttpResponse::Ok().json(&response))
 }
@@ -0,0 +1,64 @@
+//! Scheduled rotation of the signing keys
+//!
+//! Every instance of the AS checks the key store on a schedule.  Keys that
+//! are due are replaced, and keys rotated by another instance are picked up.
+use actix_web::rt::time::interval;
+use actix_web::web;
+use dao::service::Service;
+use log::{error, info};
+use std::time::Duration;
+
+/// How often the signing keys are checked.
+const ROTATION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Rotate the signing keys on a schedule, for as long as the server runs.
+pub fn spawn(service: web::Data<Service>) {
+    actix_web::rt::spawn(async move {
+        let mut schedule = interval(ROTATION_INTERVAL);
+        loop {
+            schedule.tick().await;
+            match service.key_store.rotate().await {
+                Ok(0) => (),
+                Ok(rotated) => info!("Rotated {} signing keys", rotated),
+                Err(err) => error!("Failed to rotate signing keys: {:?}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use dao::service::Service;
+    use model::signing::{KeyPurpose, StoredKey};
+    use mongodb::bson::doc;
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn activate_keys_at_the_same_time() {
+        dotenv::dotenv().ok();
+        let service = Service::create().await;
+        let key_store = &service.key_store;
+
+        let first = key_store.generate(KeyPurpose::Jwt).await.unwrap();
+        let second = key_store.generate(KeyPurpose::Jwt).await.unwrap();
+        let activated = futures::join!(
+            key_store.activate(&first.kid),
+            key_store.activate(&second.kid)
+        );
+        assert!(activated.0.is_ok() && activated.1.is_ok());
+        // A key is only activated once.
+        assert!(key_store.activate(&first.kid).await.is_err());
+
+        let active = service
+            .db_client
+            .database
+            .collection::<StoredKey>("keys")
+            .count_documents(doc! { "purpose": "jwt", "status": "active" }, None)
+            .await
+            .unwrap();
+        assert_eq!(active, 1);
+        key_store.refresh().await.unwrap();
+        let kid = key_store.signing_key().kid;
+        assert!(kid == first.kid || kid == second.kid);
+    }
+}
@@ -3,10 +3,13 @@ use dao::auth_service::AuthService;
 use dao::resource_service::ResourceService;
 use dao::service::Service;
 use dao::token_service::TokenService;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use log::info;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
 use std::env;
 use std::net::SocketAddr;
 
+pub mod key_rotation;
+pub mod sweeper;
 mod utils;
 
 /// Set up shared App state
@@ -30,8 +33,9 @@ pub async fn token_state() -> web::Data<TokenService> {
     web::Data::new(tokenservice)
 }
 
-pub async fn rs_state() -> web::Data<ResourceService> {
-    let rs_service = ResourceService::create().await;
+/// The resource service shares the signing keys of the application state.
+pub async fn rs_state(app_state: &web::Data<Service>) -> web::Data<ResourceService> {
+    let rs_service = ResourceService::create(app_state.get_ref().clone()).await;
     web::Data::new(rs_service)
 }
 
@@ -86,6 +90,10 @@ To create a self-signed temporary cert for testing, copy&paste the following:
 */
 
 /// SSL builder for HttpServer
+///
+/// The server requests a client certificate.  The certificate is not
+/// required, and is not validated against a CA: client instances using `mtls`
+/// proofing register the certificate itself.
 pub fn tls_builder() -> SslAcceptorBuilder {
     // load ssl keys
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
@@ -95,5 +103,16 @@ pub fn tls_builder() -> SslAcceptorBuilder {
     builder
         .set_certificate_chain_file(".keystore/cert.pem")
         .unwrap();
+
+    info!("Requesting client certificates for mutual TLS");
+    builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
     builder
 }
+
+/// True if the TLS listener for mutual TLS is started.  Off unless
+/// `MTLS_ENABLED=true`.
+pub fn mtls_enabled() -> bool {
+    env::var("MTLS_ENABLED")
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
@@ -1,13 +1,17 @@
-use actix_web::{middleware, App, HttpServer};
+use actix_web::{middleware, web, App, HttpServer};
 use dotenv::dotenv;
 use actix_cors::Cors;
 
-use log::info;
+use errors::GnapError;
+use log::{debug, info};
 
-#[allow(unused_imports)]
-use gnap_as::{app_state, auth_state, get_ip_addresses, rs_state, tls_builder, token_state};
+use gnap_as::{
+    app_state, auth_state, get_ip_addresses, key_rotation, mtls_enabled, rs_state, sweeper,
+    tls_builder, token_state,
+};
 mod grant;
 mod handlers;
+mod proof;
 mod routes;
 
 /// Crate main.
@@ -33,8 +37,10 @@ async fn main() -> std::io::Result<()> {
     let app_state = app_state().await;
     let auth_state = auth_state().await;
     let token_state = token_state().await;
-    let rs_state = rs_state().await;
-    
+    let rs_state = rs_state(&app_state).await;
+    sweeper::spawn(app_state.clone(), token_state.clone());
+    key_rotation::spawn(app_state.clone());
+
     // Create the actix-web App instance, with middleware and routes.
     let app = move || {
         App::new()
@@ -43,6 +49,19 @@ async fn main() -> std::io::Result<()> {
             .app_data(auth_state.clone())
             .app_data(token_state.clone())
             .app_data(rs_state.clone())
+            // Malformed bodies and paths are answered with a GNAP error.
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                debug!("Invalid JSON body: {:?}", err);
+                GnapError::BadData.into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                debug!("Invalid query: {:?}", err);
+                GnapError::BadData.into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                debug!("Invalid path: {:?}", err);
+                GnapError::BadData.into()
+            }))
             // Add each of the router modules.
             .configure(routes::login::routes)
             .configure(routes::db::routes)
@@ -54,10 +73,13 @@ async fn main() -> std::io::Result<()> {
             .wrap(Cors::permissive())
     };
 
-    // Start http server with the app
-    HttpServer::new(app)
-        .bind(api_address)?
-        //.bind_openssl(tls_address, tls_builder())?
-        .run()
-        .await
+    // Start http server with the app.  The TLS listener is only started for
+    // mutual TLS.
+    let mut server = HttpServer::new(app)
+        .on_connect(proof::mtls::on_connect)
+        .bind(api_address)?;
+    if mtls_enabled() {
+        server = server.bind_openssl(tls_address, tls_builder())?;
+    }
+    server.run().await
 }
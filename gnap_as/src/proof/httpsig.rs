@@ -0,0 +1,455 @@
+//! HTTP Message Signatures key proofing
+//!
+//! Implements the `httpsig` proof method (GNAP Section 7.3.1) on top of
+//! [RFC 9421](https://www.rfc-editor.org/rfc/rfc9421).  The client instance
+//! signs the request with the private half of its registered key, and sends
+//! the `Signature`, `Signature-Input` and, when there is a body,
+//! `Content-Digest` headers.
+use super::{check_created, check_key_algorithm, key_algorithm, RequestProof};
+use actix_web::http::Uri;
+use errors::GnapError;
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{crypto, Algorithm, DecodingKey};
+use log::{debug, trace};
+use model::key::ClientKey;
+use openssl::sha::{sha256, sha512};
+
+/// The parsed value of one `Signature-Input` member.
+#[derive(Debug)]
+pub struct SignatureInput {
+    pub components: Vec<String>,
+    pub created: Option<u64>,
+    pub keyid: Option<String>,
+    pub alg: Option<String>,
+    /// The serialized inner list, exactly as sent.  It is the value of the
+    /// `@signature-params` line of the signature base.
+    pub raw: String,
+}
+
+impl SignatureInput {
+    pub fn parse(raw: &str) -> Result<Self, GnapError> {
+        let raw = raw.trim();
+        let end = raw.find(')').ok_or(GnapError::InvalidProof)?;
+        if !raw.starts_with('(') {
+            return Err(GnapError::InvalidProof);
+        }
+
+        let components = raw[1..end]
+            .split_whitespace()
+            .map(|c| unquote(c).to_lowercase())
+            .collect::<Vec<String>>();
+
+        let mut input = SignatureInput {
+            components,
+            created: None,
+            keyid: None,
+            alg: None,
+            raw: raw.to_owned(),
+        };
+
+        for param in raw[end + 1..].split(';').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=').ok_or(GnapError::InvalidProof)?;
+            match name.trim() {
+                "created" => {
+                    input.created = Some(value.parse().map_err(|_| GnapError::InvalidProof)?)
+                }
+                "keyid" => input.keyid = Some(unquote(value).to_owned()),
+                "alg" => input.alg = Some(unquote(value).to_owned()),
+                _ => trace!("Ignoring signature parameter {}", name),
+            }
+        }
+
+        Ok(input)
+    }
+}
+
+/// Verify the signature of `proof` against the JWK of `key`.  Returns the
+/// signature base that was signed.
+pub fn verify(proof: &RequestProof, key: &ClientKey) -> Result<String, GnapError> {
+    let jwk = key.jwk.as_ref().ok_or_else(|| {
+        debug!("httpsig requires a jwk key");
+        GnapError::InvalidProof
+    })?;
+
+    let (label, input) = dictionary(proof.header("signature-input")?)
+        .into_iter()
+        .next()
+        .ok_or(GnapError::InvalidProof)?;
+    let input = SignatureInput::parse(&input)?;
+
+    let signature = dictionary(proof.header("signature")?)
+        .into_iter()
+        .find(|(name, _)| name.eq(&label))
+        .map(|(_, value)| byte_sequence(&value))
+        .ok_or(GnapError::InvalidProof)??;
+
+    if let (Some(keyid), Some(kid)) = (&input.keyid, &jwk.common.key_id) {
+        if keyid.ne(kid) {
+            debug!(
+                "Signature keyid {} does not match registered key {}",
+                keyid, kid
+            );
+            return Err(GnapError::InvalidProof);
+        }
+    }
+
+    check_components(proof, &input)?;
+    check_created(input.created.ok_or(GnapError::InvalidProof)?)?;
+    if !proof.body.is_empty() {
+        verify_content_digest(proof)?;
+    }
+
+    let base = signature_base(proof, &input)?;
+    trace!("Signature base:\n{}", &base);
+
+    let algorithm = algorithm(&input, jwk)?;
+    let decoding_key = DecodingKey::from_jwk(jwk).map_err(|_| GnapError::InvalidProof)?;
+    let signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
+
+    match crypto::verify(&signature, base.as_bytes(), &decoding_key, algorithm) {
+        Ok(true) => Ok(base),
+        _ => {
+            debug!("httpsig signature did not verify");
+            Err(GnapError::InvalidProof)
+        }
+    }
+}
+
+/// Build the signature base (RFC 9421 Section 2.5) for the covered components.
+pub fn signature_base(proof: &RequestProof, input: &SignatureInput) -> Result<String, GnapError> {
+    let uri: Uri = proof
+        .target_uri
+        .parse()
+        .map_err(|_| GnapError::InvalidProof)?;
+
+    let mut base = String::new();
+    for component in input.components.iter() {
+        let value = match component.as_str() {
+            "@method" => proof.method.to_uppercase(),
+            "@target-uri" => proof.target_uri.clone(),
+            "@authority" => uri
+                .authority()
+                .map(|a| a.as_str().to_lowercase())
+                .ok_or(GnapError::InvalidProof)?,
+            "@scheme" => uri
+                .scheme_str()
+                .map(|s| s.to_lowercase())
+                .ok_or(GnapError::InvalidProof)?,
+            "@path" => uri.path().to_owned(),
+            "@query" => format!("?{}", uri.query().unwrap_or_default()),
+            name if name.starts_with('@') => {
+                debug!("Unsupported derived component {}", name);
+                return Err(GnapError::InvalidProof);
+            }
+            name => header_field(proof, name).ok_or(GnapError::InvalidProof)?,
+        };
+        base.push_str(&format!("\"{}\": {}\n", component, value));
+    }
+    base.push_str(&format!("\"@signature-params\": {}", input.raw));
+
+    Ok(base)
+}
+
+/// GNAP requires the method and target to always be signed, the body digest
+/// when there is a body, and the `Authorization` header when it is sent.
+fn check_components(proof: &RequestProof, input: &SignatureInput) -> Result<(), GnapError> {
+    let mut required = vec!["@method", "@target-uri"];
+    if !proof.body.is_empty() {
+        required.push("content-digest");
+    }
+    if proof.headers.contains_key("authorization") {
+        required.push("authorization");
+    }
+
+    for component in required {
+        if !input.components.iter().any(|c| c.eq(component)) {
+            debug!("Signature does not cover {}", component);
+            return Err(GnapError::InvalidProof);
+        }
+    }
+    Ok(())
+}
+
+/// Check the `Content-Digest` header (RFC 9530) against the request body.
+fn verify_content_digest(proof: &RequestProof) -> Result<(), GnapError> {
+    let mut verified = false;
+    for (alg, value) in dictionary(proof.header("content-digest")?) {
+        let digest = byte_sequence(&value)?;
+        let expected = match alg.as_str() {
+            "sha-256" => sha256(&proof.body).to_vec(),
+            "sha-512" => sha512(&proof.body).to_vec(),
+            _ => {
+                trace!("Ignoring unsupported digest {}", alg);
+                continue;
+            }
+        };
+        if digest.ne(&expected) {
+            debug!("Content-Digest {} does not match the body", alg);
+            return Err(GnapError::InvalidProof);
+        }
+        verified = true;
+    }
+
+    if verified {
+        Ok(())
+    } else {
+        Err(GnapError::InvalidProof)
+    }
+}
+
+/// Select the signing algorithm from the `alg` parameter, or from the key.
+fn algorithm(input: &SignatureInput, jwk: &Jwk) -> Result<Algorithm, GnapError> {
+    if let Some(alg) = &input.alg {
+        let algorithm = match alg.as_str() {
+            "rsa-pss-sha512" => Algorithm::PS512,
+            "rsa-v1_5-sha256" => Algorithm::RS256,
+            "ecdsa-p256-sha256" => Algorithm::ES256,
+            "ecdsa-p384-sha384" => Algorithm::ES384,
+            "ed25519" => Algorithm::EdDSA,
+            _ => {
+                debug!("Unsupported httpsig algorithm {}", alg);
+                return Err(GnapError::InvalidProof);
+            }
+        };
+        check_key_algorithm(jwk, algorithm)?;
+        return Ok(algorithm);
+    }
+
+    key_algorithm(jwk)
+}
+
+/// All values of a header field, combined as described in RFC 9421 Section 2.1.
+fn header_field(proof: &RequestProof, name: &str) -> Option<String> {
+    let values = proof
+        .headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .collect::<Vec<&str>>();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// Split a structured field dictionary into its members.  Commas inside
+/// inner lists or strings do not separate members.
+fn dictionary(value: &str) -> Vec<(String, String)> {
+    let mut members = Vec::<(String, String)>::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+
+    let mut push = |member: &str| {
+        if let Some((name, value)) = member.trim().split_once('=') {
+            members.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    };
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    push(&value[start..]);
+
+    members
+}
+
+/// Decode a structured field byte sequence, `:base64:`.
+fn byte_sequence(value: &str) -> Result<Vec<u8>, GnapError> {
+    let inner = value
+        .trim()
+        .strip_prefix(':')
+        .and_then(|v| v.strip_suffix(':'))
+        .ok_or(GnapError::InvalidProof)?;
+    base64::decode_config(inner, base64::STANDARD).map_err(|_| GnapError::InvalidProof)
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim().trim_matches('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::MAX_SIGNATURE_AGE;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use model::key::KeyProofMethod;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const BODY: &str =
+        r#"{"access_token":{"access":["foo"]},"client":"7e057b0c-17e8-4ab4-9260-2b33f32b2cce"}"#;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn client_key(pkey: &PKey<Private>) -> ClientKey {
+        let x = base64::encode_config(pkey.raw_public_key().unwrap(), base64::URL_SAFE_NO_PAD);
+        let key = format!(
+            r#"{{"proof": "httpsig", "jwk": {{"kty": "OKP", "crv": "Ed25519", "kid": "test-key", "x": "{}"}}}}"#,
+            x
+        );
+        serde_json::from_str(&key).unwrap()
+    }
+
+    fn digest(body: &str) -> String {
+        format!(
+            "sha-256=:{}:",
+            base64::encode_config(sha256(body.as_bytes()), base64::STANDARD)
+        )
+    }
+
+    fn signed_proof(
+        pkey: &PKey<Private>,
+        body: &str,
+        sent_body: &str,
+        created: u64,
+    ) -> RequestProof {
+        let params = format!(
+            r#"("@method" "@target-uri" "content-digest" "content-type");created={};keyid="test-key";alg="ed25519""#,
+            created
+        );
+        let unsigned = TestRequest::post()
+            .uri("/gnap/tx")
+            .insert_header(("host", "localhost:8000"))
+            .insert_header(("content-type", "application/json"))
+            .insert_header(("content-digest", digest(body)))
+            .to_http_request();
+        let proof = RequestProof::from_parts(&unsigned, Bytes::from(body.to_owned()));
+        let base = signature_base(&proof, &SignatureInput::parse(&params).unwrap()).unwrap();
+
+        let mut signer = Signer::new_without_digest(pkey).unwrap();
+        let signature = signer.sign_oneshot_to_vec(base.as_bytes()).unwrap();
+
+        let signed = TestRequest::post()
+            .uri("/gnap/tx")
+            .insert_header(("host", "localhost:8000"))
+            .insert_header(("content-type", "application/json"))
+            .insert_header(("content-digest", digest(body)))
+            .insert_header(("signature-input", format!("sig1={}", params)))
+            .insert_header((
+                "signature",
+                format!(
+                    "sig1=:{}:",
+                    base64::encode_config(signature, base64::STANDARD)
+                ),
+            ))
+            .to_http_request();
+        RequestProof::from_parts(&signed, Bytes::from(sent_body.to_owned()))
+    }
+
+    #[test]
+    fn parse_signature_input() {
+        let input = SignatureInput::parse(
+            r#"("@method" "@target-uri" "content-digest");created=1618884473;keyid="test-key-ed25519""#,
+        )
+        .unwrap();
+        assert_eq!(
+            input.components,
+            vec!["@method", "@target-uri", "content-digest"]
+        );
+        assert_eq!(input.created, Some(1618884473));
+        assert_eq!(input.keyid, Some("test-key-ed25519".to_owned()));
+        assert_eq!(input.alg, None);
+    }
+
+    #[test]
+    fn build_signature_base() {
+        let params = r#"("@method" "@target-uri" "content-type");created=1618884473"#;
+        let request = TestRequest::post()
+            .uri("/gnap/tx?a=b")
+            .insert_header(("host", "localhost:8000"))
+            .insert_header(("content-type", "application/json"))
+            .to_http_request();
+        let proof = RequestProof::from_parts(&request, Bytes::new());
+        let base = signature_base(&proof, &SignatureInput::parse(params).unwrap()).unwrap();
+
+        assert_eq!(
+            base,
+            "\"@method\": POST\n\
+             \"@target-uri\": http://localhost:8000/gnap/tx?a=b\n\
+             \"content-type\": application/json\n\
+             \"@signature-params\": (\"@method\" \"@target-uri\" \"content-type\");created=1618884473"
+        );
+    }
+
+    #[test]
+    fn split_dictionary() {
+        let members = dictionary(r#"sig1=("@method" "a,b");created=1, sig2=:AAA=:"#);
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].0, "sig1");
+        assert_eq!(members[1], ("sig2".to_owned(), ":AAA=:".to_owned()));
+    }
+
+    #[test]
+    fn verify_signed_request() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let proof = signed_proof(&pkey, BODY, BODY, now());
+        assert!(verify(&proof, &client_key(&pkey)).is_ok());
+    }
+
+    #[test]
+    fn reject_tampered_body() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let proof = signed_proof(&pkey, BODY, r#"{"access_token":{"access":["bar"]}}"#, now());
+        assert!(verify(&proof, &client_key(&pkey)).is_err());
+    }
+
+    #[test]
+    fn reject_other_key() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let other = PKey::generate_ed25519().unwrap();
+        let proof = signed_proof(&pkey, BODY, BODY, now());
+        assert!(verify(&proof, &client_key(&other)).is_err());
+    }
+
+    #[test]
+    fn reject_stale_signature() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let proof = signed_proof(&pkey, BODY, BODY, now() - MAX_SIGNATURE_AGE - 10);
+        assert!(verify(&proof, &client_key(&pkey)).is_err());
+    }
+
+    #[test]
+    fn reject_unsigned_request() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let request = TestRequest::post()
+            .uri("/gnap/tx")
+            .insert_header(("content-type", "application/json"))
+            .to_http_request();
+        let proof = RequestProof::from_parts(&request, Bytes::from(BODY));
+        let key = client_key(&pkey);
+        assert_eq!(key.proof, KeyProofMethod::Httpsig);
+        assert!(verify(&proof, &key).is_err());
+    }
+
+    #[test]
+    fn reject_algorithm_of_other_key_family() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let key = client_key(&pkey);
+        let jwk = key.jwk.as_ref().unwrap();
+        let input = SignatureInput::parse(r#"("@method");created=1618884473;alg="rsa-pss-sha512""#)
+            .unwrap();
+        assert!(matches!(
+            algorithm(&input, jwk),
+            Err(GnapError::InvalidProof)
+        ));
+    }
+}
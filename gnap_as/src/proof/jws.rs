@@ -0,0 +1,372 @@
+//! JSON Web Signature key proofing
+//!
+//! Implements the `jwsd` (GNAP Section 7.3.3) and `jws` (Section 7.3.4) proof
+//! methods.  Both sign a JWS whose protected header binds the signature to the
+//! HTTP request with the `htm`, `uri`, `created` and, when an access token is
+//! presented, `ath` parameters.
+//!
+//! With `jwsd` the JWS is detached: the payload is the request body, and the
+//! JWS is sent without it in the `Detached-JWS` header.  With `jws` the request
+//! body itself is the compact JWS, sent as `application/jose`.  Requests without
+//! a body always use the detached form.
+use super::{check_created, check_key_algorithm, RequestProof};
+use errors::GnapError;
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{crypto, Algorithm, DecodingKey};
+use log::{debug, trace};
+use model::key::ClientKey;
+use openssl::sha::sha256;
+use serde::Deserialize;
+
+const TYP_DETACHED: &str = "gnap-binding+jwsd";
+const TYP_ATTACHED: &str = "gnap-binding+jws";
+const CONTENT_TYPE_JOSE: &str = "application/jose";
+
+/// The protected header of a GNAP binding JWS.
+#[derive(Debug, Deserialize)]
+pub struct BindingHeader {
+    pub alg: Algorithm,
+    pub kid: Option<String>,
+    pub typ: Option<String>,
+    /// The HTTP method of the request.
+    pub htm: String,
+    /// The HTTP URI of the request.
+    pub uri: String,
+    /// Creation time of the signature, in seconds since the epoch.
+    pub created: u64,
+    /// Hash of the access token presented with the request.
+    pub ath: Option<String>,
+}
+
+/// Verify a `Detached-JWS` header against the request body.  Returns the
+/// JWS signing input.
+pub fn verify_detached(proof: &RequestProof, key: &ClientKey) -> Result<String, GnapError> {
+    let jwk = jwk(key)?;
+    let (header, payload, signature) = split(proof.header("detached-jws")?)?;
+    if !payload.is_empty() {
+        debug!("Detached JWS must not carry a payload");
+        return Err(GnapError::InvalidProof);
+    }
+
+    let payload = base64::encode_config(&proof.body, base64::URL_SAFE_NO_PAD);
+    verify_binding(proof, jwk, header, &payload, signature, TYP_DETACHED)
+}
+
+/// Verify a request whose body is an attached JWS.  Returns the JWS signing
+/// input.
+pub fn verify_attached(proof: &RequestProof, key: &ClientKey) -> Result<String, GnapError> {
+    if proof.body.is_empty() {
+        return verify_detached(proof, key);
+    }
+    if !is_attached(proof) {
+        debug!("Attached JWS must be sent as {}", CONTENT_TYPE_JOSE);
+        return Err(GnapError::InvalidProof);
+    }
+
+    let jwk = jwk(key)?;
+    let body = std::str::from_utf8(&proof.body).map_err(|_| GnapError::InvalidProof)?;
+    let (header, payload, signature) = split(body)?;
+    verify_binding(proof, jwk, header, payload, signature, TYP_ATTACHED)
+}
+
+/// Whether the request body is a compact JWS.
+pub fn is_attached(proof: &RequestProof) -> bool {
+    proof
+        .header("content-type")
+        .map(|value| value.trim().eq_ignore_ascii_case(CONTENT_TYPE_JOSE))
+        .unwrap_or(false)
+}
+
+/// The decoded payload of an attached JWS body.
+pub fn payload(proof: &RequestProof) -> Result<Vec<u8>, GnapError> {
+    let body = std::str::from_utf8(&proof.body).map_err(|_| GnapError::BadData)?;
+    let (_, payload, _) = split(body).map_err(|_| GnapError::BadData)?;
+    base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| GnapError::BadData)
+}
+
+fn verify_binding(
+    proof: &RequestProof,
+    jwk: &Jwk,
+    header: &str,
+    payload: &str,
+    signature: &str,
+    typ: &str,
+) -> Result<String, GnapError> {
+    let decoded = base64::decode_config(header, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| GnapError::InvalidProof)?;
+    let binding: BindingHeader = serde_json::from_slice(&decoded).map_err(|err| {
+        debug!("Invalid JWS header: {:?}", err);
+        GnapError::InvalidProof
+    })?;
+    trace!("JWS header: {:?}", &binding);
+
+    check_binding(proof, jwk, &binding, typ)?;
+
+    let decoding_key = DecodingKey::from_jwk(jwk).map_err(|_| GnapError::InvalidProof)?;
+    let message = format!("{}.{}", header, payload);
+    match crypto::verify(signature, message.as_bytes(), &decoding_key, binding.alg) {
+        Ok(true) => Ok(message),
+        _ => {
+            debug!("JWS signature did not verify");
+            Err(GnapError::InvalidProof)
+        }
+    }
+}
+
+/// Check that the header binds the signature to this request and key.
+fn check_binding(
+    proof: &RequestProof,
+    jwk: &Jwk,
+    binding: &BindingHeader,
+    typ: &str,
+) -> Result<(), GnapError> {
+    if binding.typ.as_deref() != Some(typ) {
+        debug!("JWS typ {:?} is not {}", binding.typ, typ);
+        return Err(GnapError::InvalidProof);
+    }
+
+    if let (Some(kid), Some(key_id)) = (&binding.kid, &jwk.common.key_id) {
+        if kid.ne(key_id) {
+            debug!("JWS kid {} does not match registered key {}", kid, key_id);
+            return Err(GnapError::InvalidProof);
+        }
+    }
+
+    check_key_algorithm(jwk, binding.alg)?;
+
+    if !binding.htm.eq_ignore_ascii_case(&proof.method) || binding.uri.ne(&proof.target_uri) {
+        debug!(
+            "JWS is bound to {} {}, not {} {}",
+            binding.htm, binding.uri, proof.method, proof.target_uri
+        );
+        return Err(GnapError::InvalidProof);
+    }
+
+    check_created(binding.created)?;
+
+    if let Some(token) = proof.access_token() {
+        let ath = base64::encode_config(sha256(token.as_bytes()), base64::URL_SAFE_NO_PAD);
+        if binding.ath.as_deref() != Some(ath.as_str()) {
+            debug!("JWS ath does not match the presented access token");
+            return Err(GnapError::InvalidProof);
+        }
+    }
+
+    Ok(())
+}
+
+fn jwk(key: &ClientKey) -> Result<&Jwk, GnapError> {
+    key.jwk.as_ref().ok_or_else(|| {
+        debug!("JWS proofing requires a jwk key");
+        GnapError::InvalidProof
+    })
+}
+
+/// Split a compact JWS into its header, payload and signature.
+fn split(jws: &str) -> Result<(&str, &str, &str), GnapError> {
+    let mut parts = jws.trim().split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(signature), None) => Ok((header, payload, signature)),
+        _ => {
+            debug!("Malformed compact JWS");
+            Err(GnapError::InvalidProof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use model::key::KeyProofMethod;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const BODY: &str =
+        r#"{"access_token":{"access":["foo"]},"client":"7e057b0c-17e8-4ab4-9260-2b33f32b2cce"}"#;
+    const URI: &str = "http://localhost:8000/gnap/tx";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn client_key(pkey: &PKey<Private>, proof: &str) -> ClientKey {
+        let x = base64::encode_config(pkey.raw_public_key().unwrap(), base64::URL_SAFE_NO_PAD);
+        serde_json::from_value(json!({
+            "proof": proof,
+            "jwk": { "kty": "OKP", "crv": "Ed25519", "kid": "test-key", "x": x }
+        }))
+        .unwrap()
+    }
+
+    fn b64(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Sign `payload` as a compact JWS with the binding header values.
+    fn sign(
+        pkey: &PKey<Private>,
+        typ: &str,
+        htm: &str,
+        payload: &[u8],
+        ath: Option<&str>,
+    ) -> String {
+        let mut header = json!({
+            "alg": "EdDSA",
+            "kid": "test-key",
+            "typ": typ,
+            "htm": htm,
+            "uri": URI,
+            "created": now(),
+        });
+        if let Some(token) = ath {
+            header["ath"] = json!(b64(&sha256(token.as_bytes())));
+        }
+
+        let message = format!("{}.{}", b64(header.to_string().as_bytes()), b64(payload));
+        let mut signer = Signer::new_without_digest(pkey).unwrap();
+        let signature = signer.sign_oneshot_to_vec(message.as_bytes()).unwrap();
+        format!("{}.{}", message, b64(&signature))
+    }
+
+    fn detached(jws: &str) -> String {
+        let mut parts = jws.split('.');
+        let header = parts.next().unwrap();
+        let _ = parts.next();
+        format!("{}..{}", header, parts.next().unwrap())
+    }
+
+    fn request(method: &str, headers: Vec<(&str, String)>, body: &str) -> RequestProof {
+        let mut request = TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri("/gnap/tx")
+            .insert_header(("host", "localhost:8000"));
+        for header in headers {
+            request = request.insert_header(header);
+        }
+        RequestProof::from_parts(&request.to_http_request(), Bytes::from(body.to_owned()))
+    }
+
+    #[test]
+    fn verify_detached_jws() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let jws = sign(&pkey, TYP_DETACHED, "POST", BODY.as_bytes(), None);
+        let proof = request("POST", vec![("detached-jws", detached(&jws))], BODY);
+        assert!(proof.verify(&client_key(&pkey, "jwsd")).is_ok());
+    }
+
+    #[test]
+    fn verify_attached_jws() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let jws = sign(&pkey, TYP_ATTACHED, "POST", BODY.as_bytes(), None);
+        let proof = request(
+            "POST",
+            vec![("content-type", CONTENT_TYPE_JOSE.to_owned())],
+            &jws,
+        );
+        let key = client_key(&pkey, "jws");
+        assert_eq!(key.proof, KeyProofMethod::Jws);
+        assert!(proof.verify(&key).is_ok());
+
+        let body: serde_json::Value = proof.json().unwrap();
+        assert_eq!(body["client"], "7e057b0c-17e8-4ab4-9260-2b33f32b2cce");
+    }
+
+    #[test]
+    fn verify_access_token_hash() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let key = client_key(&pkey, "jwsd");
+        let jws = sign(
+            &pkey,
+            TYP_DETACHED,
+            "DELETE",
+            b"",
+            Some("OS9M2PMHKUR64TB8N6BW7OZB8CDFONP219RP1LT0"),
+        );
+
+        let proof = request(
+            "DELETE",
+            vec![
+                ("detached-jws", detached(&jws)),
+                (
+                    "authorization",
+                    "GNAP OS9M2PMHKUR64TB8N6BW7OZB8CDFONP219RP1LT0".to_owned(),
+                ),
+            ],
+            "",
+        );
+        assert!(proof.verify(&key).is_ok());
+
+        let proof = request(
+            "DELETE",
+            vec![
+                ("detached-jws", detached(&jws)),
+                ("authorization", "GNAP 80UPRY5NM33OMUKMKSKU".to_owned()),
+            ],
+            "",
+        );
+        assert!(proof.verify(&key).is_err());
+    }
+
+    #[test]
+    fn reject_tampered_body() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let jws = sign(&pkey, TYP_DETACHED, "POST", BODY.as_bytes(), None);
+        let proof = request(
+            "POST",
+            vec![("detached-jws", detached(&jws))],
+            &BODY.replace("foo", "bar"),
+        );
+        assert!(proof.verify(&client_key(&pkey, "jwsd")).is_err());
+    }
+
+    #[test]
+    fn reject_wrong_method() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let jws = sign(&pkey, TYP_DETACHED, "PATCH", BODY.as_bytes(), None);
+        let proof = request("POST", vec![("detached-jws", detached(&jws))], BODY);
+        assert!(proof.verify(&client_key(&pkey, "jwsd")).is_err());
+    }
+
+    #[test]
+    fn reject_wrong_typ() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let jws = sign(&pkey, TYP_ATTACHED, "POST", BODY.as_bytes(), None);
+        let proof = request("POST", vec![("detached-jws", detached(&jws))], BODY);
+        assert!(proof.verify(&client_key(&pkey, "jwsd")).is_err());
+    }
+
+    #[test]
+    fn accept_every_algorithm_of_the_key() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let jwk: Jwk = serde_json::from_value(json!({
+            "kty": "RSA",
+            "n": b64(&rsa.n().to_vec()),
+            "e": b64(&rsa.e().to_vec())
+        }))
+        .unwrap();
+        let proof = request("POST", vec![], BODY);
+        let binding = |alg: Algorithm| BindingHeader {
+            alg,
+            kid: None,
+            typ: Some(TYP_DETACHED.to_owned()),
+            htm: "POST".to_owned(),
+            uri: URI.to_owned(),
+            created: now(),
+            ath: None,
+        };
+
+        for alg in [Algorithm::PS512, Algorithm::PS256, Algorithm::RS256] {
+            assert!(check_binding(&proof, &jwk, &binding(alg), TYP_DETACHED).is_ok());
+        }
+        for alg in [Algorithm::ES256, Algorithm::HS256] {
+            assert!(check_binding(&proof, &jwk, &binding(alg), TYP_DETACHED).is_err());
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Key proofing for client instance requests
+//!
+//! GNAP binds the requests of a client instance to the key it registered.  The
+//! `proof` method of that key decides how possession is demonstrated.  Handlers
+//! that need a proof take a [RequestProof] instead of `web::Json`, so the raw
+//! body is still available when the signature is checked.
+//!
+//! Signatures cover the target URI, which is built from the public origin of
+//! the AS rather than from headers the client controls.  A signature is only
+//! accepted once: the signed message is remembered for as long as its
+//! `created` time is valid.
+use crate::grant::ISSUER;
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Bytes;
+use actix_web::{FromRequest, HttpRequest};
+use dao::service::Service;
+use errors::GnapError;
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk};
+use jsonwebtoken::Algorithm;
+use log::{debug, error};
+use model::client::GnapClient;
+use model::key::{ClientKey, KeyProofMethod};
+use model::transaction::GnapTransaction;
+use model::GnapID;
+use openssl::sha::sha256;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod httpsig;
+pub mod jws;
+pub mod mtls;
+
+/// Signatures older than this (in seconds) are rejected.
+const MAX_SIGNATURE_AGE: u64 = 300;
+/// Allowed clock skew (in seconds) for signatures created in the future.
+const MAX_CLOCK_SKEW: u64 = 30;
+
+/// The parts of an incoming request that are covered by a key proof.
+pub struct RequestProof {
+    pub method: String,
+    pub target_uri: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// The client certificate of a mutual TLS connection.
+    pub peer_certificate: Option<mtls::PeerCertificate>,
+}
+
+impl RequestProof {
+    pub fn from_parts(request: &HttpRequest, body: Bytes) -> Self {
+        let target_uri = format!("{}{}", public_origin(), request.uri());
+
+        Self {
+            method: request.method().to_string(),
+            target_uri,
+            headers: request.headers().clone(),
+            body,
+            peer_certificate: request.conn_data::<mtls::PeerCertificate>().cloned(),
+        }
+    }
+
+    /// Deserialize the JSON body that was covered by the proof.  An attached
+    /// JWS body is unwrapped first.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, GnapError> {
+        let payload = if jws::is_attached(self) {
+            jws::payload(self)?
+        } else {
+            self.body.to_vec()
+        };
+
+        serde_json::from_slice(&payload).map_err(|err| {
+            debug!("Failed to parse request body: {:?}", err);
+            GnapError::BadData
+        })
+    }
+
+    /// The value of a single header, if present and valid.
+    pub fn header(&self, name: &str) -> Result<&str, GnapError> {
+        match self.headers.get(name).map(|value| value.to_str()) {
+            Some(Ok(value)) => Ok(value),
+            _ => {
+                debug!("Missing or invalid {} header", name);
+                Err(GnapError::InvalidProof)
+            }
+        }
+    }
+
+    /// The access token presented with `Authorization: GNAP`, if any.
+    pub fn access_token(&self) -> Option<&str> {
+        self.header("authorization")
+            .ok()
+            .and_then(|value| value.strip_prefix("GNAP "))
+            .map(|token| token.trim())
+    }
+
+    /// Verify that the request was made by the holder of `key`.  Returns the
+    /// signed message, or `None` when the proof is not a signature.
+    pub fn verify(&self, key: &ClientKey) -> Result<Option<String>, GnapError> {
+        match key.proof {
+            KeyProofMethod::Httpsig => httpsig::verify(self, key).map(Some),
+            KeyProofMethod::Jwsd => jws::verify_detached(self, key).map(Some),
+            KeyProofMethod::Jws => jws::verify_attached(self, key).map(Some),
+            KeyProofMethod::Mtls => mtls::verify(self, key).map(|_| None),
+        }
+    }
+}
+
+impl FromRequest for RequestProof {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = Bytes::from_request(&req, payload);
+        Box::pin(async move {
+            let body = body.await?;
+            Ok(RequestProof::from_parts(&req, body))
+        })
+    }
+}
+
+/// Verify the proof against an optional registered key.  A client without a
+/// key can never prove possession, so the request is rejected, and so is a
+/// signature that was already used.
+pub async fn verify_client_key(
+    service: &Service,
+    proof: &RequestProof,
+    key: Option<&ClientKey>,
+) -> Result<(), GnapError> {
+    let key = key.ok_or_else(|| {
+        error!("Client instance has no key to verify the request against");
+        GnapError::InvalidProof
+    })?;
+    match proof.verify(key)? {
+        Some(signed) => {
+            let digest = base64::encode_config(sha256(signed.as_bytes()), base64::URL_SAFE_NO_PAD);
+            let window = (MAX_SIGNATURE_AGE + MAX_CLOCK_SKEW) as usize;
+            if service.record_proof(&digest, window).await? {
+                Ok(())
+            } else {
+                debug!("Signature was already used");
+                Err(GnapError::InvalidProof)
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// Verify the proof against the key of the client instance that started the
+/// transaction.
+pub async fn verify_transaction_client(
+    service: &Service,
+    tx: &GnapTransaction,
+    proof: &RequestProof,
+) -> Result<GnapClient, GnapError> {
+    let client_id = tx.request.as_ref().ok_or(GnapError::BadData)?.parse_id()?;
+    let client = service
+        .get_client(&client_id)
+        .await?
+        .ok_or(GnapError::NotFound)?;
+    verify_client_key(service, proof, client.key.as_ref()).await?;
+    Ok(client)
+}
+
+/// The origin clients reach the AS at, from `PUBLIC_ORIGIN`.  Defaults to
+/// the issuer.
+fn public_origin() -> String {
+    env::var("PUBLIC_ORIGIN")
+        .map(|origin| origin.trim_end_matches('/').to_owned())
+        .unwrap_or_else(|_| ISSUER.to_owned())
+}
+
+/// Reject proofs created too long ago, or too far in the future.
+fn check_created(created: u64) -> Result<(), GnapError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| GnapError::GeneralError)?
+        .as_secs();
+
+    if created > now + MAX_CLOCK_SKEW || now.saturating_sub(created) > MAX_SIGNATURE_AGE {
+        debug!("Proof created at {} is outside the allowed window", created);
+        return Err(GnapError::InvalidProof);
+    }
+    Ok(())
+}
+
+/// The algorithm to use with a key that does not name one in the proof.  An
+/// `alg` declared by the key itself must belong to its key family.
+fn key_algorithm(jwk: &Jwk) -> Result<Algorithm, GnapError> {
+    let alg = match (jwk.common.algorithm, &jwk.algorithm) {
+        (Some(alg), _) => alg,
+        (None, AlgorithmParameters::RSA(_)) => Algorithm::PS512,
+        (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+            EllipticCurve::P256 => Algorithm::ES256,
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => return Err(GnapError::InvalidProof),
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+        (None, AlgorithmParameters::OctetKey(_)) => return Err(GnapError::InvalidProof),
+    };
+    check_key_algorithm(jwk, alg)?;
+    Ok(alg)
+}
+
+/// Reject an algorithm that does not belong to the family of the key.  HMAC
+/// is never accepted: a client key is public, so it cannot key a MAC.
+fn check_key_algorithm(jwk: &Jwk, alg: Algorithm) -> Result<(), GnapError> {
+    let allowed = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(
+            alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(params) => matches!(
+            (&params.curve, alg),
+            (EllipticCurve::P256, Algorithm::ES256) | (EllipticCurve::P384, Algorithm::ES384)
+        ),
+        AlgorithmParameters::OctetKeyPair(_) => alg == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => false,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        debug!("Algorithm {:?} cannot be used with this key", alg);
+        Err(GnapError::InvalidProof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn jwk(value: serde_json::Value) -> Jwk {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn rsa(alg: Option<&str>) -> Jwk {
+        let mut value = serde_json::json!({
+            "kty": "RSA",
+            "n": "sXchDaQebHnPiGvyDOAT4saGEUetSyo9MKLOoWFsueri23bOdgWp4Dy1WlUzewbgBHod5pcM9H95GQRV3JDXboIRROSBigeC5yjU1hGzHHyXss8UDprecbAYxknTcQkhslANGRUZmdTOQ5qTRsLAt6BTYuyvVRdhS8exSZEy_c4gs_7svlJJQ4H9_NxsiIoLwAEk7-Q3UXERGYw_75IDrGA84-lA_-Ct4eTlXHBIY2EaV7t7LjJaynVJCpkv4LKjTTAumiGUIuQhrNhZLuF_RJLqHpM2kgWFLU7-VTdL1VbC2tejvcI2BlMkEpk1BzBZI0KQB0GaDWFLN-aEAw3vRw",
+            "e": "AQAB"
+        });
+        if let Some(alg) = alg {
+            value["alg"] = serde_json::json!(alg);
+        }
+        jwk(value)
+    }
+
+    fn p256(alg: Option<&str>) -> Jwk {
+        let mut value = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+            "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"
+        });
+        if let Some(alg) = alg {
+            value["alg"] = serde_json::json!(alg);
+        }
+        jwk(value)
+    }
+
+    fn ed25519(alg: Option<&str>) -> Jwk {
+        let mut value = serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+        });
+        if let Some(alg) = alg {
+            value["alg"] = serde_json::json!(alg);
+        }
+        jwk(value)
+    }
+
+    #[test]
+    fn default_algorithm_for_key_type() {
+        assert_eq!(key_algorithm(&rsa(None)).unwrap(), Algorithm::PS512);
+        assert_eq!(key_algorithm(&p256(None)).unwrap(), Algorithm::ES256);
+        assert_eq!(key_algorithm(&ed25519(None)).unwrap(), Algorithm::EdDSA);
+    }
+
+    #[test]
+    fn declared_algorithm_of_key_family() {
+        assert_eq!(
+            key_algorithm(&rsa(Some("RS256"))).unwrap(),
+            Algorithm::RS256
+        );
+        assert_eq!(
+            key_algorithm(&p256(Some("ES256"))).unwrap(),
+            Algorithm::ES256
+        );
+        assert_eq!(
+            key_algorithm(&ed25519(Some("EdDSA"))).unwrap(),
+            Algorithm::EdDSA
+        );
+    }
+
+    #[test]
+    fn reject_mismatched_algorithm() {
+        assert!(matches!(
+            key_algorithm(&rsa(Some("ES256"))),
+            Err(GnapError::InvalidProof)
+        ));
+        assert!(matches!(
+            key_algorithm(&p256(Some("ES384"))),
+            Err(GnapError::InvalidProof)
+        ));
+        assert!(matches!(
+            key_algorithm(&ed25519(Some("PS512"))),
+            Err(GnapError::InvalidProof)
+        ));
+    }
+
+    #[test]
+    fn reject_hmac_algorithm() {
+        for key in [
+            rsa(Some("HS256")),
+            p256(Some("HS256")),
+            ed25519(Some("HS512")),
+        ] {
+            assert!(matches!(key_algorithm(&key), Err(GnapError::InvalidProof)));
+        }
+        assert!(matches!(
+            check_key_algorithm(&p256(None), Algorithm::HS256),
+            Err(GnapError::InvalidProof)
+        ));
+    }
+
+    #[test]
+    fn reject_symmetric_key() {
+        let key = jwk(serde_json::json!({
+            "kty": "oct",
+            "k": "c2VjcmV0",
+            "alg": "HS256"
+        }));
+        assert!(matches!(key_algorithm(&key), Err(GnapError::InvalidProof)));
+    }
+
+    #[test]
+    fn target_uri_from_public_origin() {
+        let request = TestRequest::default()
+            .uri("/gnap/tx?a=b")
+            .insert_header(("host", "attacker.example"))
+            .insert_header(("x-forwarded-host", "attacker.example"))
+            .insert_header(("forwarded", "host=attacker.example;proto=https"))
+            .to_http_request();
+        let proof = RequestProof::from_parts(&request, Bytes::new());
+        assert_eq!(proof.target_uri, format!("{}/gnap/tx?a=b", public_origin()));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn proof_is_used_once() {
+        dotenv::dotenv().ok();
+        let service = Service::create().await;
+        let digest = base64::encode_config(
+            sha256(model::tokens::generate_token().as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert!(service.record_proof(&digest, 60).await.unwrap());
+        assert!(!service.record_proof(&digest, 60).await.unwrap());
+    }
+}
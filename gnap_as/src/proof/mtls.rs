@@ -0,0 +1,187 @@
+//! Mutual TLS key proofing
+//!
+//! Implements the `mtls` proof method (GNAP Section 7.3.2).  The client instance
+//! presents its certificate in the TLS handshake, and the AS matches it to the
+//! `cert` or `cert#S256` value of the registered key.  The certificate is
+//! captured per connection with [on_connect], and is available to handlers
+//! through the [RequestProof].
+use super::RequestProof;
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use errors::GnapError;
+use log::{debug, trace};
+use model::key::{ClientKey, KeyProofMethod};
+use openssl::sha::sha256;
+use openssl::x509::X509;
+use std::any::Any;
+
+/// DER encoding of the certificate the peer presented during the handshake.
+#[derive(Clone, Debug)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// Connection callback for `HttpServer::on_connect`.  Stores the client
+/// certificate of TLS connections in the connection data.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = stream.ssl().peer_certificate() {
+            match cert.to_der() {
+                Ok(der) => {
+                    trace!("Client presented a certificate");
+                    data.insert(PeerCertificate(der));
+                }
+                Err(err) => debug!("Failed to encode client certificate: {:?}", err),
+            }
+        }
+    }
+}
+
+/// The `x5t#S256` thumbprint of a DER encoded certificate, as defined by
+/// OAuth-MTLS.
+pub fn thumbprint(der: &[u8]) -> String {
+    base64::encode_config(sha256(der), base64::URL_SAFE_NO_PAD)
+}
+
+/// Verify that the TLS client certificate matches the registered key.
+pub fn verify(proof: &RequestProof, key: &ClientKey) -> Result<(), GnapError> {
+    let presented = proof.peer_certificate.as_ref().ok_or_else(|| {
+        debug!("mtls proof requires a client certificate");
+        GnapError::InvalidProof
+    })?;
+
+    let matches = if let Some(expected) = &key.cert_s256 {
+        thumbprint(&presented.0).eq(expected)
+    } else if let Some(cert) = &key.cert {
+        registered_der(cert)?.eq(&presented.0)
+    } else {
+        debug!("mtls key has neither cert nor cert#S256");
+        return Err(GnapError::InvalidProof);
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        debug!("Client certificate does not match the registered key");
+        Err(GnapError::InvalidProof)
+    }
+}
+
+/// The key a token is bound to when issued over a mutual TLS connection.
+pub fn bound_key(proof: &RequestProof) -> Option<ClientKey> {
+    proof.peer_certificate.as_ref().map(|cert| ClientKey {
+        proof: KeyProofMethod::Mtls,
+        jwk: None,
+        cert: None,
+        cert_s256: Some(thumbprint(&cert.0)),
+    })
+}
+
+/// DER encoding of a registered `cert` value.  Accepts PEM, or the bare
+/// base64 body of a PEM.
+fn registered_der(cert: &str) -> Result<Vec<u8>, GnapError> {
+    let cert = if cert.contains("-----BEGIN") {
+        X509::from_pem(cert.as_bytes())
+    } else {
+        let body = cert.split_whitespace().collect::<String>();
+        let der = base64::decode(body).map_err(|_| GnapError::InvalidProof)?;
+        X509::from_der(&der)
+    };
+
+    cert.and_then(|cert| cert.to_der()).map_err(|err| {
+        debug!("Invalid registered certificate: {:?}", err);
+        GnapError::InvalidProof
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509NameBuilder;
+
+    fn certificate() -> X509 {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "test-client").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&pkey, MessageDigest::null()).unwrap();
+        builder.build()
+    }
+
+    fn proof(cert: Option<&X509>) -> RequestProof {
+        let mut proof = RequestProof::from_parts(
+            &TestRequest::post().uri("/gnap/tx").to_http_request(),
+            Bytes::new(),
+        );
+        proof.peer_certificate = cert.map(|cert| PeerCertificate(cert.to_der().unwrap()));
+        proof
+    }
+
+    fn key(cert: Option<String>, cert_s256: Option<String>) -> ClientKey {
+        ClientKey {
+            proof: KeyProofMethod::Mtls,
+            jwk: None,
+            cert,
+            cert_s256,
+        }
+    }
+
+    #[test]
+    fn verify_thumbprint() {
+        let cert = certificate();
+        let expected = thumbprint(&cert.to_der().unwrap());
+        assert!(proof(Some(&cert))
+            .verify(&key(None, Some(expected)))
+            .is_ok());
+    }
+
+    #[test]
+    fn verify_pem_certificate() {
+        let cert = certificate();
+        let pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        assert!(proof(Some(&cert)).verify(&key(Some(pem), None)).is_ok());
+
+        let body = base64::encode(cert.to_der().unwrap());
+        assert!(proof(Some(&cert)).verify(&key(Some(body), None)).is_ok());
+    }
+
+    #[test]
+    fn reject_other_certificate() {
+        let cert = certificate();
+        let expected = thumbprint(&certificate().to_der().unwrap());
+        assert!(proof(Some(&cert))
+            .verify(&key(None, Some(expected)))
+            .is_err());
+    }
+
+    #[test]
+    fn reject_missing_certificate() {
+        let expected = thumbprint(&certificate().to_der().unwrap());
+        assert!(proof(None).verify(&key(None, Some(expected))).is_err());
+    }
+
+    #[test]
+    fn bind_to_thumbprint() {
+        let cert = certificate();
+        let bound = bound_key(&proof(Some(&cert))).unwrap();
+        assert_eq!(bound.proof, KeyProofMethod::Mtls);
+        assert_eq!(bound.cert_s256, Some(thumbprint(&cert.to_der().unwrap())));
+        assert!(bound_key(&proof(None)).is_none());
+    }
+}
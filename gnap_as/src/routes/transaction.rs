@@ -15,12 +15,26 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                     )
                     .service(
                         web::resource("/{tx_id}")
-                            .route(web::post().to(handlers::transaction::continue_request)),
+                            .route(web::post().to(handlers::transaction::continue_request))
+                            .route(web::patch().to(handlers::transaction::modify_request))
+                            .route(web::delete().to(handlers::transaction::delete_request)),
                     ),
             )
+            .service(web::resource("/app/{code}").route(web::post().to(handlers::auth::app)))
+            .service(
+                web::resource("/device")
+                    .route(web::get().to(handlers::device::device_page))
+                    .route(web::post().to(handlers::device::device_submit)),
+            )
             .service(
                 web::resource("/introspect").route(web::post().to(handlers::resources::introspect)),
             )
+            .service(web::resource("/jwks").route(web::get().to(handlers::well_known::jwks)))
+            .service(
+                web::resource("/userinfo")
+                    .route(web::get().to(handlers::userinfo::userinfo))
+                    .route(web::post().to(handlers::userinfo::userinfo)),
+            )
             .service(
                 web::scope("/resource")
                     .service(
@@ -36,7 +50,13 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                 web::scope("/auth")
                     .service(web::resource("/").route(web::put().to(handlers::auth::create)))
                     .service(
-                        web::resource("/{instance}").route(web::get().to(handlers::auth::auth)),
+                        web::resource("/{tx_id}")
+                            .route(web::get().to(handlers::consent::interact))
+                            .route(web::post().to(handlers::consent::consent)),
+                    )
+                    .service(
+                        web::resource("/{tx_id}/login")
+                            .route(web::post().to(handlers::consent::login)),
                     ),
             )
             .service(
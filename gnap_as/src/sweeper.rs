@@ -0,0 +1,141 @@
+//! Expiry of abandoned transactions and expired tokens
+//!
+//! A transaction the end-user never finished expires, and is removed along
+//! with its tokens by a background task.  MongoDB removes expired
+//! transactions on its own as well, but can't remove their tokens.  The same
+//! task removes the access tokens of issued grants once they expire.
+use actix_web::rt::time::interval;
+use actix_web::web;
+use dao::service::Service;
+use dao::token_service::TokenService;
+use errors::GnapError;
+use log::{debug, error};
+use std::time::Duration;
+
+/// How often expired transactions are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a sweep removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Swept {
+    pub transactions: usize,
+    pub tokens: u64,
+}
+
+/// Remove the expired transactions and revoke their tokens, then remove the
+/// expired tokens of the grants that remain.
+pub async fn sweep(service: &Service, token_service: &TokenService) -> Result<Swept, GnapError> {
+    let expired = service.expired_transactions().await?;
+    for tx_id in &expired {
+        token_service.revoke_tx_tokens(tx_id).await.map_err(|err| {
+            error!("Failed to revoke tokens of {}: {:?}", tx_id, err);
+            GnapError::GeneralError
+        })?;
+        service.delete_transaction(tx_id.clone()).await?;
+    }
+    let tokens = token_service.prune_expired_tokens().await.map_err(|err| {
+        error!("Failed to prune expired tokens: {:?}", err);
+        GnapError::GeneralError
+    })?;
+    Ok(Swept {
+        transactions: expired.len(),
+        tokens,
+    })
+}
+
+/// Sweep expired transactions and tokens on a schedule, for as long as the
+/// server runs.
+pub fn spawn(service: web::Data<Service>, token_service: web::Data<TokenService>) {
+    actix_web::rt::spawn(async move {
+        let mut schedule = interval(SWEEP_INTERVAL);
+        loop {
+            schedule.tick().await;
+            match sweep(&service, &token_service).await {
+                Ok(swept) if swept == Swept::default() => (),
+                Ok(swept) => debug!(
+                    "Removed {} expired transactions and {} expired tokens",
+                    swept.transactions, swept.tokens
+                ),
+                Err(err) => error!("Failed to sweep: {:?}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::TokenError;
+    use model::tokens::{generate_token, TokenBuilder, DEFAULT_TOKEN_LIFETIME};
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, Document};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn remove_expired_token() {
+        dotenv::dotenv().ok();
+        let service = Service::create().await;
+        let token_service = TokenService::create().await;
+
+        let mut expired = TokenBuilder::new("sweeper-test".to_owned()).build();
+        expired.issued_at -= 120;
+        expired.expires_at = Some(expired.issued_at + 60);
+        let active = TokenBuilder::new("sweeper-test".to_owned()).build();
+        token_service
+            .store_tokens(&[expired.clone(), active.clone()])
+            .await
+            .unwrap();
+
+        let swept = sweep(&service, &token_service).await.unwrap();
+        assert!(swept.tokens >= 1);
+        let fetch = |id: &str| token_service.db_client.fetch_token_by_id(id.to_owned());
+        assert!(matches!(
+            fetch(&expired.id).await,
+            Err(TokenError::NotFound)
+        ));
+        assert!(fetch(&active.id).await.is_ok());
+
+        token_service.revoke_token(&active).await.unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs the MongoDB and Redis of the .env file"]
+    async fn remove_token_stored_without_expiry() {
+        dotenv::dotenv().ok();
+        let service = Service::create().await;
+        let token_service = TokenService::create().await;
+
+        // A token stored before tokens expired, two lifetimes ago.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let created = now - 2 * DEFAULT_TOKEN_LIFETIME;
+        let mut oid = ObjectId::new().bytes();
+        oid[..4].copy_from_slice(&(created as u32).to_be_bytes());
+        let id = uuid::Uuid::new_v4().to_string();
+        token_service
+            .db_client
+            .database
+            .collection::<Document>("tokens")
+            .insert_one(
+                doc! {
+                    "_id": ObjectId::from_bytes(oid),
+                    "id": &id,
+                    "access_token": generate_token(),
+                    "tx": "sweeper-test",
+                    "expire": 0,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        sweep(&service, &token_service).await.unwrap();
+        assert!(matches!(
+            token_service.db_client.fetch_token_by_id(id).await,
+            Err(TokenError::NotFound)
+        ));
+    }
+}
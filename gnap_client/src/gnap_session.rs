@@ -3,6 +3,7 @@ use model::gnap::GnapOptions;
 pub struct GnapSession {
     pub access_token: Option<String>,
     pub tx_contiune: Option<String>,
+    pub continue_token: Option<String>,
     pub instance_id: Option<String>,
     pub redirect: Option<String>,
     pub nonce: Option<String>,
@@ -14,6 +15,7 @@ impl GnapSession {
         Self {
             access_token: None,
             tx_contiune: None,
+            continue_token: None,
             instance_id: None,
             redirect: None,
             nonce: None,
@@ -25,6 +27,7 @@ impl GnapSession {
         Self {
             access_token: None,
             tx_contiune: None,
+            continue_token: None,
             instance_id: None,
             redirect: None,
             nonce: None,
@@ -0,0 +1,76 @@
+//! HTTP Message Signatures for client instance requests.
+//!
+//! Signs requests to the AS with the client instance key, as required by the
+//! `httpsig` proof method.
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use std::error::Error as StdError;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct HttpSigner {
+    key: PKey<Private>,
+    keyid: String,
+}
+
+impl HttpSigner {
+    /// Load an Ed25519 private key from a PEM file.
+    pub fn from_pem_file(path: &str, keyid: &str) -> Result<Self, Box<dyn StdError>> {
+        let pem = fs::read(path)?;
+        Ok(Self {
+            key: PKey::private_key_from_pem(&pem)?,
+            keyid: keyid.to_owned(),
+        })
+    }
+
+    /// Produce the `Content-Digest`, `Signature-Input` and `Signature` headers
+    /// for a request.
+    pub fn sign(
+        &self,
+        method: &str,
+        target_uri: &str,
+        body: &[u8],
+        authorization: Option<&str>,
+    ) -> Result<Vec<(String, String)>, Box<dyn StdError>> {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let digest = format!("sha-256=:{}:", base64::encode(sha256(body)));
+
+        let mut components = vec![
+            ("@method", method.to_uppercase()),
+            ("@target-uri", target_uri.to_owned()),
+            ("content-digest", digest.clone()),
+        ];
+        if let Some(authorization) = authorization {
+            components.push(("authorization", authorization.to_owned()));
+        }
+
+        let names = components
+            .iter()
+            .map(|(name, _)| format!("\"{}\"", name))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let params = format!(
+            "({});created={};keyid=\"{}\";alg=\"ed25519\"",
+            names, created, self.keyid
+        );
+
+        let mut base = String::new();
+        for (name, value) in components.iter() {
+            base.push_str(&format!("\"{}\": {}\n", name, value));
+        }
+        base.push_str(&format!("\"@signature-params\": {}", params));
+
+        let mut signer = Signer::new_without_digest(&self.key)?;
+        let signature = signer.sign_oneshot_to_vec(base.as_bytes())?;
+
+        Ok(vec![
+            ("Content-Digest".to_owned(), digest),
+            ("Signature-Input".to_owned(), format!("sig1={}", params)),
+            (
+                "Signature".to_owned(),
+                format!("sig1=:{}:", base64::encode(signature)),
+            ),
+        ])
+    }
+}
@@ -1,6 +1,7 @@
 use model::grant::*;
 use uuid::Uuid;
 pub mod gnap_session;
+pub mod httpsig;
 
 fn generate_nonce() -> String {
     Uuid::new_v4().to_simple().to_string()
@@ -28,8 +29,9 @@ pub fn make_request() -> GrantRequest {
         start: vec![InteractStartMode::Redirect],
         finish: Some(InteractFinishRequest {
             method: InteractFinishMethodType::Redirect,
-            uri: "localhost:8000/gnap/auth".to_owned(),
+            uri: "http://localhost:3000/gnap/finish".to_owned(),
             nonce: generate_nonce(),
+            hash_method: None,
         }),
     };
 
@@ -1,19 +1,42 @@
 use dotenv::dotenv;
 use gnap_client::gnap_session::GnapSession;
+use gnap_client::httpsig::HttpSigner;
 use gnap_client::make_request;
 use log::trace;
 use model::gnap::GnapOptions;
 use model::grant::*;
-use model::instances::InstanceResponse;
 use std::error::Error as StdError;
 use std::io;
 
 const GNAP_AS_HOST: &str = "http://localhost:8000";
+const CLIENT_KEY: &str = ".keystore/client_key.pem";
+const CLIENT_KEY_ID: &str = "test_client_1";
 
 fn as_path(part: &str) -> String {
     format!("{}/{}", GNAP_AS_HOST, part)
 }
 
+/// POST a JSON body to the AS, signed with the client instance key.  The
+/// optional access token is sent with `Authorization: GNAP`.
+async fn signed_post(
+    signer: &HttpSigner,
+    target: &str,
+    body: Vec<u8>,
+    access_token: Option<&str>,
+) -> Result<reqwest::Response, Box<dyn StdError>> {
+    let authorization = access_token.map(|token| format!("GNAP {}", token));
+    let mut request = reqwest::Client::new()
+        .post(target)
+        .header("Content-Type", "application/json");
+    if let Some(authorization) = &authorization {
+        request = request.header("Authorization", authorization);
+    }
+    for (name, value) in signer.sign("POST", target, &body, authorization.as_deref())? {
+        request = request.header(name, value);
+    }
+    Ok(request.body(body).send().await?)
+}
+
 async fn get_config() -> Result<GnapOptions, Box<dyn StdError>> {
     let path = as_path(".well-known/gnap-as-rs");
     println!("Using path: {}", &path);
@@ -39,12 +62,12 @@ async fn main() -> Result<(), Box<dyn StdError>> {
     let options = get_config().await?;
 
     let mut gnap_session = GnapSession::new_with_options(options.clone());
+    let signer = HttpSigner::from_pem_file(CLIENT_KEY, CLIENT_KEY_ID)?;
 
     // 2.
     let request = make_request();
-    let session = request.interact.clone().unwrap().finish.unwrap();
-    gnap_session.redirect = Some(session.uri.clone());
-    gnap_session.nonce = Some(session.nonce.clone());
+    let finish = request.interact.clone().unwrap().finish.unwrap();
+    gnap_session.nonce = Some(finish.nonce.clone());
 
     println!("Request: {:#?}", &request);
     trace!(
@@ -52,55 +75,114 @@ async fn main() -> Result<(), Box<dyn StdError>> {
         &options.service_endpoints.grant_request_endpoint
     );
     // 3.
-    let step3: GrantResponse = reqwest::Client::new()
-        .post(options.service_endpoints.grant_request_endpoint)
-        .json(&request)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let step3: GrantResponse = signed_post(
+        &signer,
+        &options.service_endpoints.grant_request_endpoint,
+        serde_json::to_vec(&request)?,
+        None,
+    )
+    .await?
+    .json()
+    .await?;
 
     // server http response
     println!("\nResponse: {:#?}", step3);
 
-    gnap_session.instance_id = Some(step3.instance_id);
-    gnap_session.tx_contiune = Some(step3.interact.unwrap().tx_continue.uri);
+    let tx_continue = step3.tx_continue.unwrap();
+    let interact = step3.interact.unwrap();
+    gnap_session.instance_id = step3.instance_id;
+    gnap_session.tx_contiune = Some(tx_continue.uri);
+    gnap_session.continue_token = tx_continue.access_token.map(|token| token.value);
+    gnap_session.redirect = interact.redirect;
 
     //let (username, password ) = get_user_input().expect("Failed to get user input");
 
     let (username, password) = ("kenneth", "password");
 
-    let secret = base64::encode(format!("{}:{}", username, password));
-
-    let instance = gnap_session.instance_id.clone().unwrap();
-
-    let step4: InstanceResponse = reqwest::Client::new()
-        .get(format!(
-            "http://{}/{}",
-            &gnap_session.redirect.unwrap(),
-            instance
-        ))
-        .header("Content-type", "application/x-www-form-urlencoded")
-        .header("Authorization", "Basic ".to_owned() + &secret)
-        .json(&instance)
+    // The user agent signs in on the interaction page, and approves every
+    // access right it lists.  It is sent back to the finish uri once the
+    // user has approved the grant, so capture the redirects instead of
+    // following them.
+    let redirect = gnap_session.redirect.clone().unwrap();
+    let user_agent = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let login = user_agent
+        .post(format!("{}/login", redirect))
+        .form(&[("username", username), ("password", password)])
+        .send()
+        .await?;
+    let session = login
+        .headers()
+        .get_all(reqwest::header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next())
+        .find(|cookie| cookie.starts_with("gnap_session="))
+        .ok_or("The AS did not start a session")?
+        .to_owned();
+
+    let mut consent = vec![("decision", "approve".to_owned())];
+    for (t, token) in request.access_token.iter().enumerate() {
+        for (a, access) in token.access.iter().enumerate() {
+            match access {
+                AccessRequest::Value {
+                    actions: Some(actions),
+                    ..
+                } if !actions.is_empty() => {
+                    for action in actions {
+                        consent.push(("grant", format!("{}.{}.{}", t, a, action)));
+                    }
+                }
+                _ => consent.push(("grant", format!("{}.{}", t, a))),
+            }
+        }
+    }
+    let step4 = user_agent
+        .post(&redirect)
+        .header(reqwest::header::COOKIE, session)
+        .form(&consent)
         .send()
-        .await?
-        .json()
         .await?;
 
-    println!("Response: {:#?}", step4);
+    let location = step4
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .ok_or("The AS did not redirect back to the client")?
+        .to_str()?;
+    println!("Redirect: {}", location);
+    let callback = reqwest::Url::parse(location)?;
+    let query_value = |name: &str| {
+        callback
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let hash = query_value("hash").ok_or("Missing hash")?;
+    let interact_ref = query_value("interact_ref").ok_or("Missing interact_ref")?;
+
+    // Verify the callback came from the AS that handled the grant request.
+    let expected = finish.hash(
+        &interact.finish.unwrap(),
+        &interact_ref,
+        &options.service_endpoints.grant_request_endpoint,
+    );
+    if hash != expected {
+        return Err("Interaction hash does not match".into());
+    }
 
-    let continue_request =
-        ContinuationRequest::create_with_ref(gnap_session.instance_id.clone().unwrap());
+    let continue_request = ContinuationRequest::create_with_ref(interact_ref);
     let target = gnap_session.tx_contiune.unwrap().to_string();
     println!("{}", target);
-    let step8: GrantResponse = reqwest::Client::new()
-        .post(target)
-        .json(&continue_request)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let step8: GrantResponse = signed_post(
+        &signer,
+        &target,
+        serde_json::to_vec(&continue_request)?,
+        gnap_session.continue_token.as_deref(),
+    )
+    .await?
+    .json()
+    .await?;
 
     println!("Response: {:#?}", step8);
 
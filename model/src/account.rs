@@ -2,9 +2,11 @@
 //!
 //! Account and claims related to identities.
 //!
+use super::transaction::now;
 use super::CachePath;
 use redis::{RedisWrite, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use uuid::Uuid;
 
 /// Snail mail address and verification status
@@ -79,6 +81,11 @@ pub struct Account {
     website: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     zoneinfo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    did: Option<String>,
+    /// When the account was last updated (seconds since the epoch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<u64>,
 }
 
 impl CachePath for Account {
@@ -108,6 +115,8 @@ impl From<AccountRequest> for Account {
             tax_id: ar.tax_id,
             website: ar.website,
             zoneinfo: ar.zoneinfo,
+            did: ar.did,
+            updated_at: Some(now()),
         }
     }
 }
@@ -116,6 +125,99 @@ impl Account {
     pub fn create_id() -> Uuid {
         Uuid::new_v4()
     }
+
+    pub fn account_id(&self) -> Uuid {
+        self.account_id
+    }
+
+    /// The primary email address, or the first one if none is primary.
+    pub fn email(&self) -> Option<&str> {
+        primary(&self.email, |email| email.primary).map(|email| email.address.as_str())
+    }
+
+    /// The primary phone number, or the first one if none is primary.
+    pub fn phone_number(&self) -> Option<&str> {
+        primary(&self.phone, |phone| phone.primary).map(|phone| phone.phone_number.as_str())
+    }
+
+    /// The decentralized identifier of the account holder.
+    pub fn did(&self) -> Option<&str> {
+        self.did.as_deref()
+    }
+
+    pub fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    /// The standard OpenID Connect claims of the account holder.  Only the
+    /// primary email address, phone number and address are claimed.
+    pub fn claims(&self) -> Map<String, Value> {
+        let mut claims = Map::new();
+        let mut claim = |name: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                claims.insert(name.to_owned(), value);
+            }
+        };
+        let text = |value: &Option<String>| value.as_ref().map(|value| json!(value));
+
+        claim("name", Some(json!(self.name)));
+        claim("given_name", Some(json!(self.given_name)));
+        claim("family_name", Some(json!(self.family_name)));
+        claim("middle_name", text(&self.middle_name));
+        claim("nickname", text(&self.nickname));
+        claim("preferred_username", text(&self.preferred_username));
+        claim("profile", text(&self.profile));
+        claim("picture", text(&self.picture));
+        claim("website", text(&self.website));
+        claim("gender", self.gender.as_ref().map(Gender::claim));
+        claim("birthdate", text(&self.birthdate));
+        claim("zoneinfo", text(&self.zoneinfo));
+        claim("locale", text(&self.locale));
+        if let Some(email) = primary(&self.email, |email| email.primary) {
+            claim("email", Some(json!(email.address)));
+            claim("email_verified", Some(json!(email.verified)));
+        }
+        if let Some(phone) = primary(&self.phone, |phone| phone.primary) {
+            claim("phone_number", Some(json!(phone.phone_number)));
+            claim("phone_number_verified", Some(json!(phone.verified)));
+        }
+        if let Some(address) = primary(&self.address, |address| address.primary) {
+            claim(
+                "address",
+                Some(json!({
+                    "formatted": address.formatted,
+                    "street_address": address.street_address,
+                    "locality": address.locality,
+                    "region": address.region,
+                    "postal_code": address.postal_code,
+                    "country": address.country,
+                })),
+            );
+        }
+        claim(
+            "updated_at",
+            self.updated_at.map(|updated_at| json!(updated_at)),
+        );
+        claims
+    }
+}
+
+impl Gender {
+    fn claim(&self) -> Value {
+        match self {
+            Gender::Male => json!("male"),
+            Gender::Female => json!("female"),
+        }
+    }
+}
+
+/// The primary entry, or the first one if none is primary.
+fn primary<T>(entries: &Option<Vec<T>>, is_primary: impl Fn(&T) -> bool) -> Option<&T> {
+    let entries = entries.as_deref()?;
+    entries
+        .iter()
+        .find(|entry| is_primary(entry))
+        .or_else(|| entries.first())
 }
 
 impl ToRedisArgs for &Account {
@@ -161,6 +263,8 @@ pub struct AccountRequest {
     website: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     zoneinfo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    did: Option<String>,
 }
 
 impl AccountRequest {
@@ -183,6 +287,7 @@ impl AccountRequest {
             tax_id: None,
             website: None,
             zoneinfo: None,
+            did: None,
         }
     }
 }
@@ -198,6 +303,43 @@ mod tests {
         assert!(true)
     }
 
+    #[test]
+    fn primary_identifiers() {
+        let mut acct = Account::from(AccountRequest::new("John", "Smith"));
+        assert_eq!(acct.email(), None);
+        assert!(acct.updated_at().is_some());
+
+        acct.email = Some(vec![
+            EmailAddress {
+                address: "john@example.com".to_owned(),
+                verified: true,
+                primary: false,
+            },
+            EmailAddress {
+                address: "smith@example.com".to_owned(),
+                verified: true,
+                primary: true,
+            },
+        ]);
+        acct.phone = Some(vec![PhoneNumber {
+            phone_number: "+47 0000 0000".to_owned(),
+            verified: false,
+            primary: false,
+        }]);
+        assert_eq!(acct.email(), Some("smith@example.com"));
+        assert_eq!(acct.phone_number(), Some("+47 0000 0000"));
+
+        acct.gender = Some(Gender::Female);
+        let claims = acct.claims();
+        assert_eq!(claims["name"], "John Smith");
+        assert_eq!(claims["email"], "smith@example.com");
+        assert_eq!(claims["email_verified"], true);
+        assert_eq!(claims["phone_number_verified"], false);
+        assert_eq!(claims["gender"], "female");
+        assert!(!claims.contains_key("nickname"));
+        assert!(!claims.contains_key("address"));
+    }
+
     #[test]
     fn cache_path() {
         assert_eq!(Account::cache_path(), "gnap:accounts");
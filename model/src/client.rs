@@ -4,7 +4,10 @@
 //! for client/service interaction.
 //!
 use super::CachePath;
+use crate::grant::ClientDisplay;
+use crate::key::ClientKey;
 use crate::oauth::{AcrValueType, ApplicationType, GrantType, ResponseType, SubjectType};
+use crate::tokens::TokenFormat;
 use errors::GnapError;
 use jsonwebtoken::Algorithm;
 use redis::{RedisWrite, ToRedisArgs};
@@ -15,6 +18,17 @@ use uuid::Uuid;
 pub struct GnapClientRequest {
     pub redirect_uris: Vec<String>,
     pub client_name: String,
+    #[serde(default)]
+    pub key: Option<ClientKey>,
+    /// Seconds the access tokens issued to the client are active.
+    #[serde(default)]
+    pub access_token_lifetime: Option<u64>,
+    /// The format of the access tokens issued to the client.
+    #[serde(default)]
+    pub access_token_format: Option<TokenFormat>,
+    /// The algorithm ID tokens issued to the client are signed with.
+    #[serde(default)]
+    pub id_token_signed_response_alg: Option<Algorithm>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -22,6 +36,24 @@ pub struct GnapClient {
     pub client_id: Uuid,
     pub redirect_uris: Vec<String>,
     pub client_name: String,
+    /// The key the client instance proves possession of on every request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    /// Identifies the client software of a client instance registered by value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_id: Option<String>,
+    /// Thumbprint of the key of a client instance registered by value, so a
+    /// later request with the same key reuses the registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_thumbprint: Option<String>,
+    /// Seconds the access tokens issued to the client are active.  The AS
+    /// default applies when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_lifetime: Option<u64>,
+    /// The format of the access tokens issued to the client, unless a
+    /// resource server requires another.  Opaque when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_format: Option<TokenFormat>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub contacts: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -86,6 +118,11 @@ impl GnapClient {
             client_id: GnapClient::create_id(),
             redirect_uris,
             client_name,
+            key: None,
+            class_id: None,
+            key_thumbprint: None,
+            access_token_lifetime: None,
+            access_token_format: None,
             contacts: None,
             application_type: None,
             response_types: None,
@@ -116,6 +153,29 @@ impl GnapClient {
         }
     }
 
+    /// Register a client instance that was presented by value in a grant
+    /// request.
+    pub fn from_instance(
+        key: ClientKey,
+        class_id: Option<String>,
+        display: Option<ClientDisplay>,
+    ) -> Self {
+        let name = display
+            .as_ref()
+            .map(|d| d.name.clone())
+            .or_else(|| class_id.clone())
+            .unwrap_or_default();
+        let mut client = GnapClient::new(Vec::new(), name);
+        client.key_thumbprint = key.thumbprint();
+        client.key = Some(key);
+        client.class_id = class_id;
+        if let Some(display) = display {
+            client.client_uri = display.uri;
+            client.logo_uri = display.logo_uri;
+        }
+        client
+    }
+
     /// Validate a request body against openid-connect-registration-1_0
     pub fn validate_request(&self) -> Result<(), GnapError> {
         Ok(())
@@ -4,8 +4,10 @@
 //! a grant request.
 //!
 use super::GnapID;
+use crate::key::ClientKey;
 use errors::GnapError;
 use log::trace;
+use openssl::hash::{hash, MessageDigest};
 use serde::{Deserialize, Serialize};
 use serde_utils::vec_or_one::deser_one_as_vec;
 use uuid::Uuid;
@@ -15,7 +17,7 @@ use uuid::Uuid;
 /// attributes or behavior to be attached to the access token by the
 /// AS.  This field is OPTIONAL.
 /// Flag values MUST NOT be included more than once.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum AccessTokenFlag {
     // This flag indicates whether the token is a bearer token,
@@ -65,6 +67,82 @@ pub enum AccessRequest {
         data_types: Option<Vec<String>>,
     },
 }
+impl AccessRequest {
+    /// Whether these rights are already included in `granted`.  A reference
+    /// must be granted as is.  For a value, every requested action, location
+    /// and data type must be granted for the same type.  A value without
+    /// actions asks for all of them.
+    pub fn is_covered_by(&self, granted: &[AccessRequest]) -> bool {
+        match self {
+            AccessRequest::Reference(_) => granted.contains(self),
+            AccessRequest::Value {
+                resource_type,
+                actions,
+                locations,
+                data_types,
+            } => {
+                let same_type = granted
+                    .iter()
+                    .filter_map(|access| match access {
+                        AccessRequest::Value {
+                            resource_type: granted_type,
+                            actions,
+                            locations,
+                            data_types,
+                        } if granted_type.eq(resource_type) => {
+                            Some((actions, locations, data_types))
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let granted_actions = same_type
+                    .iter()
+                    .map(|(actions, _, _)| *actions)
+                    .collect::<Vec<_>>();
+                let granted_locations = same_type
+                    .iter()
+                    .map(|(_, locations, _)| *locations)
+                    .collect::<Vec<_>>();
+                let granted_data_types = same_type
+                    .iter()
+                    .map(|(_, _, data_types)| *data_types)
+                    .collect::<Vec<_>>();
+
+                !same_type.is_empty()
+                    && is_within(actions, &granted_actions)
+                    && is_within(locations, &granted_locations)
+                    && is_within(data_types, &granted_data_types)
+            }
+        }
+    }
+
+    /// The resource type of rights requested by value.
+    pub fn resource_type(&self) -> Option<&str> {
+        match self {
+            AccessRequest::Value { resource_type, .. } => Some(resource_type),
+            AccessRequest::Reference(_) => None,
+        }
+    }
+}
+
+/// Whether the requested actions, locations or data types are within the
+/// granted ones.
+/// Without any, a right is not restricted, so it is only covered by a grant
+/// that is not restricted either.
+fn is_within(requested: &Option<Vec<String>>, granted: &[&Option<Vec<String>>]) -> bool {
+    if granted.iter().any(|values| values.is_none()) {
+        return true;
+    }
+    match requested {
+        Some(requested) => requested.iter().all(|value| {
+            granted
+                .iter()
+                .any(|values| values.iter().flatten().any(|v| v.eq(value)))
+        }),
+        None => false,
+    }
+}
+
 /// Access Token portion of a grant request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessTokenRequest {
@@ -99,21 +177,27 @@ impl Default for AccessTokenRequest {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Subject identifier formats, as registered by RFC 9493.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum SubjectFormatType {
-    IssSubject,
     Opaque,
+    IssSub,
+    Email,
+    PhoneNumber,
+    Account,
+    Did,
+    Aliases,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum SubjectAssertionType {
     IdToken,
     SAML2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SubjectRequest {
     pub formats: Option<Vec<SubjectFormatType>>,
     pub assertions: Option<Vec<SubjectAssertionType>>,
@@ -122,7 +206,7 @@ pub struct SubjectRequest {
 // 2.5.1 Start Mode Definitions
 // This specification defines the following interaction start modes as
 // an array of string values under the start key:
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum InteractStartMode {
     // Indicates that the client instance can direct the end-
@@ -145,11 +229,55 @@ pub enum InteractFinishMethodType {
     Redirect,
     Push,
 }
+/// The hash algorithm used for the interaction finish `hash`, named as in
+/// the IANA Named Information Hash Algorithm registry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum HashMethod {
+    #[default]
+    #[serde(rename = "sha-256")]
+    Sha256,
+    #[serde(rename = "sha3-512")]
+    Sha3_512,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InteractFinishRequest {
     pub method: InteractFinishMethodType,
     pub uri: String,
     pub nonce: String,
+    // The hash calculation method used for the interaction
+    //  finish hash.  Defaults to sha-256.  Section 4.2.3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_method: Option<HashMethod>,
+}
+
+impl InteractFinishRequest {
+    /// Calculate the interaction hash (Section 4.2.3) over the client nonce,
+    /// the AS `finish` nonce, the interaction reference and the grant endpoint.
+    pub fn hash(&self, finish: &str, interact_ref: &str, grant_endpoint: &str) -> String {
+        let base = format!(
+            "{}\n{}\n{}\n{}",
+            self.nonce, finish, interact_ref, grant_endpoint
+        );
+        let digest = match self.hash_method.unwrap_or_default() {
+            HashMethod::Sha256 => hash(MessageDigest::sha256(), base.as_bytes()),
+            HashMethod::Sha3_512 => hash(MessageDigest::sha3_512(), base.as_bytes()),
+        }
+        .expect("Failed to calculate interaction hash");
+        base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// The body the AS posts to the client instance for the `push` finish
+/// method.  Section 4.2.2
+///
+/// Both fields are left out when the end-user denied the grant.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InteractFinishPush {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interact_ref: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -158,10 +286,40 @@ pub struct InteractRequest {
     pub finish: Option<InteractFinishRequest>,
 }
 
+/// 2.3.2 Display Information
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientDisplay {
+    /// Display name of the client software.
+    pub name: String,
+    /// User-facing information about the client software, such as a web page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    /// Display image to represent the client software.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+}
+
+/// 2.3 Identifying the Client Instance
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged, rename_all = "lowercase")]
+#[serde(untagged)]
 pub enum GnapClientInstance {
-    Value {},
+    Value {
+        // The public key of the client instance to be used in this request
+        //  as described in Section 7.1 or a reference to a key as described
+        //  in Section 7.1.1.  REQUIRED.
+        key: Box<ClientKey>,
+
+        // An identifier string that the AS can use to identify
+        //  the client software comprising this client instance.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        class_id: Option<String>,
+
+        // An object containing additional information that
+        //  the AS MAY display to the RO during interaction, authorization,
+        //  and management.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        display: Option<ClientDisplay>,
+    },
     Ref(String),
 }
 
@@ -170,7 +328,6 @@ pub struct GrantRequest {
     #[serde(deserialize_with = "deser_one_as_vec")]
     pub access_token: Vec<AccessTokenRequest>,
     pub subject: Option<SubjectRequest>,
-    // We will only support client reference identifiers for now
     pub client: Option<GnapClientInstance>,
     // We will only support user ref ids for now
     pub user: Option<String>,
@@ -201,19 +358,69 @@ impl GnapID for GrantRequest {
 }
 
 impl GrantRequest {
+    /// Whether `requested` asks for any rights beyond what this request has
+    /// been granted.
+    pub fn is_expanded_by(&self, requested: &[AccessTokenRequest]) -> bool {
+        let granted = self
+            .access_token
+            .iter()
+            .flat_map(|token| token.access.clone())
+            .collect::<Vec<AccessRequest>>();
+
+        requested
+            .iter()
+            .flat_map(|token| token.access.iter())
+            .any(|access| !access.is_covered_by(&granted))
+    }
+
+    /// Replace the requested access tokens.
+    pub fn update_access_token(self, access_token: Vec<AccessTokenRequest>) -> Self {
+        Self {
+            access_token,
+            ..self
+        }
+    }
+
     pub fn add_user(self, user: String) -> Self {
         Self {
             user: Some(user),
             ..self
         }
     }
+
+    /// Replace the client instance with a reference to a registered client.
+    pub fn client_ref(self, client_id: &Uuid) -> Self {
+        Self {
+            client: Some(GnapClientInstance::Ref(client_id.to_string())),
+            ..self
+        }
+    }
 }
+/// 5.3 Modifying an Existing Request
+///
+/// The client instance can change the requested access of an ongoing grant.
+/// The client instance itself can not be changed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ContinuationAccessToken {}
+pub struct GrantModificationRequest {
+    #[serde(deserialize_with = "deser_one_as_vec")]
+    pub access_token: Vec<AccessTokenRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interact: Option<InteractRequest>,
+}
+
+/// The continuation access token.  It is always bound to the client instance
+/// key, so only the value is returned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContinuationAccessToken {
+    pub value: String,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The body of a continuation request.  A polling client instance sends no
+/// `interact_ref`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ContinuationRequest {
-    pub interact_ref: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interact_ref: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub interact: Option<InteractRequest>,
 }
@@ -221,7 +428,7 @@ pub struct ContinuationRequest {
 impl ContinuationRequest {
     pub fn create_with_ref(int_ref: String) -> Self {
         Self {
-            interact_ref: int_ref,
+            interact_ref: Some(int_ref),
             interact: None,
         }
     }
@@ -250,7 +457,7 @@ pub struct RequestContinuation {
     //  client instance MUST present the continuation access token in all
     //  requests to the continuation URI as described in Section 7.2.
     #[serde(skip_serializing_if = "Option::is_none")]
-    access_token: Option<ContinuationAccessToken>,
+    pub access_token: Option<ContinuationAccessToken>,
 }
 impl RequestContinuation {
     pub fn as_uri(uri: &str) -> Self {
@@ -260,6 +467,20 @@ impl RequestContinuation {
             access_token: None,
         }
     }
+
+    pub fn with_access_token(self, value: String) -> Self {
+        Self {
+            access_token: Some(ContinuationAccessToken { value }),
+            ..self
+        }
+    }
+
+    pub fn with_wait(self, wait: u32) -> Self {
+        Self {
+            wait: Some(wait),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -314,31 +535,114 @@ pub struct AccessToken {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InteractResponse {
-    #[serde(rename = "continue")]
-    pub tx_continue: RequestContinuation,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub redirect: Option<String>,
+
+    // A nonce used by the client instance to verify the
+    //  callback after interaction is completed.  Only returned when the
+    //  client instance asked for an interaction finish method.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish: Option<String>,
+
+    // The code the end-user enters at the AS's stable code
+    //  entry page.  Only returned for the user_code start mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_code: Option<UserCodeResponse>,
+
+    // The URI the client instance launches as an
+    //  application for the end-user to interact with.  Only returned for
+    //  the app start mode.  Section 3.3.2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+}
+
+/// The `user_code` interaction start mode.  Section 3.3.3
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserCodeResponse {
+    // A unique short code that the end-user can type into
+    //  the code entry page.
+    pub code: String,
+
+    // The stable URI at which the end-user enters the code.
+    pub uri: String,
+}
+
+/// A subject identifier (RFC 9493), tagged with its format.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum SubjectIdentifier {
+    Opaque { id: String },
+    IssSub { iss: String, sub: String },
+    Email { email: String },
+    PhoneNumber { phone_number: String },
+    Account { uri: String },
+    Did { url: String },
+    Aliases { identifiers: Vec<SubjectIdentifier> },
+}
+
+impl SubjectIdentifier {
+    pub fn format(&self) -> SubjectFormatType {
+        match self {
+            Self::Opaque { .. } => SubjectFormatType::Opaque,
+            Self::IssSub { .. } => SubjectFormatType::IssSub,
+            Self::Email { .. } => SubjectFormatType::Email,
+            Self::PhoneNumber { .. } => SubjectFormatType::PhoneNumber,
+            Self::Account { .. } => SubjectFormatType::Account,
+            Self::Did { .. } => SubjectFormatType::Did,
+            Self::Aliases { .. } => SubjectFormatType::Aliases,
+        }
+    }
+}
+
+/// An assertion about the subject, such as an OpenID Connect ID token.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SubjectAssertion {
+    pub format: SubjectAssertionType,
+    pub value: String,
+}
+
+// 3.4 Returning Subject Information
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubjectResponse {
+    // Subject identifiers for the end-user, in the requested formats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_ids: Option<Vec<SubjectIdentifier>>,
+    // Assertions about the end-user, in the requested formats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assertions: Option<Vec<SubjectAssertion>>,
+    // When the end-user's information was last updated, as an RFC 3339
+    //  timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GrantResponse {
-    pub instance_id: String,
+    // An identifier this client instance can use to
+    //  identify itself when making future requests.  Only returned when the
+    //  client instance was presented by value and registered by the AS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    // Indicates that the client instance can continue the
+    //  request by making one or more continuation requests.
+    #[serde(rename = "continue", skip_serializing_if = "Option::is_none")]
+    pub tx_continue: Option<RequestContinuation>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub interact: Option<InteractResponse>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub access_token: Option<Vec<AccessToken>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<SubjectResponse>,
 }
 
 impl GrantResponse {
-    fn create_id() -> String {
-        Uuid::new_v4().to_string()
-    }
-
     pub fn new() -> Self {
         Self {
-            instance_id: Self::create_id(),
+            instance_id: None,
+            tx_continue: None,
             interact: None,
             access_token: None,
+            subject: None,
         }
     }
 }
@@ -358,20 +662,213 @@ mod tests {
         let tx_id = Uuid::new_v4().to_string();
 
         let uri = format!("http://localhost:8000/tx/{}", &tx_id);
-        let rc = RequestContinuation::as_uri(&uri.clone());
+        let rc = RequestContinuation::as_uri(&uri.clone())
+            .with_access_token("80UPRY5NM33OMUKMKSKU".to_owned());
 
         let ic = InteractResponse {
-            tx_continue: rc,
             redirect: Some(uri),
+            finish: None,
+            user_code: None,
+            app: None,
         };
 
         let response = GrantResponse {
-            instance_id: tx_id,
+            instance_id: None,
+            tx_continue: Some(rc),
             interact: Some(ic),
             access_token: None,
+            subject: None,
         };
 
         println!("{}", serde_json::to_string(&response).expect("oops"));
-        assert!(true);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            json["continue"]["access_token"]["value"],
+            "80UPRY5NM33OMUKMKSKU"
+        );
+    }
+
+    #[test]
+    fn interaction_hash() {
+        let finish = InteractFinishRequest {
+            method: InteractFinishMethodType::Redirect,
+            uri: "https://client.example.net/return/123455".to_owned(),
+            nonce: "VJLO6A4CAYLBXHTR0KRO".to_owned(),
+            hash_method: None,
+        };
+        let hash = finish.hash(
+            "MBDOFXG4Y5CVJCX821LH",
+            "4IFWWIKYB2PQ6U56NL1",
+            "https://server.example.com/tx",
+        );
+        assert_eq!(hash, "MfU8I5jo-L_QoQRgotCDUsUbynz2L4ZhUN2ORtnFjrU");
+
+        let finish = InteractFinishRequest {
+            hash_method: Some(HashMethod::Sha3_512),
+            ..finish
+        };
+        let hash = finish.hash(
+            "MBDOFXG4Y5CVJCX821LH",
+            "4IFWWIKYB2PQ6U56NL1",
+            "https://server.example.com/tx",
+        );
+        assert_eq!(
+            hash,
+            "olPgEzZxdInBzO40XxpQEjzlIkMSda9mIWEMMYmBjTEPLWkdkcJY0KYedXCgMx4Db5Usa3gUVkgTk1WgCvLxGQ"
+        );
+    }
+
+    #[test]
+    fn parse_client_by_value() {
+        let request = r#"{
+            "access_token": { "access": ["foo"] },
+            "client": {
+                "key": {
+                    "proof": "httpsig",
+                    "jwk": {
+                        "kty": "OKP",
+                        "crv": "Ed25519",
+                        "kid": "test-client",
+                        "x": "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+                    }
+                },
+                "class_id": "web-server-1234",
+                "display": { "name": "My Client", "uri": "https://example.net/client" }
+            }
+        }"#;
+        let request: GrantRequest = serde_json::from_str(request).unwrap();
+        match &request.client {
+            Some(GnapClientInstance::Value {
+                key,
+                class_id,
+                display,
+            }) => {
+                assert!(key.jwk.is_some());
+                assert_eq!(class_id.as_deref(), Some("web-server-1234"));
+                assert_eq!(display.as_ref().unwrap().name, "My Client");
+            }
+            _ => panic!("client should be parsed by value"),
+        }
+        assert!(request.parse_id().is_err());
+    }
+
+    fn access(resource_type: &str, actions: &[&str]) -> AccessRequest {
+        AccessRequest::Value {
+            resource_type: resource_type.to_owned(),
+            actions: Some(actions.iter().map(|a| a.to_string()).collect()),
+            locations: None,
+            data_types: None,
+        }
+    }
+
+    #[test]
+    fn detect_expanded_access() {
+        let granted = GrantRequest {
+            access_token: vec![AccessTokenRequest {
+                access: vec![
+                    access("bowls", &["read", "create"]),
+                    AccessRequest::Reference("foo".to_owned()),
+                ],
+                label: None,
+                flags: None,
+            }],
+            subject: None,
+            client: None,
+            user: None,
+            interact: None,
+        };
+        let request = |access: Vec<AccessRequest>| {
+            vec![AccessTokenRequest {
+                access,
+                label: None,
+                flags: None,
+            }]
+        };
+
+        assert!(!granted.is_expanded_by(&request(vec![access("bowls", &["read"])])));
+        assert!(!granted.is_expanded_by(&request(vec![AccessRequest::Reference("foo".to_owned())])));
+        assert!(granted.is_expanded_by(&request(vec![access("bowls", &["delete"])])));
+        assert!(granted.is_expanded_by(&request(vec![access("levels", &["read"])])));
+        assert!(granted.is_expanded_by(&request(vec![AccessRequest::Reference("bar".to_owned())])));
+
+        // A right without actions asks for all of them.
+        let all_actions = AccessRequest::Value {
+            resource_type: "bowls".to_owned(),
+            actions: None,
+            locations: None,
+            data_types: None,
+        };
+        assert!(granted.is_expanded_by(&request(vec![all_actions.clone()])));
+        let granted_all = GrantRequest {
+            access_token: request(vec![all_actions.clone()]),
+            ..granted
+        };
+        assert!(!granted_all.is_expanded_by(&request(vec![all_actions])));
+        assert!(!granted_all.is_expanded_by(&request(vec![access("bowls", &["delete"])])));
+    }
+
+    #[test]
+    fn detect_expanded_locations_and_data_types() {
+        let right = |locations: Option<&[&str]>, data_types: Option<&[&str]>| {
+            let values = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+            AccessRequest::Value {
+                resource_type: "bowls".to_owned(),
+                actions: Some(vec!["read".to_owned()]),
+                locations: locations.map(values),
+                data_types: data_types.map(values),
+            }
+        };
+        let grant = |access: AccessRequest| GrantRequest {
+            access_token: vec![AccessTokenRequest {
+                access: vec![access],
+                label: None,
+                flags: None,
+            }],
+            subject: None,
+            client: None,
+            user: None,
+            interact: None,
+        };
+        let request = |access: AccessRequest| {
+            vec![AccessTokenRequest {
+                access: vec![access],
+                label: None,
+                flags: None,
+            }]
+        };
+
+        let granted = grant(right(Some(&["https://rs/bowls/"]), Some(&["email"])));
+        assert!(!granted.is_expanded_by(&request(right(
+            Some(&["https://rs/bowls/"]),
+            Some(&["email"])
+        ))));
+        assert!(granted.is_expanded_by(&request(right(
+            Some(&["https://rs/bowls/", "https://rs/levels/"]),
+            Some(&["email"])
+        ))));
+        assert!(granted.is_expanded_by(&request(right(
+            Some(&["https://rs/bowls/"]),
+            Some(&["email", "openid"])
+        ))));
+        assert!(granted.is_expanded_by(&request(right(None, Some(&["email"])))));
+        assert!(granted.is_expanded_by(&request(right(Some(&["https://rs/bowls/"]), None))));
+
+        // A right granted without restrictions covers a restricted one.
+        let unrestricted = grant(right(None, None));
+        assert!(!unrestricted.is_expanded_by(&request(right(
+            Some(&["https://rs/bowls/"]),
+            Some(&["openid"])
+        ))));
+    }
+
+    #[test]
+    fn parse_client_by_ref() {
+        let id = Uuid::new_v4();
+        let request = format!(
+            r#"{{ "access_token": {{ "access": ["foo"] }}, "client": "{}" }}"#,
+            id
+        );
+        let request: GrantRequest = serde_json::from_str(&request).unwrap();
+        assert_eq!(request.parse_id().unwrap(), id);
     }
 }
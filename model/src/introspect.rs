@@ -4,6 +4,7 @@ use std::str::FromStr;
 use void::Void;
 
 use crate::grant::AccessRequest;
+use crate::key::ClientKey;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct IntrospectRequest {
@@ -20,8 +21,9 @@ pub struct InstrospectResponse {
     pub active: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub access: Option<Vec<AccessRequest>>,
+    /// The key the token is bound to.  Omitted for bearer tokens.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub key: Option<String>,
+    pub key: Option<ClientKey>,
 }
 
 impl FromStr for IntrospectRequest {
@@ -0,0 +1,157 @@
+//! Client instance key models
+//!
+//! Every request a client instance makes to the AS is bound to a key, and
+//! proven with the proofing method registered alongside that key.
+//!
+use jsonwebtoken::jwk::Jwk;
+use openssl::sha::sha256;
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 7.3 Proving Possession of a Key with a Request
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyProofMethod {
+    // HTTP Message Signatures.  Section 7.3.1
+    Httpsig,
+
+    // Mutual TLS.  Section 7.3.2
+    Mtls,
+
+    // Detached JWS.  Section 7.3.3
+    Jwsd,
+
+    // Attached JWS.  Section 7.3.4
+    Jws,
+}
+
+/// 7.1 Key Formats
+///
+/// The public key of a client instance, together with the proofing method the
+/// client instance will use to present it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClientKey {
+    pub proof: KeyProofMethod,
+    /// The public key in JSON Web Key format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwk: Option<Jwk>,
+    /// PEM serialized value of the certificate used to sign the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    /// The certificate thumbprint calculated as per OAuth-MTLS in base64 URL
+    /// encoding.
+    #[serde(rename = "cert#S256", skip_serializing_if = "Option::is_none")]
+    pub cert_s256: Option<String>,
+}
+
+impl ClientKey {
+    /// A stable identifier of the key: the JWK thumbprint of a `jwk`, or the
+    /// `x5t#S256` thumbprint of a certificate.
+    pub fn thumbprint(&self) -> Option<String> {
+        if let Some(jwk) = &self.jwk {
+            return jwk_thumbprint(jwk);
+        }
+        if let Some(cert_s256) = &self.cert_s256 {
+            return Some(cert_s256.clone());
+        }
+        self.cert
+            .as_deref()
+            .and_then(cert_der)
+            .map(|der| b64(&sha256(&der)))
+    }
+}
+
+/// The JWK thumbprint (RFC 7638) of a key: the SHA-256 of its required
+/// members, in lexicographic order and without whitespace.
+pub fn jwk_thumbprint(jwk: &Jwk) -> Option<String> {
+    // The key type parameters hold exactly the required members.
+    let members: BTreeMap<String, serde_json::Value> =
+        serde_json::from_value(serde_json::to_value(&jwk.algorithm).ok()?).ok()?;
+    let members = serde_json::to_string(&members).ok()?;
+    Some(b64(&sha256(members.as_bytes())))
+}
+
+/// DER encoding of a PEM certificate, or of the bare base64 body of a PEM.
+fn cert_der(cert: &str) -> Option<Vec<u8>> {
+    match X509::from_pem(cert.as_bytes()) {
+        Ok(cert) => cert.to_der().ok(),
+        Err(_) => base64::decode(cert.trim()).ok(),
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_jwk_key() {
+        let key = r#"{
+            "proof": "httpsig",
+            "jwk": {
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "kid": "test-client",
+                "x": "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+            }
+        }"#;
+        let key: ClientKey = serde_json::from_str(key).unwrap();
+        assert_eq!(key.proof, KeyProofMethod::Httpsig);
+        assert!(key.jwk.is_some());
+        assert!(key.cert.is_none());
+    }
+
+    #[test]
+    fn parse_cert_thumbprint() {
+        let key =
+            r#"{ "proof": "mtls", "cert#S256": "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2" }"#;
+        let key: ClientKey = serde_json::from_str(key).unwrap();
+        assert_eq!(key.proof, KeyProofMethod::Mtls);
+        assert_eq!(
+            key.cert_s256,
+            Some("bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2".to_owned())
+        );
+    }
+
+    #[test]
+    fn rfc7638_thumbprint() {
+        // The example of RFC 7638 Section 3.1
+        let key = r#"{
+            "proof": "jwsd",
+            "jwk": {
+                "kty": "RSA",
+                "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+                "e": "AQAB",
+                "alg": "RS256",
+                "kid": "2011-04-29"
+            }
+        }"#;
+        let key: ClientKey = serde_json::from_str(key).unwrap();
+        assert_eq!(
+            key.thumbprint(),
+            Some("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs".to_owned())
+        );
+    }
+
+    #[test]
+    fn thumbprint_ignores_optional_members() {
+        let key = |kid: &str| -> ClientKey {
+            serde_json::from_value(serde_json::json!({
+                "proof": "httpsig",
+                "jwk": {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "kid": kid,
+                    "x": "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+                }
+            }))
+            .unwrap()
+        };
+        assert!(key("a").thumbprint().is_some());
+        assert_eq!(key("a").thumbprint(), key("b").thumbprint());
+    }
+}
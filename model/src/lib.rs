@@ -1,3 +1,4 @@
+use bson::DateTime;
 use errors::GnapError;
 use uuid::Uuid;
 pub mod account;
@@ -7,9 +8,12 @@ pub mod gnap;
 pub mod grant;
 pub mod instances;
 pub mod introspect;
+pub mod key;
 pub mod oauth;
 pub mod oidc;
+pub mod paseto;
 pub mod resource;
+pub mod signing;
 pub mod tokens;
 pub mod transaction;
 pub mod users;
@@ -26,6 +30,13 @@ pub trait GnapID {
     fn parse_id(&self) -> Result<Uuid, GnapError>;
 }
 
+/// Format seconds since the epoch as an RFC 3339 timestamp.
+pub fn rfc3339(secs: u64) -> Result<String, GnapError> {
+    DateTime::from_millis(secs as i64 * 1000)
+        .try_to_rfc3339_string()
+        .map_err(|_| GnapError::BadData)
+}
+
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
@@ -35,4 +46,9 @@ mod tests {
         println!("{}", my_uuid);
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn format_rfc3339() {
+        assert_eq!(super::rfc3339(0).unwrap(), "1970-01-01T00:00:00Z");
+    }
 }
@@ -1,6 +1,113 @@
-//! This follows openid-connect-discovery-1_0
+//! This follows openid-connect-discovery-1_0, and openid-connect-core-1_0 for
+//! ID tokens.
 //!
+use crate::transaction::now;
+use errors::GnapError;
+use jsonwebtoken::Algorithm;
+use log::debug;
 use serde::{self, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Seconds an ID token is valid for.
+pub const ID_TOKEN_LIFETIME: u64 = 300;
+/// The algorithm ID tokens are signed with, as the AS signs with P-256 keys.
+pub const ID_TOKEN_ALGORITHM: Algorithm = Algorithm::ES256;
+
+/// The type of the access right to the userinfo endpoint.
+pub const USERINFO_ACCESS: &str = "openid";
+
+/// The claims requested by each OpenID Connect scope (OIDC Core 5.4).
+const SCOPE_CLAIMS: [(&str, &[&str]); 4] = [
+    (
+        "profile",
+        &[
+            "name",
+            "family_name",
+            "given_name",
+            "middle_name",
+            "nickname",
+            "preferred_username",
+            "profile",
+            "picture",
+            "website",
+            "gender",
+            "birthdate",
+            "zoneinfo",
+            "locale",
+            "updated_at",
+        ],
+    ),
+    ("email", &["email", "email_verified"]),
+    ("address", &["address"]),
+    ("phone", &["phone_number", "phone_number_verified"]),
+];
+
+/// Keep the claims named by `data_types`, either by claim name or by scope.
+/// Without data types, every claim is kept.
+pub fn filter_claims(
+    claims: Map<String, Value>,
+    data_types: Option<&[String]>,
+) -> Map<String, Value> {
+    let data_types = match data_types {
+        Some(data_types) => data_types,
+        None => return claims,
+    };
+    let in_scope = |claim: &str, data_type: &str| {
+        SCOPE_CLAIMS
+            .iter()
+            .any(|(scope, claims)| *scope == data_type && claims.contains(&claim))
+    };
+    claims
+        .into_iter()
+        .filter(|(claim, _)| {
+            data_types
+                .iter()
+                .any(|data_type| data_type == claim || in_scope(claim, data_type))
+        })
+        .collect()
+}
+
+/// The algorithm to sign the ID tokens of a client with, given the one it
+/// registered.  Other algorithms than ES256 are not supported.
+pub fn id_token_algorithm(registered: Option<Algorithm>) -> Result<Algorithm, GnapError> {
+    match registered {
+        None | Some(ID_TOKEN_ALGORITHM) => Ok(ID_TOKEN_ALGORITHM),
+        Some(alg) => {
+            debug!("ID tokens can't be signed with {:?}", alg);
+            Err(GnapError::BadData)
+        }
+    }
+}
+
+/// The claims of an OpenID Connect ID token.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    /// The client the token is issued to.
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// Claims about the end-user, such as `name` and `email`.
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+impl IdTokenClaims {
+    /// An ID token for `sub`, issued now to the client `aud`, with the given
+    /// claims about the end-user.
+    pub fn new(iss: &str, sub: &str, aud: &str, claims: Map<String, Value>) -> Self {
+        let iat = now();
+        Self {
+            iss: iss.to_owned(),
+            sub: sub.to_owned(),
+            aud: aud.to_owned(),
+            iat,
+            exp: iat + ID_TOKEN_LIFETIME,
+            claims,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OpenIDConfiguration {
@@ -118,3 +225,55 @@ impl OpenIDConfiguration {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{Account, AccountRequest};
+    use crate::signing::SigningKey;
+    use jsonwebtoken::{decode, Validation};
+
+    #[test]
+    fn signed_id_token() {
+        let account = Account::from(AccountRequest::new("Kenneth", "Fossen"));
+        let claims = filter_claims(account.claims(), Some(&["given_name".to_owned()]));
+        let claims = IdTokenClaims::new("http://localhost:8000", "user", "client", claims);
+        assert_eq!(claims.exp - claims.iat, ID_TOKEN_LIFETIME);
+
+        let key = SigningKey::generate().unwrap();
+        let token = key.sign("JWT", &claims).unwrap();
+        let mut validation = Validation::new(key.algorithm());
+        validation.set_audience(&["client"]);
+        let decoded = decode::<Value>(&token, &key.decoding_key().unwrap(), &validation).unwrap();
+        assert_eq!(decoded.header.kid, Some(key.kid));
+        assert_eq!(decoded.claims["sub"], "user");
+        assert_eq!(decoded.claims["given_name"], "Kenneth");
+        assert!(decoded.claims.get("name").is_none());
+    }
+
+    #[test]
+    fn supported_id_token_algorithms() {
+        assert_eq!(id_token_algorithm(None).unwrap(), Algorithm::ES256);
+        assert_eq!(
+            id_token_algorithm(Some(Algorithm::ES256)).unwrap(),
+            Algorithm::ES256
+        );
+        assert!(id_token_algorithm(Some(Algorithm::RS256)).is_err());
+    }
+
+    #[test]
+    fn filter_by_data_types() {
+        let account = Account::from(AccountRequest::new("Kenneth", "Fossen"));
+        let claims = account.claims();
+        assert_eq!(filter_claims(claims.clone(), None), claims);
+
+        let data_types = vec!["given_name".to_owned(), "email".to_owned()];
+        let filtered = filter_claims(claims.clone(), Some(&data_types));
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered["given_name"], "Kenneth");
+
+        let filtered = filter_claims(claims, Some(&["profile".to_owned()]));
+        assert!(filtered.contains_key("family_name"));
+        assert!(filtered.contains_key("updated_at"));
+    }
+}
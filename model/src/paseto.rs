@@ -0,0 +1,239 @@
+//! PASETO v4.public tokens
+//!
+//! A v4.public token is a message signed with Ed25519 over its
+//! pre-authentication encoding (PAE).  Unlike a JWT, the version fixes the
+//! algorithm, so a verifier can't be tricked into using another one.  The
+//! footer carries the id of the signing key, as a JSON object.
+//!
+//! See <https://github.com/paseto-standard/paseto-spec/blob/master/docs/01-Protocol-Versions/Version4.md>
+use errors::GnapError;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse,
+};
+use log::{debug, error};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+
+/// The header of every v4.public token.
+pub const HEADER: &str = "v4.public.";
+/// Length of an Ed25519 signature in bytes.
+const SIGNATURE_LENGTH: usize = 64;
+
+/// The footer of the tokens the AS signs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub kid: String,
+}
+
+/// An Ed25519 key the AS signs PASETO tokens with.
+#[derive(Clone)]
+pub struct PasetoKey {
+    pub kid: String,
+    /// The public key, as published to resource servers.
+    pub jwk: Jwk,
+    key: PKey<Private>,
+}
+
+impl PasetoKey {
+    /// Generate a new Ed25519 key.
+    pub fn generate() -> Result<Self, GnapError> {
+        Self::from_pkey(PKey::generate_ed25519().map_err(key_error)?)
+    }
+
+    /// Load a PEM encoded Ed25519 private key.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, GnapError> {
+        Self::from_pkey(PKey::private_key_from_pem(pem).map_err(key_error)?)
+    }
+
+    fn from_pkey(key: PKey<Private>) -> Result<Self, GnapError> {
+        if key.id() != Id::ED25519 {
+            error!("PASETO v4 keys must be Ed25519 keys");
+            return Err(GnapError::GeneralError);
+        }
+        let x = b64(&key.raw_public_key().map_err(key_error)?);
+        let members = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x);
+        let kid = b64(&sha256(members.as_bytes()));
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x,
+            }),
+        };
+        Ok(Self { kid, jwk, key })
+    }
+
+    /// The private key in PKCS#8 PEM form.
+    pub fn to_pem(&self) -> Result<Vec<u8>, GnapError> {
+        self.key.private_key_to_pem_pkcs8().map_err(key_error)
+    }
+
+    /// The raw public key.
+    pub fn public_key(&self) -> Result<Vec<u8>, GnapError> {
+        self.key.raw_public_key().map_err(key_error)
+    }
+
+    /// Sign `claims` as a v4.public token, with the key id in the footer.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, GnapError> {
+        let message = serde_json::to_vec(claims)?;
+        let footer = serde_json::to_vec(&Footer {
+            kid: self.kid.clone(),
+        })?;
+        let pae = pae(&[HEADER.as_bytes(), &message, &footer, b""]);
+        let signature = Signer::new_without_digest(&self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(&pae))
+            .map_err(key_error)?;
+
+        let mut payload = message;
+        payload.extend_from_slice(&signature);
+        Ok(format!("{}{}.{}", HEADER, b64(&payload), b64(&footer)))
+    }
+}
+
+/// The footer of a token.  It is not authenticated until the token is
+/// verified, but tells the verifier which key to verify with.
+pub fn footer(token: &str) -> Option<Footer> {
+    let (_, footer) = split(token).ok()?;
+    serde_json::from_slice(&footer?).ok()
+}
+
+/// Verify a v4.public token with a raw Ed25519 public key, and return its
+/// claims.
+pub fn verify(token: &str, public_key: &[u8]) -> Result<Value, GnapError> {
+    let (payload, footer) = split(token)?;
+    if payload.len() < SIGNATURE_LENGTH {
+        debug!("PASETO payload is too short");
+        return Err(GnapError::InvalidToken);
+    }
+    let (message, signature) = payload.split_at(payload.len() - SIGNATURE_LENGTH);
+    let pae = pae(&[
+        HEADER.as_bytes(),
+        message,
+        footer.as_deref().unwrap_or_default(),
+        b"",
+    ]);
+
+    let key = PKey::public_key_from_raw_bytes(public_key, Id::ED25519).map_err(key_error)?;
+    let verified = Verifier::new_without_digest(&key)
+        .and_then(|mut verifier| verifier.verify_oneshot(signature, &pae))
+        .unwrap_or(false);
+    if !verified {
+        debug!("PASETO signature verification failed");
+        return Err(GnapError::InvalidToken);
+    }
+    serde_json::from_slice(message).map_err(|_| GnapError::InvalidToken)
+}
+
+/// Split a token in its decoded payload and footer.
+fn split(token: &str) -> Result<(Vec<u8>, Option<Vec<u8>>), GnapError> {
+    let body = token.strip_prefix(HEADER).ok_or(GnapError::InvalidToken)?;
+    let mut parts = body.split('.');
+    let payload = parts.next().map(unb64).ok_or(GnapError::InvalidToken)??;
+    let footer = parts.next().map(unb64).transpose()?;
+    if parts.next().is_some() {
+        return Err(GnapError::InvalidToken);
+    }
+    Ok((payload, footer))
+}
+
+/// Pre-authentication encoding: the number of pieces, then each piece
+/// prefixed with its length, all as 64 bit little endian integers.
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut out = le64(pieces.len());
+    for piece in pieces {
+        out.extend_from_slice(&le64(piece.len()));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+/// A 64 bit little endian integer, with the most significant bit cleared.
+fn le64(n: usize) -> Vec<u8> {
+    ((n as u64) & (u64::MAX >> 1)).to_le_bytes().to_vec()
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(data: &str) -> Result<Vec<u8>, GnapError> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).map_err(|_| GnapError::InvalidToken)
+}
+
+fn key_error<E: Debug>(err: E) -> GnapError {
+    error!("PASETO key error: {:?}", err);
+    GnapError::GeneralError
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pre_authentication_encoding() {
+        // Test vectors from the PASETO specification.
+        assert_eq!(pae(&[]), b"\x00\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(
+            pae(&[b""]),
+            b"\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"
+        );
+        assert_eq!(
+            pae(&[b"test"]),
+            b"\x01\x00\x00\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00test"
+        );
+    }
+
+    #[test]
+    fn verify_specification_vector() {
+        // Test vector 4-S-2 of the PASETO specification.
+        let public_key = hex("1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2");
+        let token = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9v3Jt8mx_TdM2ceTGoqwrh4yDFn0XsHvvV_D0DtwQxVrJEBMl0F2caAdgnpKlt4p7xBnx1HcO-SPo8FPp214HDw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9";
+
+        let claims = verify(token, &public_key).unwrap();
+        assert_eq!(claims["data"], "this is a signed message");
+        assert_eq!(
+            footer(token).unwrap().kid,
+            "zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN"
+        );
+
+        let tampered = token.replacen("eyJkYXRh", "eyJkYXRi", 1);
+        assert!(verify(&tampered, &public_key).is_err());
+        assert!(verify(&token.replace("v4.public.", "v4.local."), &public_key).is_err());
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = PasetoKey::generate().unwrap();
+        let token = key.sign(&json!({ "sub": "kenneth" })).unwrap();
+        assert!(token.starts_with(HEADER));
+        assert_eq!(footer(&token).unwrap().kid, key.kid);
+
+        let claims = verify(&token, &key.public_key().unwrap()).unwrap();
+        assert_eq!(claims["sub"], "kenneth");
+
+        let other = PasetoKey::generate().unwrap();
+        assert_ne!(other.kid, key.kid);
+        assert!(verify(&token, &other.public_key().unwrap()).is_err());
+
+        let pem = key.key.private_key_to_pem_pkcs8().unwrap();
+        assert_eq!(PasetoKey::from_pem(&pem).unwrap().kid, key.kid);
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
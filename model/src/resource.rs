@@ -14,14 +14,22 @@ use uuid::Uuid;
 use void::Void;
 
 use crate::grant::AccessRequest;
+use crate::key::ClientKey;
+use crate::tokens::TokenFormat;
 
 #[derive(Serialize, Deserialize)]
 pub struct GnapRegisterResourceServer {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_server_name: Option<String>,
     pub resource_server_key: String,
+    /// The key the RS proves possession of when calling the AS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_set: Option<Vec<AccessRequest>>,
+    /// The format of the access tokens for the resource set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_format: Option<TokenFormat>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,8 +38,14 @@ pub struct GnapResourceServer {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_server_name: Option<String>,
     pub resource_server_key: String,
+    /// The key the RS proves possession of when calling the AS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_set: Option<Vec<AccessRequest>>,
+    /// The format of the access tokens for the resource set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_format: Option<TokenFormat>,
 }
 
 impl GnapResourceServer {
@@ -42,7 +56,9 @@ impl GnapResourceServer {
             resource_server: id,
             resource_server_name: register.resource_server_name,
             resource_server_key: register.resource_server_key,
+            key: register.key,
             resource_set: register.resource_set,
+            token_format: register.token_format,
         }
     }
 }
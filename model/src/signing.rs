@@ -0,0 +1,375 @@
+//! Signing keys of the AS
+//!
+//! The AS signs self-contained access tokens, so a resource server can verify
+//! them without calling the AS.  JWT keys are P-256 keys used with ES256, and
+//! PASETO keys are Ed25519 keys.  Keys are identified by their JWK thumbprint
+//! (RFC 7638).
+//!
+//! The AS keeps its keys in a key store.  A key is generated as pending, is
+//! published before it is activated, signs while it is active, and is still
+//! published for a while after it is retired, so tokens signed with it can
+//! be verified until they expire.
+//!
+use crate::paseto::PasetoKey;
+use crate::transaction::now;
+use errors::GnapError;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use log::error;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// Length of a P-256 coordinate in bytes.
+const COORDINATE_LENGTH: i32 = 32;
+
+/// Seconds a key signs before it is rotated, unless configured otherwise.
+pub const DEFAULT_KEY_ROTATION: u64 = 30 * 24 * 3600;
+/// Seconds a retired key is still published, unless configured otherwise.
+pub const DEFAULT_KEY_RETENTION: u64 = 7 * 24 * 3600;
+
+/// A private key the AS signs JWTs with, and its public JWK.
+#[derive(Clone)]
+pub struct SigningKey {
+    pub kid: String,
+    /// The public key, as published to resource servers.
+    pub jwk: Jwk,
+    encoding: EncodingKey,
+    pem: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Generate a new P-256 key.
+    pub fn generate() -> Result<Self, GnapError> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(key_error)?;
+        let key = EcKey::generate(&group).map_err(key_error)?;
+        Self::from_ec_key(key)
+    }
+
+    /// Load a PEM encoded P-256 private key, in either SEC1 or PKCS#8 form.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, GnapError> {
+        let key = PKey::private_key_from_pem(pem)
+            .and_then(|pkey| pkey.ec_key())
+            .map_err(key_error)?;
+        Self::from_ec_key(key)
+    }
+
+    fn from_ec_key(key: EcKey<Private>) -> Result<Self, GnapError> {
+        if key.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+            error!("Signing keys must be P-256 keys");
+            return Err(GnapError::GeneralError);
+        }
+        let mut ctx = BigNumContext::new().map_err(key_error)?;
+        let mut x = BigNum::new().map_err(key_error)?;
+        let mut y = BigNum::new().map_err(key_error)?;
+        key.public_key()
+            .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)
+            .map_err(key_error)?;
+        let x = b64(&x.to_vec_padded(COORDINATE_LENGTH).map_err(key_error)?);
+        let y = b64(&y.to_vec_padded(COORDINATE_LENGTH).map_err(key_error)?);
+        let kid = thumbprint(&x, &y);
+
+        let pem = PKey::from_ec_key(key)
+            .and_then(|pkey| pkey.private_key_to_pem_pkcs8())
+            .map_err(key_error)?;
+        let encoding = EncodingKey::from_ec_pem(&pem).map_err(key_error)?;
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(Algorithm::ES256),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x,
+                y,
+            }),
+        };
+        Ok(Self {
+            kid,
+            jwk,
+            encoding,
+            pem,
+        })
+    }
+
+    /// The private key in PKCS#8 PEM form.
+    pub fn to_pem(&self) -> &[u8] {
+        &self.pem
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        Algorithm::ES256
+    }
+
+    /// Sign `claims` as a JWT of type `typ`, with the key id in the header.
+    pub fn sign<T: Serialize>(&self, typ: &str, claims: &T) -> Result<String, GnapError> {
+        let mut header = Header::new(self.algorithm());
+        header.typ = Some(typ.to_owned());
+        header.kid = Some(self.kid.clone());
+        jsonwebtoken::encode(&header, claims, &self.encoding).map_err(key_error)
+    }
+
+    /// The key to verify signatures with.
+    pub fn decoding_key(&self) -> Result<DecodingKey, GnapError> {
+        DecodingKey::from_jwk(&self.jwk).map_err(key_error)
+    }
+}
+
+/// What a key signs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyPurpose {
+    Jwt,
+    Paseto,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStatus {
+    /// Published, but not yet signing.
+    Pending,
+    /// Signing, and published.
+    Active,
+    /// No longer signing.  Published until the retention period ends.
+    Retired,
+}
+
+/// A key as kept in the key store.  Times are in seconds since the epoch.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredKey {
+    pub kid: String,
+    pub purpose: KeyPurpose,
+    pub status: KeyStatus,
+    /// The private key in PEM form.  It is not encrypted.
+    pub pem: String,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activated_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retired_at: Option<u64>,
+}
+
+impl StoredKey {
+    /// Generate a new pending key.
+    pub fn generate(purpose: KeyPurpose) -> Result<Self, GnapError> {
+        let pem = match purpose {
+            KeyPurpose::Jwt => SigningKey::generate()?.to_pem().to_vec(),
+            KeyPurpose::Paseto => PasetoKey::generate()?.to_pem()?,
+        };
+        Self::from_pem(purpose, &pem)
+    }
+
+    /// A pending key from a PEM encoded private key.
+    pub fn from_pem(purpose: KeyPurpose, pem: &[u8]) -> Result<Self, GnapError> {
+        let kid = match purpose {
+            KeyPurpose::Jwt => SigningKey::from_pem(pem)?.kid,
+            KeyPurpose::Paseto => PasetoKey::from_pem(pem)?.kid,
+        };
+        Ok(Self {
+            kid,
+            purpose,
+            status: KeyStatus::Pending,
+            pem: String::from_utf8(pem.to_vec()).map_err(key_error)?,
+            created_at: now(),
+            activated_at: None,
+            retired_at: None,
+        })
+    }
+
+    pub fn activate(self) -> Self {
+        Self {
+            status: KeyStatus::Active,
+            activated_at: Some(now()),
+            ..self
+        }
+    }
+
+    pub fn retire(self) -> Self {
+        Self {
+            status: KeyStatus::Retired,
+            retired_at: Some(now()),
+            ..self
+        }
+    }
+
+    pub fn signing_key(&self) -> Result<SigningKey, GnapError> {
+        SigningKey::from_pem(self.pem.as_bytes())
+    }
+
+    pub fn paseto_key(&self) -> Result<PasetoKey, GnapError> {
+        PasetoKey::from_pem(self.pem.as_bytes())
+    }
+
+    /// The public key.
+    pub fn jwk(&self) -> Result<Jwk, GnapError> {
+        match self.purpose {
+            KeyPurpose::Jwt => Ok(self.signing_key()?.jwk),
+            KeyPurpose::Paseto => Ok(self.paseto_key()?.jwk),
+        }
+    }
+}
+
+/// When keys are rotated, and how long retired keys are published.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRotationPolicy {
+    /// Seconds a key is active before it is replaced.
+    pub rotate_after: u64,
+    /// Seconds a retired key is still published.  This should be longer than
+    /// the lifetime of the tokens it signed.
+    pub retain_for: u64,
+}
+
+impl Default for KeyRotationPolicy {
+    fn default() -> Self {
+        Self {
+            rotate_after: DEFAULT_KEY_ROTATION,
+            retain_for: DEFAULT_KEY_RETENTION,
+        }
+    }
+}
+
+impl KeyRotationPolicy {
+    /// Parse the configured periods in seconds.  Malformed values are
+    /// ignored.
+    pub fn parse(rotate_after: Option<&str>, retain_for: Option<&str>) -> Self {
+        let parse = |secs: Option<&str>, default| {
+            secs.and_then(|secs| secs.trim().parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            rotate_after: parse(rotate_after, DEFAULT_KEY_ROTATION),
+            retain_for: parse(retain_for, DEFAULT_KEY_RETENTION),
+        }
+    }
+
+    /// Whether an active key should be replaced.
+    pub fn is_due(&self, key: &StoredKey) -> bool {
+        key.status == KeyStatus::Active
+            && key
+                .activated_at
+                .map(|at| at + self.rotate_after <= now())
+                .unwrap_or(true)
+    }
+
+    /// Whether a key is published.
+    pub fn is_published(&self, key: &StoredKey) -> bool {
+        match (key.status, key.retired_at) {
+            (KeyStatus::Retired, Some(at)) => at + self.retain_for > now(),
+            (KeyStatus::Retired, None) => false,
+            _ => true,
+        }
+    }
+}
+
+/// The JWK thumbprint of a P-256 public key.
+fn thumbprint(x: &str, y: &str) -> String {
+    let members = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+    b64(&sha256(members.as_bytes()))
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn key_error<E: Debug>(err: E) -> GnapError {
+    error!("Signing key error: {:?}", err);
+    GnapError::GeneralError
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::Validation;
+
+    #[derive(Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: u64,
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::generate().unwrap();
+        let claims = Claims {
+            sub: "kenneth".to_owned(),
+            exp: 4_000_000_000,
+        };
+        let jwt = key.sign("at+jwt", &claims).unwrap();
+
+        let header = jsonwebtoken::decode_header(&jwt).unwrap();
+        assert_eq!(header.kid, Some(key.kid.clone()));
+        assert_eq!(header.typ.as_deref(), Some("at+jwt"));
+
+        let data = jsonwebtoken::decode::<Claims>(
+            &jwt,
+            &key.decoding_key().unwrap(),
+            &Validation::new(Algorithm::ES256),
+        )
+        .unwrap();
+        assert_eq!(data.claims.sub, "kenneth");
+
+        let other = SigningKey::generate().unwrap();
+        assert_ne!(other.kid, key.kid);
+        assert!(jsonwebtoken::decode::<Claims>(
+            &jwt,
+            &other.decoding_key().unwrap(),
+            &Validation::new(Algorithm::ES256),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn load_pem_key() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = EcKey::generate(&group).unwrap();
+        let pem = ec.private_key_to_pem().unwrap();
+
+        let key = SigningKey::from_pem(&pem).unwrap();
+        let again = SigningKey::from_pem(&pem).unwrap();
+        assert_eq!(key.kid, again.kid);
+        assert_eq!(key.jwk, again.jwk);
+
+        let rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        assert!(SigningKey::from_pem(&rsa.private_key_to_pem_pkcs8().unwrap()).is_err());
+        assert_eq!(SigningKey::from_pem(key.to_pem()).unwrap().kid, key.kid);
+    }
+
+    #[test]
+    fn key_lifecycle() {
+        let policy = KeyRotationPolicy::parse(Some("60"), Some("bogus"));
+        assert_eq!(policy.rotate_after, 60);
+        assert_eq!(policy.retain_for, DEFAULT_KEY_RETENTION);
+
+        let key = StoredKey::generate(KeyPurpose::Paseto).unwrap();
+        assert_eq!(key.status, KeyStatus::Pending);
+        assert_eq!(key.paseto_key().unwrap().kid, key.kid);
+        assert!(key.signing_key().is_err());
+        assert!(policy.is_published(&key));
+        assert!(!policy.is_due(&key));
+
+        let mut key = key.activate();
+        assert!(!policy.is_due(&key));
+        key.activated_at = Some(now() - 60);
+        assert!(policy.is_due(&key));
+
+        let mut key = key.retire();
+        assert!(!policy.is_due(&key));
+        assert!(policy.is_published(&key));
+        key.retired_at = Some(now() - DEFAULT_KEY_RETENTION);
+        assert!(!policy.is_published(&key));
+
+        let jwt = StoredKey::generate(KeyPurpose::Jwt).unwrap();
+        assert_eq!(jwt.jwk().unwrap().common.key_id, Some(jwt.kid));
+    }
+}
@@ -1,16 +1,60 @@
 use super::CachePath;
+use crate::grant::{AccessRequest, AccessTokenFlag};
+use crate::key::{jwk_thumbprint, ClientKey};
+use crate::rfc3339;
+use crate::transaction::now;
+use errors::GnapError;
+use jsonwebtoken::jwk::Jwk;
 use rand::Rng;
 use redis::{RedisWrite, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Seconds an access token is active, unless configured otherwise.
+pub const DEFAULT_TOKEN_LIFETIME: u64 = 3600;
+
+/// The format of an access token value.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenFormat {
+    /// A random string, only meaningful to the AS.  Resource servers
+    /// introspect it.
+    #[default]
+    Opaque,
+    /// A JWT signed by the AS, which resource servers validate offline.
+    Jwt,
+    /// A PASETO v4.public token signed by the AS, which resource servers
+    /// validate offline.
+    Paseto,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Token {
     pub id: String,
     pub access_token: Option<String>,
     pub tx: Option<String>,
     pub label: Option<String>,
-    pub expire: Option<u32>,
+    /// When the token was issued, in seconds since the epoch.
+    #[serde(default)]
+    pub issued_at: u64,
+    /// When the token stops being active.  A token without an expiry does
+    /// not expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The key the token is bound to.  Bearer tokens have no key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    /// The rights granted to the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Vec<AccessRequest>>,
+    /// The flags the token was issued with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<AccessTokenFlag>>,
+    /// The format of the token value.
+    #[serde(default)]
+    pub format: TokenFormat,
 }
 
 #[derive(Default)]
@@ -19,7 +63,11 @@ pub struct TokenBuilder {
     pub access_token: Option<String>,
     pub tx: Option<String>,
     pub label: Option<String>,
-    pub expire: Option<u32>,
+    pub lifetime: Option<u64>,
+    pub key: Option<ClientKey>,
+    pub access: Option<Vec<AccessRequest>>,
+    pub flags: Option<Vec<AccessTokenFlag>>,
+    pub format: TokenFormat,
 }
 
 impl Token {
@@ -29,12 +77,18 @@ impl Token {
     pub fn create(tx: String) -> Self {
         let id = Uuid::new_v4().to_string();
         let access_token = generate_token();
+        let issued_at = now();
         Self {
             id,
             access_token: Some(access_token),
             tx: Some(tx),
             label: None,
-            expire: Some(0),
+            issued_at,
+            expires_at: Some(issued_at + DEFAULT_TOKEN_LIFETIME),
+            key: None,
+            access: None,
+            flags: None,
+            format: TokenFormat::Opaque,
         }
     }
     pub fn from_string(s: String) -> Self {
@@ -43,7 +97,49 @@ impl Token {
             access_token: None,
             tx: None,
             label: None,
-            expire: None,
+            issued_at: 0,
+            expires_at: None,
+            key: None,
+            access: None,
+            flags: None,
+            format: TokenFormat::Opaque,
+        }
+    }
+
+    /// A durable token keeps working after it has been rotated.
+    pub fn is_durable(&self) -> bool {
+        self.flags
+            .as_ref()
+            .map(|flags| flags.contains(&AccessTokenFlag::Durable))
+            .unwrap_or(false)
+    }
+
+    /// Seconds the token was issued for, if it expires.
+    pub fn lifetime(&self) -> Option<u64> {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_sub(self.issued_at))
+    }
+
+    /// Seconds until the token expires.  Zero once it has expired.
+    pub fn expires_in(&self) -> Option<u64> {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_sub(now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_in() == Some(0)
+    }
+
+    /// A new token with the same rights, under a new id and value.  The new
+    /// token is issued now, for the lifetime of the old one.
+    pub fn rotate(&self) -> Self {
+        let issued_at = now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            access_token: Some(generate_token()),
+            issued_at,
+            expires_at: self.lifetime().map(|lifetime| issued_at + lifetime),
+            ..self.clone()
         }
     }
 }
@@ -56,7 +152,11 @@ impl TokenBuilder {
             access_token: None,
             tx: Some(tx),
             label: None,
-            expire: Some(0),
+            lifetime: Some(DEFAULT_TOKEN_LIFETIME),
+            key: None,
+            access: None,
+            flags: None,
+            format: TokenFormat::Opaque,
         }
     }
 
@@ -65,23 +165,172 @@ impl TokenBuilder {
         self
     }
 
-    pub fn expire(mut self, expire: u32) -> TokenBuilder {
-        self.expire = Some(expire);
+    /// Seconds the token is active.  `None` issues a token that does not
+    /// expire.
+    pub fn lifetime(mut self, lifetime: Option<u64>) -> TokenBuilder {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn key(mut self, key: Option<ClientKey>) -> TokenBuilder {
+        self.key = key;
+        self
+    }
+
+    pub fn access(mut self, access: Vec<AccessRequest>) -> TokenBuilder {
+        self.access = Some(access);
+        self
+    }
+
+    pub fn flags(mut self, flags: Vec<AccessTokenFlag>) -> TokenBuilder {
+        self.flags = Some(flags);
+        self
+    }
+
+    pub fn format(mut self, format: TokenFormat) -> TokenBuilder {
+        self.format = format;
         self
     }
 
     pub fn build(self) -> Token {
         let access_token = generate_token();
+        let issued_at = now();
         Token {
             id: self.id,
             access_token: Some(access_token),
             tx: self.tx,
             label: self.label,
-            expire: self.expire
+            issued_at,
+            expires_at: self.lifetime.map(|lifetime| issued_at + lifetime),
+            key: self.key,
+            access: self.access,
+            flags: self.flags,
+            format: self.format,
         }
     }
 }
 
+/// Claims of a self-contained access token.  `jti` is the id of the token,
+/// and `access` the rights it grants.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+    pub iat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Vec<AccessRequest>>,
+    /// The key the token is bound to.  Omitted for bearer tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+impl AccessTokenClaims {
+    pub fn new(token: &Token, iss: &str, sub: &str, aud: Vec<String>) -> Self {
+        Self {
+            iss: iss.to_owned(),
+            sub: sub.to_owned(),
+            aud,
+            iat: token.issued_at,
+            exp: token.expires_at,
+            jti: token.id.clone(),
+            access: token.access.clone(),
+            cnf: token.key.as_ref().map(Confirmation::from),
+        }
+    }
+
+    /// The claims as PASETO registered claims, where times are ISO 8601
+    /// strings and a single audience is a string.
+    pub fn paseto(&self) -> Result<Value, GnapError> {
+        let mut claims = serde_json::to_value(self)?;
+        claims["iat"] = Value::String(rfc3339(self.iat)?);
+        if let Some(exp) = self.exp {
+            claims["exp"] = Value::String(rfc3339(exp)?);
+        }
+        if let [aud] = self.aud.as_slice() {
+            claims["aud"] = Value::String(aud.clone());
+        }
+        Ok(claims)
+    }
+}
+
+/// Proof-of-possession key confirmation (RFC 7800).  A key is either a JWK
+/// with its thumbprint, or the thumbprint of a client certificate (RFC 8705).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwk: Option<Jwk>,
+    /// The JWK thumbprint (RFC 7638) of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+    #[serde(rename = "x5t#S256", default, skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+}
+
+impl From<&ClientKey> for Confirmation {
+    fn from(key: &ClientKey) -> Self {
+        Self {
+            jwk: key.jwk.clone(),
+            jkt: key.jwk.as_ref().and_then(jwk_thumbprint),
+            x5t_s256: key.cert_s256.clone(),
+        }
+    }
+}
+
+/// How long the access tokens issued by the AS are active.
+///
+/// A client can be registered with its own token lifetime, which replaces the
+/// default.  A resource type can be configured with a lifetime, which caps
+/// the lifetime of any token that grants access to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenLifetimes {
+    pub default: u64,
+    pub resource_types: HashMap<String, u64>,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_TOKEN_LIFETIME,
+            resource_types: HashMap::new(),
+        }
+    }
+}
+
+impl TokenLifetimes {
+    /// Parse the configured lifetimes.  `resource_types` is a comma separated
+    /// list of `type=seconds` pairs.  Malformed entries are ignored.
+    pub fn parse(default: Option<&str>, resource_types: Option<&str>) -> Self {
+        let default = default
+            .and_then(|secs| secs.trim().parse().ok())
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        let resource_types = resource_types
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| {
+                let (resource_type, secs) = entry.split_once('=')?;
+                Some((resource_type.trim().to_owned(), secs.trim().parse().ok()?))
+            })
+            .collect();
+        Self {
+            default,
+            resource_types,
+        }
+    }
+
+    /// The lifetime of a token for a client, granting `access`.
+    pub fn lifetime(&self, client_lifetime: Option<u64>, access: &[AccessRequest]) -> u64 {
+        access
+            .iter()
+            .filter_map(|request| self.resource_types.get(request.resource_type()?).copied())
+            .fold(client_lifetime.unwrap_or(self.default), u64::min)
+    }
+}
+
 impl CachePath for Token {
     fn cache_path() -> &'static str {
         "gnap:token"
@@ -101,7 +350,7 @@ impl ToRedisArgs for &Token {
 Copyright SecureKey Technologies Inc. All Rights Reserved.
 SPDX-License-Identifier: Apache-2.0
 */
-fn generate_token() -> String {
+pub fn generate_token() -> String {
     let length = 64;
     let characters: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
     let mut rng = rand::thread_rng();
@@ -116,21 +365,63 @@ fn generate_token() -> String {
     token
 }
 
-#[allow(dead_code)]
-fn generate_usercode() -> String {
-    let length = 64;
-    let characters: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+/// Characters used in user codes.  Only consonants, so that codes are easy to
+/// type, can't be confused with digits, and don't spell words.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+/// Length of a user code, including the trailing check character.
+const USER_CODE_LENGTH: usize = 8;
+
+/// Generate a short, human typable code for the `user_code` interaction
+/// start mode.  The last character is a Luhn mod N check character.
+pub fn generate_usercode() -> String {
     let mut rng = rand::thread_rng();
+    let mut code: Vec<usize> = (0..USER_CODE_LENGTH - 1)
+        .map(|_| rng.gen_range(0..USER_CODE_ALPHABET.len()))
+        .collect();
+    code.push(check_character(&code));
 
-    let mut usercode = String::from("");
-    let charlength = characters.len();
+    code.into_iter()
+        .map(|pos| USER_CODE_ALPHABET[pos] as char)
+        .collect()
+}
 
-    for _ in 0..length {
-        let pos = rng.gen_range(0..charlength);
-        usercode.push(characters.chars().nth(pos).unwrap());
+/// Normalize a user code typed by an end-user.  Case, spaces and dashes are
+/// ignored.  Returns `None` if the code is malformed or the check character
+/// does not match.
+pub fn normalize_usercode(input: &str) -> Option<String> {
+    let code = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| {
+            USER_CODE_ALPHABET
+                .iter()
+                .position(|a| *a as char == c.to_ascii_uppercase())
+        })
+        .collect::<Option<Vec<usize>>>()?;
+
+    let (check, code) = code.split_last()?;
+    if code.len() + 1 != USER_CODE_LENGTH || check_character(code) != *check {
+        return None;
     }
+    Some(
+        code.iter()
+            .chain(std::iter::once(check))
+            .map(|pos| USER_CODE_ALPHABET[*pos] as char)
+            .collect(),
+    )
+}
 
-    usercode
+/// Luhn mod N check character for a code, as positions in the alphabet.
+fn check_character(code: &[usize]) -> usize {
+    let n = USER_CODE_ALPHABET.len();
+    let mut factor = 2;
+    let mut sum = 0;
+    for pos in code.iter().rev() {
+        let addend = factor * pos;
+        factor = if factor == 2 { 1 } else { 2 };
+        sum += addend / n + addend % n;
+    }
+    (n - sum % n) % n
 }
 
 #[cfg(test)]
@@ -152,22 +443,183 @@ mod test {
 
     #[test]
     fn gen_usercode() {
-        let token = generate_usercode();
-        println!("UserCode: {}", token);
-        assert_eq!(token.len(), 64);
+        let code = generate_usercode();
+        println!("UserCode: {}", code);
+        assert_eq!(code.len(), 8);
+        assert_eq!(normalize_usercode(&code), Some(code.clone()));
+
+        let typed = format!("{}-{}", &code[..4], &code[4..]).to_lowercase();
+        assert_eq!(normalize_usercode(&typed), Some(code));
+    }
+
+    #[test]
+    fn reject_mistyped_usercode() {
+        let code = generate_usercode();
+        // Any single substituted character fails the check.
+        let first = code.chars().next().unwrap();
+        let other = if first == 'B' { 'C' } else { 'B' };
+        let mistyped = format!("{}{}", other, &code[1..]);
+        assert_eq!(normalize_usercode(&mistyped), None);
+
+        assert_eq!(normalize_usercode(&code[1..]), None);
+        assert_eq!(normalize_usercode("BCDF0HJK"), None);
     }
 
     #[test]
     fn token_builder_ok() {
         let tx = Uuid::new_v4().to_string();
         let label = Some(String::from("kenneth"));
-        let token  = TokenBuilder::new(tx.clone())
-                    .label(label.clone())
-                    .build();
+        let token = TokenBuilder::new(tx.clone()).label(label.clone()).build();
 
         assert_eq!(token.label, label);
         assert!(token.access_token.is_some());
+        assert_eq!(token.lifetime(), Some(DEFAULT_TOKEN_LIFETIME));
+        assert!(!token.is_expired());
         assert_eq!(token.tx.unwrap(), tx);
-        assert_eq!(token.expire, Some(0))
+        assert!(token.key.is_none());
+    }
+
+    #[test]
+    fn token_builder_key() {
+        let key: ClientKey = serde_json::from_str(
+            r#"{ "proof": "mtls", "cert#S256": "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2" }"#,
+        )
+        .unwrap();
+        let token = TokenBuilder::new(Uuid::new_v4().to_string())
+            .key(Some(key.clone()))
+            .build();
+
+        assert_eq!(token.key, Some(key));
+    }
+
+    #[test]
+    fn rotate_token() {
+        let token = TokenBuilder::new(Uuid::new_v4().to_string())
+            .label(Some("bowls".to_owned()))
+            .access(vec![AccessRequest::Reference("foo".to_owned())])
+            .flags(vec![AccessTokenFlag::Bearer])
+            .build();
+        assert!(!token.is_durable());
+
+        let rotated = token.rotate();
+        assert_ne!(rotated.id, token.id);
+        assert_ne!(rotated.access_token, token.access_token);
+        assert_eq!(rotated.tx, token.tx);
+        assert_eq!(rotated.label, token.label);
+        assert_eq!(rotated.access, token.access);
+        assert_eq!(rotated.flags, token.flags);
+
+        assert_eq!(rotated.lifetime(), token.lifetime());
+
+        let durable = TokenBuilder::new(Uuid::new_v4().to_string())
+            .flags(vec![AccessTokenFlag::Durable])
+            .build();
+        assert!(durable.is_durable());
+    }
+
+    #[test]
+    fn token_expiry() {
+        let mut token = TokenBuilder::new(Uuid::new_v4().to_string())
+            .lifetime(Some(60))
+            .build();
+        assert_eq!(token.expires_at, Some(token.issued_at + 60));
+        assert!(token.expires_in().unwrap() <= 60);
+
+        token.issued_at -= 120;
+        token.expires_at = Some(token.issued_at + 60);
+        assert!(token.is_expired());
+        assert_eq!(token.expires_in(), Some(0));
+        assert_eq!(token.rotate().lifetime(), Some(60));
+
+        let forever = TokenBuilder::new(Uuid::new_v4().to_string())
+            .lifetime(None)
+            .build();
+        assert_eq!(forever.expires_in(), None);
+        assert!(!forever.is_expired());
+    }
+
+    #[test]
+    fn jwk_confirmation() {
+        let key: ClientKey = serde_json::from_str(
+            r#"{
+                "proof": "httpsig",
+                "jwk": {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "kid": "test-client",
+                    "x": "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+                }
+            }"#,
+        )
+        .unwrap();
+        let token = TokenBuilder::new(Uuid::new_v4().to_string())
+            .key(Some(key.clone()))
+            .format(TokenFormat::Jwt)
+            .build();
+        let claims = AccessTokenClaims::new(&token, "https://as", "kenneth", vec![]);
+
+        let json = serde_json::to_value(&claims).unwrap();
+        assert_eq!(
+            json["cnf"]["jwk"]["x"],
+            "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+        );
+        assert_eq!(json["cnf"]["jkt"], key.thumbprint().unwrap().as_str());
+        assert!(json["cnf"].get("x5t#S256").is_none());
+    }
+
+    #[test]
+    fn access_token_claims() {
+        let key: ClientKey = serde_json::from_str(
+            r#"{ "proof": "mtls", "cert#S256": "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2" }"#,
+        )
+        .unwrap();
+        let token = TokenBuilder::new(Uuid::new_v4().to_string())
+            .access(vec![AccessRequest::Reference("foo".to_owned())])
+            .key(Some(key))
+            .format(TokenFormat::Jwt)
+            .build();
+        let claims = AccessTokenClaims::new(&token, "https://as", "kenneth", vec![]);
+
+        let json = serde_json::to_value(&claims).unwrap();
+        assert_eq!(json["jti"], token.id.as_str());
+        assert_eq!(json["exp"], token.expires_at.unwrap());
+        assert_eq!(json["access"][0], "foo");
+        assert_eq!(
+            json["cnf"]["x5t#S256"],
+            "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2"
+        );
+        assert!(json.get("aud").is_none());
+
+        let mut claims = claims;
+        claims.iat = 1640995200;
+        claims.exp = Some(1640998800);
+        claims.aud = vec!["rs".to_owned()];
+        let paseto = claims.paseto().unwrap();
+        assert_eq!(paseto["iat"], "2022-01-01T00:00:00Z");
+        assert_eq!(paseto["exp"], "2022-01-01T01:00:00Z");
+        assert_eq!(paseto["aud"], "rs");
+        assert_eq!(paseto["jti"], token.id.as_str());
+    }
+
+    #[test]
+    fn token_lifetimes() {
+        let lifetimes = TokenLifetimes::parse(Some("600"), Some("photo-api=300, bogus, x=y"));
+        assert_eq!(lifetimes.default, 600);
+        assert_eq!(lifetimes.resource_types.len(), 1);
+
+        let photos: AccessRequest =
+            serde_json::from_str(r#"{"type": "photo-api", "actions": ["read"]}"#).unwrap();
+        let other: AccessRequest = serde_json::from_str(r#"{"type": "other"}"#).unwrap();
+        let reference = AccessRequest::Reference("photo-api".to_owned());
+
+        assert_eq!(lifetimes.lifetime(None, &[]), 600);
+        assert_eq!(
+            lifetimes.lifetime(Some(120), std::slice::from_ref(&other)),
+            120
+        );
+        assert_eq!(lifetimes.lifetime(None, &[other, photos.clone()]), 300);
+        assert_eq!(lifetimes.lifetime(Some(120), &[photos]), 120);
+        assert_eq!(lifetimes.lifetime(None, &[reference]), 600);
+        assert_eq!(TokenLifetimes::parse(None, None), TokenLifetimes::default());
     }
 }
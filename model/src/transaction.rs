@@ -2,10 +2,15 @@
 //!
 //!All interaction with the server starts with a grant request.
 //!
-use super::grant::GrantRequest;
+use super::grant::{GrantRequest, InteractStartMode};
+use super::tokens::{generate_token, generate_usercode};
 use super::CachePath;
+use bson::DateTime;
+use errors::GnapError;
+use log::debug;
 use redis::{RedisWrite, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 //#[allow(proc_macro_derive_resolution_fallback)]
@@ -16,6 +21,12 @@ pub type KeyProofs = Vec<String>;
 pub type SubjectFormats = Vec<String>;
 pub type Assertions = Vec<String>;
 
+/// Seconds a client instance must wait between polling continuation requests.
+pub const POLL_WAIT: u32 = 5;
+/// Seconds an unfinished transaction is kept, before it is considered
+/// abandoned.
+pub const TRANSACTION_LIFETIME: u64 = 3600;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionOptions {
     /// The location of the AS's
@@ -84,7 +95,7 @@ impl TransactionOptions {
             "phone_number".to_owned(),
         ];
 
-        let assertions = vec!["oidc".to_owned(), "saml2".to_owned()];
+        let assertions = vec!["id_token".to_owned()];
 
         Self {
             grant_request_endpoint: "localhost::8000/gnap/grant".to_owned(),
@@ -127,7 +138,7 @@ impl ToRedisArgs for &TransactionOptions {
        WAITING,	// we are waiting for the user
        DENIED; 	// the user denied the transaction
 */
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum GnapTransactionState {
     Start,
@@ -142,11 +153,111 @@ pub enum GnapTransactionState {
     Denied,
 }
 
+impl GnapTransactionState {
+    /// True if a transaction in this state may move to `next`.
+    ///
+    /// A grant is received, its client verified, and then waits for the
+    /// end-user.  Once authorized, tokens are issued once.  A modification
+    /// either re-authorizes the grant, or makes it wait for the end-user
+    /// again.  A denied grant is final.
+    pub fn can_transition_to(self, next: GnapTransactionState) -> bool {
+        use GnapTransactionState::*;
+        matches!(
+            (self, next),
+            (Start | New, Received)
+                | (Received, ClientVerified)
+                | (ClientVerified, Waiting | Authorized)
+                | (
+                    Waiting,
+                    Waiting | ResourceOwnerVerified | Authorized | Denied
+                )
+                | (ResourceOwnerVerified, Authorized | Denied)
+                | (Authorized, Issued | Waiting)
+                | (Issued, Authorized | Waiting)
+        )
+    }
+}
+
+/// A state change of a transaction, kept for debugging.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateTransition {
+    pub from: GnapTransactionState,
+    pub to: GnapTransactionState,
+    /// When the transition happened (seconds since the epoch).
+    pub at: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GnapTransaction {
     pub tx_id: String,
     pub state: GnapTransactionState,
     pub request: Option<GrantRequest>,
+    /// The continuation access token the client instance must present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continue_token: Option<String>,
+    /// When the client instance was last told to wait (seconds since the
+    /// epoch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_poll: Option<u64>,
+    /// The `finish` nonce the AS returned for the interaction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_nonce: Option<String>,
+    /// The interaction reference handed to the client instance when the
+    /// interaction finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interact_ref: Option<String>,
+    /// The code the end-user enters at the code entry page, for the
+    /// `user_code` start mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_code: Option<String>,
+    /// The one-time code in the launch URI of the `app` start mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_code: Option<String>,
+    /// Delivery of the `push` interaction finish to the client instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push: Option<PushDelivery>,
+    /// True if the RO consented to releasing their identity to the client
+    /// instance.
+    #[serde(default)]
+    pub subject_consent: bool,
+    /// Every state the transaction went through.
+    #[serde(default)]
+    pub history: Vec<StateTransition>,
+    /// When the grant request was received.
+    #[serde(default = "DateTime::now")]
+    pub created_at: DateTime,
+    /// When an unfinished transaction is abandoned.  Cleared once tokens are
+    /// issued.  Stored as a date, so MongoDB can expire it with a TTL index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PushStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// The delivery status of an interaction finish push.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PushDelivery {
+    pub status: PushStatus,
+    /// Number of delivery attempts made so far.
+    pub attempts: u32,
+    /// When the status was last updated (seconds since the epoch).
+    pub updated_at: u64,
+}
+
+impl PushDelivery {
+    pub fn new(status: PushStatus, attempts: u32) -> Self {
+        Self {
+            status,
+            attempts,
+            updated_at: now(),
+        }
+    }
 }
 
 impl GnapTransaction {
@@ -154,16 +265,148 @@ impl GnapTransaction {
         Uuid::new_v4().to_string()
     }
 
+    /// A transaction for a received grant request.  The client instance still
+    /// has to be verified.
     pub fn new(request: Option<GrantRequest>) -> Self {
         Self {
             tx_id: Self::create_id(),
-            state: GnapTransactionState::Waiting,
+            state: GnapTransactionState::Received,
             request,
+            continue_token: Some(generate_token()),
+            last_poll: Some(now()),
+            finish_nonce: None,
+            interact_ref: None,
+            user_code: None,
+            app_code: None,
+            push: None,
+            subject_consent: false,
+            history: vec![StateTransition {
+                from: GnapTransactionState::Start,
+                to: GnapTransactionState::Received,
+                at: now(),
+            }],
+            created_at: DateTime::now(),
+            expires_at: Some(expiry()),
+        }
+        .start_interaction()
+    }
+
+    /// Move the transaction to the next state, and record the transition.
+    /// Illegal transitions are rejected.
+    ///
+    /// A grant with issued tokens does not expire.  Waiting for the end-user
+    /// again starts a new lifetime.
+    pub fn transition(self, next: GnapTransactionState) -> Result<Self, GnapError> {
+        if !self.state.can_transition_to(next) {
+            let transition = format!("{:?} -> {:?}", self.state, next);
+            debug!(
+                "Illegal transition of transaction {}: {}",
+                self.tx_id, transition
+            );
+            return Err(GnapError::InvalidTransition(transition));
+        }
+        let mut history = self.history;
+        history.push(StateTransition {
+            from: self.state,
+            to: next,
+            at: now(),
+        });
+        let expires_at = match next {
+            GnapTransactionState::Issued => None,
+            GnapTransactionState::Waiting => Some(expiry()),
+            _ => self.expires_at,
+        };
+        Ok(Self {
+            state: next,
+            history,
+            expires_at,
+            ..self
+        })
+    }
+
+    /// Prepare a new interaction.  A `finish` nonce is only issued when the
+    /// client instance asked for an interaction finish method.  A user code
+    /// and an app launch code are issued for the `user_code` and `app` start
+    /// modes.
+    pub fn start_interaction(self) -> Self {
+        let interact = self
+            .request
+            .as_ref()
+            .and_then(|request| request.interact.as_ref());
+        let finish = interact.and_then(|interact| interact.finish.as_ref());
+        let starts = |mode: InteractStartMode| {
+            interact
+                .map(|interact| interact.start.contains(&mode))
+                .unwrap_or(false)
+        };
+        let user_code = starts(InteractStartMode::UserCode).then(generate_usercode);
+        let app_code = starts(InteractStartMode::App).then(generate_token);
+        Self {
+            finish_nonce: finish.map(|_| generate_token()),
+            interact_ref: None,
+            user_code,
+            app_code,
+            push: None,
+            ..self
+        }
+    }
+
+    /// Issue the interaction reference once the end-user has finished
+    /// interacting.
+    pub fn finish_interaction(self) -> Self {
+        Self {
+            interact_ref: Some(generate_token()),
+            ..self
+        }
+    }
+
+    /// The end-user denied the grant.  No interaction reference is issued, so
+    /// the grant can't be continued.
+    pub fn deny_interaction(self) -> Result<Self, GnapError> {
+        Ok(self
+            .transition(GnapTransactionState::Denied)?
+            .clear_interact_ref())
+    }
+
+    /// The interaction reference can only be used once.
+    pub fn clear_interact_ref(self) -> Self {
+        Self {
+            interact_ref: None,
+            ..self
+        }
+    }
+
+    /// Record that the client instance polled, and must wait again.
+    pub fn record_poll(self) -> Self {
+        Self {
+            last_poll: Some(now()),
+            ..self
         }
     }
 
-    pub fn update_state(self, state: GnapTransactionState) -> Self {
-        Self { state, ..self }
+    /// True if the client instance polls before the wait it was given has
+    /// passed.
+    pub fn polled_too_fast(&self) -> bool {
+        match self.last_poll {
+            Some(last_poll) => now() < last_poll + u64::from(POLL_WAIT),
+            None => false,
+        }
+    }
+
+    /// Issue a new continuation access token, replacing the previous one.
+    pub fn rotate_continue_token(self) -> Self {
+        Self {
+            continue_token: Some(generate_token()),
+            ..self
+        }
+    }
+
+    /// Record whether the RO consented to releasing their identity.
+    pub fn update_subject_consent(self, subject_consent: bool) -> Self {
+        Self {
+            subject_consent,
+            ..self
+        }
     }
 
     pub fn update_grantrequest(self, gr: GrantRequest) -> Self {
@@ -181,6 +424,19 @@ impl GnapTransaction {
     }
 }
 
+/// The current time in seconds since the epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The expiry of a transaction that starts waiting now.
+fn expiry() -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + TRANSACTION_LIFETIME as i64 * 1000)
+}
+
 impl CachePath for GnapTransaction {
     fn cache_path() -> &'static str {
         "gnap:tx"
@@ -197,3 +453,99 @@ impl ToRedisArgs for &GnapTransaction {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_polling_too_fast() {
+        let tx = GnapTransaction::new(None);
+        assert!(tx.polled_too_fast());
+
+        let tx = GnapTransaction {
+            last_poll: Some(now() - u64::from(POLL_WAIT)),
+            ..tx
+        };
+        assert!(!tx.polled_too_fast());
+        assert!(tx.record_poll().polled_too_fast());
+    }
+
+    #[test]
+    fn record_state_transitions() {
+        use GnapTransactionState::*;
+        let tx = GnapTransaction::new(None);
+        assert_eq!(tx.state, Received);
+
+        let tx = tx
+            .transition(ClientVerified)
+            .and_then(|tx| tx.transition(Waiting))
+            .and_then(|tx| tx.transition(Authorized))
+            .and_then(|tx| tx.transition(Issued))
+            .unwrap();
+        assert_eq!(tx.state, Issued);
+        let states: Vec<(GnapTransactionState, GnapTransactionState)> = tx
+            .history
+            .iter()
+            .map(|transition| (transition.from, transition.to))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (Start, Received),
+                (Received, ClientVerified),
+                (ClientVerified, Waiting),
+                (Waiting, Authorized),
+                (Authorized, Issued),
+            ]
+        );
+    }
+
+    #[test]
+    fn expire_unfinished_transactions() {
+        use GnapTransactionState::*;
+        let tx = GnapTransaction::new(None)
+            .transition(ClientVerified)
+            .and_then(|tx| tx.transition(Waiting))
+            .unwrap();
+        let expires_at = tx.expires_at.unwrap();
+        assert!(expires_at > tx.created_at);
+
+        // The dates survive the JSON round trip of the cache.
+        let json = serde_json::to_string(&tx).unwrap();
+        let cached: GnapTransaction = serde_json::from_str(&json).unwrap();
+        assert_eq!(cached.created_at, tx.created_at);
+        assert_eq!(cached.expires_at, Some(expires_at));
+
+        let issued = tx
+            .transition(Authorized)
+            .and_then(|tx| tx.transition(Issued))
+            .unwrap();
+        assert_eq!(issued.expires_at, None);
+        assert!(issued.transition(Waiting).unwrap().expires_at.is_some());
+    }
+
+    #[test]
+    fn reject_illegal_transitions() {
+        use GnapTransactionState::*;
+        let tx = GnapTransaction::new(None);
+        assert!(matches!(
+            tx.clone().transition(Authorized),
+            Err(GnapError::InvalidTransition(_))
+        ));
+
+        let issued = GnapTransaction {
+            state: Issued,
+            ..tx.clone()
+        };
+        // Tokens are only issued once.
+        assert!(issued.transition(Issued).is_err());
+
+        let denied = GnapTransaction {
+            state: Denied,
+            ..tx
+        };
+        assert!(denied.clone().transition(Authorized).is_err());
+        assert!(denied.transition(Waiting).is_err());
+    }
+}
@@ -11,6 +11,9 @@ pub struct User {
     pub password: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub access: Option<Vec<AccessRequest>>,
+    /// The account holding the identity claims of the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<Uuid>,
 }
 
 impl User {
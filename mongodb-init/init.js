@@ -31,8 +31,7 @@ const config =
         "phone_number"
     ],
     assertions_supported: [
-        "oidc",
-        "saml2"
+        "id_token"
     ],
     token_formats_supported: [
         "jwt",
@@ -44,7 +43,16 @@ const clients = [
     {
         client_id: "7e057b0c-17e8-4ab4-9260-2b33f32b2cce",
         client_name: "test_client_1",
-        redirect_uris: ["http://localhost:8000"]
+        redirect_uris: ["http://localhost:8000"],
+        key: {
+            proof: "httpsig",
+            jwk: {
+                kty: "OKP",
+                crv: "Ed25519",
+                kid: "test_client_1",
+                x: "34mc3CWQo81FphKLlWuClXSzxoafWDz2OFWzuqyWG2k"
+            }
+        }
     }
 ]
 
@@ -52,6 +60,7 @@ const users = [
     {
         "id": "6785732c-682a-458b-8465-2986a77abf6a",
         "username": "kenneth",
+        "account_id": "e63769de-3a44-11ec-8d3d-0242ac130001",
         "password": "$argon2id$v=19$m=1500,t=2,p=1$SQ7OGnJMWaiUVfo1lOd8Iw$my2NzNZkr3h3phXr0cjtiNPTc2vLIrRmWMHxlDRouCI",
         "access": [
             {
@@ -73,6 +82,12 @@ const users = [
                 "locations": [
                     "https://localhost:8080/waterlevels/"
                 ]
+            },
+            {
+                "type": "openid",
+                "actions": [
+                    "read"
+                ]
             }
         ]
     },
@@ -135,6 +150,15 @@ const resources = [
         "resource_server": "e8a2968a-f183-45a3-b63d-4bbbd1dad276",
         "resource_server_name": "simple-api",
         "resource_server_key": "httsig",
+        "key": {
+            "proof": "httpsig",
+            "jwk": {
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "kid": "simple-api",
+                "x": "3uRgQaMfNMchA7kNI4HW16gDPx1mS6jcp4UkSlNaEeA"
+            }
+        },
         "resource_set": [
             {
                 "type": "waterbowl-access",
@@ -202,6 +226,7 @@ const accounts = [
         profile: 'https://johnswebsite.com',
         website: 'http://example.com',
         zoneinfo: 'Europe/Berlin',
+        updated_at: NumberLong(1666000000),
     },
     {
         account_id: 'e63769de-3a44-11ec-8d3d-0242ac130001',
@@ -242,6 +267,7 @@ const accounts = [
         profile: 'https://profile.kefo.no',
         website: 'https://kefo.no',
         zoneinfo: 'Europe/Berlin',
+        updated_at: NumberLong(1666000000),
     },
 ]
 